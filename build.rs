@@ -148,11 +148,6 @@ fn main() -> Result<()> {
         fs::create_dir_all(&assets_path).expect("Should be able to create assets directory if not there");
     }
 
-    let models_path = format!("{out_path}/models");
-    if !Path::new(&models_path).is_dir() {
-        fs::create_dir_all(&models_path).expect("Should be able to create models directory if not there");
-    }
-
     // Download htmx
     let htmx_file_path = format!("{assets_path}/htmx.min.js");
     if !Path::new(&htmx_file_path).is_file() {
@@ -180,16 +175,6 @@ fn main() -> Result<()> {
         fs::write(hyperscript_file_path, hyperscript_text).expect("Should be able to write hyperscript text to file");
     }
 
-    // Download Model
-    //let llama_small_model_path = format!("{models_path}/llama-2-7b.ggmlv3.q2_K.bin");
-    //if !Path::new(&llama_small_model_path).is_file() {
-    //    let model_body = reqwest::blocking::get("https://huggingface.co/TheBloke/Llama-2-7B-GGML/resolve/main/llama-2-7b.ggmlv3.q2_K.bin")
-    //        .expect("Couldn't download llama 2 model from huggingface");
-    //    let model_text = model_body.text().expect("Couldn't convert model body to text");
-    //    fs::write(llama_small_model_path, model_text).expect("Couldn't write model text to file");
-    //}
-
-
     // Walk the assets directory and copy them into the assets folder in the build directory
     let walker = WalkDir::new("assets").into_iter();
     for entry in walker.filter_entry(|e| !is_hidden(e)) {
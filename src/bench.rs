@@ -0,0 +1,93 @@
+use std::time::Instant;
+
+use futures_util::StreamExt;
+
+use crate::llama::{self, chat::{ChatMessage, Role}, Llama};
+
+const PROMPTS: [&str; 8] = [
+    "How do I request time off?",
+    "Who do I talk to about a broken laptop?",
+    "Summarize our expense reimbursement policy.",
+    "What is the process for onboarding a new hire?",
+    "Where can I find the brand guidelines?",
+    "How do I get access to the staging environment?",
+    "When are performance reviews held?",
+    "What should I do if I lose my badge?",
+];
+
+#[derive(clap::Args)]
+pub struct Args {
+    #[arg(long, default_value = "models/llama-2-7b.Q2_K.gguf")]
+    model: String,
+    /// A tokenizer.json, the tokenizer is built from the GGUF metadata otherwise
+    #[arg(long)]
+    tokenizer: Option<String>,
+    /// Generations to run, and the batch size of the batched run
+    #[arg(long, default_value_t = 4)]
+    sequences: usize,
+    /// Tokens to generate per sequence
+    #[arg(long, default_value_t = 64)]
+    max_tokens: usize,
+    /// A draft model for speculative decoding, used by the serial run
+    #[arg(long)]
+    draft: Option<String>,
+    /// Tokens the draft model proposes at a time
+    #[arg(long, default_value_t = 4)]
+    lookahead: usize,
+}
+
+/// Generates the same answers once one after the other and once all at the same time, and
+/// compares the aggregate tokens/s.
+pub async fn run(args: Args) -> anyhow::Result<()> {
+    let llama = Llama::new(
+        &args.model,
+        args.tokenizer.as_deref(),
+        llama::Config {
+            max_batch_size: args.sequences,
+            draft_model: args.draft.clone(),
+            lookahead: args.lookahead,
+            ..Default::default()
+        },
+    )?;
+    let params = |i: usize| llama::Params {
+        seed: Some(i as u64),
+        max_tokens: Some(args.max_tokens),
+        ..Default::default()
+    };
+
+    let start = Instant::now();
+    let mut serial_tokens = 0;
+    for i in 0..args.sequences {
+        serial_tokens += generate(&llama, i, params(i)).await?;
+    }
+    let serial_dt = start.elapsed();
+
+    let start = Instant::now();
+    let batched = (0..args.sequences).map(|i| generate(&llama, i, params(i)));
+    let mut batched_tokens = 0;
+    for tokens in futures::future::join_all(batched).await {
+        batched_tokens += tokens?;
+    }
+    let batched_dt = start.elapsed();
+
+    let serial = serial_tokens as f64 / serial_dt.as_secs_f64();
+    let batched = batched_tokens as f64 / batched_dt.as_secs_f64();
+    println!("\n{} sequences, up to {} tokens each", args.sequences, args.max_tokens);
+    println!("serial:  {:4} tokens in {:6.2}s, {:.2} token/s", serial_tokens, serial_dt.as_secs_f64(), serial);
+    println!("batched: {:4} tokens in {:6.2}s, {:.2} token/s", batched_tokens, batched_dt.as_secs_f64(), batched);
+    println!("speedup: {:.2}x", batched / serial);
+    Ok(())
+}
+
+// Returns the number of generated tokens
+async fn generate(llama: &Llama, i: usize, params: llama::Params) -> anyhow::Result<usize> {
+    let messages = [ChatMessage::new(Role::User, PROMPTS[i % PROMPTS.len()])];
+    let mut generation = llama.chat(None, &messages, params);
+    let mut tokens = 0;
+    while let Some(output) = generation.next().await {
+        if let llama::Output::Finished { completion_tokens, .. } = output.map_err(anyhow::Error::msg)? {
+            tokens = completion_tokens;
+        }
+    }
+    Ok(tokens)
+}
@@ -1,5 +1,7 @@
-use maud::{html, Markup, PreEscaped};
-use crate::{theme::{ColorScheme, ColorMode, Theme}, icon, page::Agent};
+use std::time::Duration;
+
+use maud::{html, Markup};
+use crate::{theme::{ColorScheme, ColorMode, Theme}, icon, page::Agent, profiler};
 
 pub fn theme_preference(color_scheme: ColorScheme, set_theme: bool) -> Markup {
     
@@ -106,7 +108,73 @@ pub fn prompt_input(id: &str) -> Markup {
     }
 }
 
-pub fn message(agent: Agent, content: &str, sse: bool) -> Markup {
+/// Nothing `selected` leaves the pick to the router.
+pub fn model_picker(conversation_id: &str, models: &[&str], selected: Option<&str>) -> Markup {
+    html! {
+        select #model-picker name="model" hx-put=(format!("/conversations/{conversation_id}/model"))
+            hx-swap="none" class="bg-transparent text-terracotta-400" {
+            option value="" selected[selected.is_none()] { "Automatic" }
+            @for model in models {
+                option value=(model) selected[Some(*model) == selected] { (model) }
+            }
+        }
+    }
+}
+
+pub fn confidence_preference(highlight: bool) -> Markup {
+    html! {
+        form #confidence hx-target="this" hx-swap="outerHTML" {
+            select name="highlight_confidence" hx-put="/settings/confidence" {
+                option value="off" selected[!highlight] { "Plain answers" }
+                option value="on" selected[highlight] { "Underline words the model was unsure about" }
+            }
+        }
+    }
+}
+
+/// Starts and stops Chrome traces and links the finished ones.
+pub fn profiling(running: Option<(String, Duration)>, traces: &[String]) -> Markup {
+    html! {
+        div #profiling hx-target="this" hx-swap="outerHTML" {
+            @if let Some((file, elapsed)) = running {
+                p class="text-sm" {
+                    (format!("Capturing {file}, {}s so far ", elapsed.as_secs()))
+                    button hx-delete="/admin/profiling" class="text-terracotta-400" { "Stop" }
+                }
+            } @else {
+                form hx-post="/admin/profiling" class="text-sm" {
+                    "Capture a Chrome trace of the next "
+                    input type="number" name="seconds" value="30" min="1"
+                        max=(profiler::MAX_WINDOW.as_secs()) class="w-12 bg-transparent";
+                    " seconds "
+                    button type="submit" class="text-terracotta-400" { "Start" }
+                }
+            }
+            ul class="text-sm" {
+                @for trace in traces {
+                    li { a href=(format!("/admin/traces/{trace}")) download { (trace) } }
+                }
+            }
+        }
+    }
+}
+
+pub fn reload_routing_button() -> Markup {
+    html! {
+        button hx-post="/admin/routing/reload" hx-swap="none"
+            class="text-terracotta-400" { "Reload" }
+    }
+}
+
+pub fn reload_button(model: &str) -> Markup {
+    html! {
+        button hx-post=(format!("/admin/models/{model}/reload")) hx-swap="none"
+            class="text-terracotta-400" { "Reload" }
+    }
+}
+
+/// `stream_from` is the URL of the event stream the answer comes from.
+pub fn message(agent: Agent, content: &str, stream_from: Option<&str>) -> Markup {
     let is_user = agent == Agent::User;
     let is_chatbot = agent == Agent::Chatbot;
     //TODO: Put in ID so that it can be swapped faster
@@ -117,16 +185,16 @@ pub fn message(agent: Agent, content: &str, sse: bool) -> Markup {
                 div class="w-5" {
                     div ."w-3"."h-3".rounded-full."mx-1".bg-dark-cyan[is_user].bg-dark-magenta[is_chatbot] {}
                 }
-                @if sse {
-                    @let (query1, query2) = match agent {
-                        Agent::User => ("/chatbot?agent=user", format!("content={content}")),
-                        Agent::Chatbot => ("/chatbot?agent=chatbot", format!("content={content}")),
-                        Agent::Other => ("/chatbot?agent=other", format!("content={content}")),
-                    };
-                    p hx-ext="sse, scroll-bottom" sse-connect={(query1) (PreEscaped("&")) (query2)} 
-                        sse-swap="chatbot" hx-swap="beforeend" scroll-bottom="bottom-spacer" {
-                        //hx-on="htmx:sseMessage: document.getElementById(\"bottom-spacer\").scrollIntoView({ block: \"end\", behavior: htmx.config.scrollBehavior })" {
-                        span {}
+                @if let Some(url) = stream_from {
+                    div hx-ext="sse, scroll-bottom" sse-connect=(url) class="flex flex-col" {
+                        small sse-swap="route" class="text-gray-500" {}
+                        small sse-swap="queue" class="text-gray-500" {}
+                        small sse-swap="compacted" class="text-gray-500" {}
+                        p sse-swap="chatbot" hx-swap="beforeend" scroll-bottom="bottom-spacer" {
+                            //hx-on="htmx:sseMessage: document.getElementById(\"bottom-spacer\").scrollIntoView({ block: \"end\", behavior: htmx.config.scrollBehavior })" {
+                            span {}
+                        }
+                        small sse-swap="seed" class="text-gray-500" {}
                     }
                 } @else {
                     p {
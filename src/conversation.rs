@@ -0,0 +1,67 @@
+use std::collections::HashMap;
+use std::sync::RwLock;
+
+use crate::llama::chat::{ChatMessage, Role};
+use crate::page::FakeMessage;
+
+// Will eventually be replaced by conversations stored in postgres. Until then every new
+// conversation starts out with a copy of the fake messages.
+pub struct Conversations {
+    fake_messages: Vec<FakeMessage>,
+    conversations: RwLock<HashMap<String, Vec<FakeMessage>>>,
+    // Name of the model each conversation picked, the router decides for the others
+    models: RwLock<HashMap<String, String>>,
+}
+
+impl Conversations {
+    pub fn new(fake_messages: Vec<FakeMessage>) -> Conversations {
+        Conversations {
+            fake_messages,
+            conversations: RwLock::new(HashMap::new()),
+            models: RwLock::new(HashMap::new()),
+        }
+    }
+
+    pub fn messages(&self, id: &str) -> Vec<FakeMessage> {
+        let conversations = self.conversations.read().unwrap_or_else(|e| e.into_inner());
+        match conversations.get(id) {
+            Some(messages) => messages.clone(),
+            None => self.fake_messages.clone(),
+        }
+    }
+
+    pub fn push(&self, id: &str, message: FakeMessage) {
+        let mut conversations = self.conversations.write().unwrap_or_else(|e| e.into_inner());
+        conversations
+            .entry(id.to_string())
+            .or_insert_with(|| self.fake_messages.clone())
+            .push(message);
+    }
+
+    pub fn model(&self, id: &str) -> Option<String> {
+        let models = self.models.read().unwrap_or_else(|e| e.into_inner());
+        models.get(id).cloned()
+    }
+
+    pub fn set_model(&self, id: &str, model: &str) {
+        let mut models = self.models.write().unwrap_or_else(|e| e.into_inner());
+        models.insert(id.to_string(), model.to_string());
+    }
+
+    pub fn unset_model(&self, id: &str) {
+        let mut models = self.models.write().unwrap_or_else(|e| e.into_inner());
+        models.remove(id);
+    }
+
+    /// The conversation as chat history for the model. Messages from other agents are left out.
+    pub fn chat_history(&self, id: &str) -> Vec<ChatMessage> {
+        self.messages(id)
+            .iter()
+            .filter_map(|m| match m.from.as_str() {
+                "user" => Some(ChatMessage::new(Role::User, &m.content)),
+                "chatbot" => Some(ChatMessage::new(Role::Assistant, &m.content)),
+                _ => None,
+            })
+            .collect()
+    }
+}
@@ -0,0 +1,243 @@
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Instant;
+
+use candle_core::{Device, Tensor};
+use candle_nn::VarBuilder;
+use candle_transformers::models::bert::{BertModel, Config as BertConfig, DTYPE};
+use serde::Deserialize;
+use tokenizers::Tokenizer;
+
+use crate::hub::{Hub, HubFile};
+use crate::metrics::Metrics;
+
+const CONFIG_FILE: &str = "config.json";
+const TOKENIZER_FILE: &str = "tokenizer.json";
+const WEIGHTS_FILE: &str = "model.safetensors";
+
+/// The sentence-embedding model of `embedding.json`, a BERT model like
+/// `sentence-transformers/all-MiniLM-L6-v2` in the safetensors format.
+#[derive(Deserialize, Clone, Debug)]
+pub struct EmbeddingConfig {
+    /// What the API calls the model
+    pub name: String,
+    /// A directory with config.json, tokenizer.json and model.safetensors
+    pub path: Option<String>,
+    /// A Hugging Face hub repository with those files, instead of `path`
+    pub repo: Option<String>,
+    /// Branch, tag or commit of `repo`
+    pub revision: Option<String>,
+    /// The most texts embedded per forward pass, 32 by default. Only texts with exactly the same
+    /// number of tokens share a pass, so requests of mixed texts mostly run one pass per text.
+    pub batch_size: Option<usize>,
+    /// Scales the embeddings to unit length, so dot products are cosine similarities. On by
+    /// default.
+    pub normalize: Option<bool>,
+}
+
+impl EmbeddingConfig {
+    // The local paths of the model files, downloaded first if needed
+    fn files(&self, hub: &Hub) -> anyhow::Result<[PathBuf; 3]> {
+        let files = [CONFIG_FILE, TOKENIZER_FILE, WEIGHTS_FILE];
+        match (&self.path, &self.repo) {
+            (Some(path), None) => Ok(files.map(|file| PathBuf::from(path).join(file))),
+            (None, Some(repo)) => {
+                let resolve = |file: &str| {
+                    hub.resolve(&HubFile {
+                        repo: repo.clone(),
+                        file: file.to_string(),
+                        revision: self.revision.clone(),
+                        sha256: None,
+                    })
+                };
+                Ok([resolve(CONFIG_FILE)?, resolve(TOKENIZER_FILE)?, resolve(WEIGHTS_FILE)?])
+            },
+            _ => anyhow::bail!("embedding model {} needs either a path or a repo", self.name),
+        }
+    }
+}
+
+// The part of config.json the BERT config keeps to itself
+#[derive(Deserialize)]
+struct Limits {
+    max_position_embeddings: usize,
+}
+
+/// The embeddings of a list of texts, in the same order.
+pub struct Embeddings {
+    pub vectors: Vec<Vec<f32>>,
+    /// Tokens of all the texts together, after truncation
+    pub tokens: usize,
+}
+
+/// Turns texts into vectors with the mean of the last hidden states of their tokens.
+pub struct Embedder {
+    model: BertModel,
+    tokenizer: Tokenizer,
+    device: Device,
+    max_tokens: usize,
+    batch_size: usize,
+    normalize: bool,
+}
+
+impl Embedder {
+    pub fn new(config: &EmbeddingConfig, hub: &Hub) -> anyhow::Result<Embedder> {
+        let start = Instant::now();
+        let [config_path, tokenizer_path, weights_path] = config.files(hub)?;
+        let bert_config = std::fs::read_to_string(&config_path)?;
+        let max_tokens = serde_json::from_str::<Limits>(&bert_config)?.max_position_embeddings;
+        let bert_config: BertConfig = serde_json::from_str(&bert_config)?;
+        let mut tokenizer = Tokenizer::from_file(tokenizer_path).map_err(anyhow::Error::msg)?;
+        // Batches never need padding, and truncation keeps the final [SEP], whatever
+        // tokenizer.json configures
+        tokenizer.with_padding(None);
+        tokenizer.with_truncation(None).map_err(anyhow::Error::msg)?;
+        let device = Device::Cpu;
+        // Like the GGUF models, the weights are read straight from the page cache
+        let vb = unsafe { VarBuilder::from_mmaped_safetensors(&[weights_path], DTYPE, &device)? };
+        let model = BertModel::load(vb, &bert_config)?;
+        tracing::info!(
+            model = config.name,
+            max_tokens,
+            elapsed_s = start.elapsed().as_secs_f32(),
+            "Embedding model built",
+        );
+        Ok(Embedder {
+            model,
+            tokenizer,
+            device,
+            max_tokens,
+            batch_size: config.batch_size.unwrap_or(32).max(1),
+            normalize: config.normalize.unwrap_or(true),
+        })
+    }
+
+    /// Embeds the texts, those with the same number of tokens in one batch. Texts longer than the
+    /// model's context are cut off.
+    pub fn embed(&self, texts: &[String]) -> anyhow::Result<Embeddings> {
+        let _span = tracing::trace_span!("embed", texts = texts.len()).entered();
+        let encodings = self.tokenizer.encode_batch(texts.to_vec(), true).map_err(anyhow::Error::msg)?;
+        let tokens: Vec<Vec<u32>> = encodings
+            .iter()
+            .map(|encoding| truncate(encoding.get_ids(), self.max_tokens))
+            .collect();
+        let lengths: Vec<usize> = tokens.iter().map(Vec::len).collect();
+        let mut vectors = vec![vec![]; texts.len()];
+        for batch in batches(&lengths, self.batch_size) {
+            let embedded = self.embed_batch(&batch.iter().map(|&i| tokens[i].as_slice()).collect::<Vec<_>>())?;
+            for (i, vector) in batch.into_iter().zip(embedded) {
+                vectors[i] = vector;
+            }
+        }
+        Ok(Embeddings { vectors, tokens: lengths.iter().sum() })
+    }
+
+    // Every text of the batch has the same number of tokens
+    fn embed_batch(&self, batch: &[&[u32]]) -> anyhow::Result<Vec<Vec<f32>>> {
+        let _span = tracing::trace_span!("batch", size = batch.len()).entered();
+        let seq_len = batch.first().map_or(0, |tokens| tokens.len());
+        let ids = Tensor::from_vec(batch.concat(), (batch.len(), seq_len), &self.device)?;
+        let token_type_ids = ids.zeros_like()?;
+        let hidden = self.model.forward(&ids, &token_type_ids)?;
+        let mut pooled = (hidden.sum(1)? / seq_len as f64)?;
+        if self.normalize {
+            pooled = pooled.broadcast_div(&pooled.sqr()?.sum_keepdim(1)?.sqrt()?)?;
+        }
+        Ok(pooled.to_vec2::<f32>()?)
+    }
+}
+
+// Keeps the last token, the [SEP] that ends every input
+fn truncate(tokens: &[u32], max_tokens: usize) -> Vec<u32> {
+    if tokens.len() <= max_tokens || max_tokens == 0 {
+        return tokens.to_vec();
+    }
+    let mut truncated = tokens[..max_tokens - 1].to_vec();
+    truncated.extend(tokens.last());
+    truncated
+}
+
+// Indices of the texts per batch. This BERT has no attention mask, so real tokens would attend
+// to padding and a text's vector would depend on the rest of its batch. Only texts with the same
+// number of tokens share a batch instead.
+fn batches(lengths: &[usize], batch_size: usize) -> Vec<Vec<usize>> {
+    let mut order: Vec<usize> = (0..lengths.len()).collect();
+    order.sort_by_key(|&i| lengths[i]);
+    let mut batches: Vec<Vec<usize>> = vec![];
+    for i in order {
+        match batches.last_mut() {
+            Some(batch) if batch.len() < batch_size && lengths[batch[0]] == lengths[i] => batch.push(i),
+            _ => batches.push(vec![i]),
+        }
+    }
+    batches
+}
+
+/// The configured embedding model, loaded on first use.
+pub struct EmbeddingModel {
+    config: EmbeddingConfig,
+    hub: Hub,
+    metrics: Arc<Metrics>,
+    embedder: tokio::sync::OnceCell<Arc<Embedder>>,
+}
+
+impl EmbeddingModel {
+    pub fn new(config: EmbeddingConfig, hub: Hub, metrics: Arc<Metrics>) -> EmbeddingModel {
+        EmbeddingModel { config, hub, metrics, embedder: tokio::sync::OnceCell::new() }
+    }
+
+    pub fn name(&self) -> &str {
+        &self.config.name
+    }
+
+    /// The embedder, which is loaded first unless that happened already. A failed load is
+    /// tried again on the next call.
+    pub async fn get(&self) -> anyhow::Result<Arc<Embedder>> {
+        let embedder = self.embedder.get_or_try_init(|| async {
+            let start = Instant::now();
+            let (config, hub) = (self.config.clone(), self.hub.clone());
+            let embedder = tokio::task::spawn_blocking(move || Embedder::new(&config, &hub)).await??;
+            self.metrics.observe_model_load(&self.config.name, start.elapsed());
+            Ok::<_, anyhow::Error>(Arc::new(embedder))
+        });
+        Ok(embedder.await?.clone())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn batches_equal_lengths() {
+        let lengths = [7, 3, 12, 3, 5, 3, 7];
+        assert_eq!(batches(&lengths, 2), [vec![1, 3], vec![5], vec![4], vec![0, 6], vec![2]]);
+        assert_eq!(batches(&lengths, 10), [vec![1, 3, 5], vec![4], vec![0, 6], vec![2]]);
+        assert!(batches(&[], 4).is_empty());
+    }
+
+    #[test]
+    fn truncation_keeps_the_separator() {
+        assert_eq!(truncate(&[101, 7, 8, 9, 102], 4), [101, 7, 8, 102]);
+        assert_eq!(truncate(&[101, 7, 102], 4), [101, 7, 102]);
+    }
+
+    // Needs the model files, run with `cargo test -- --ignored`
+    #[test]
+    #[ignore]
+    fn other_texts_in_the_request_do_not_change_an_embedding() {
+        let config = EmbeddingConfig {
+            name: String::from("all-MiniLM-L6-v2"),
+            path: Some(String::from("models/all-MiniLM-L6-v2")),
+            repo: None,
+            revision: None,
+            batch_size: None,
+            normalize: None,
+        };
+        let embedder = Embedder::new(&config, &Hub::new(true)).unwrap();
+        let long = "The quarterly planning covers hiring, budgets and the roadmap of every team. ".repeat(8);
+        let alone = embedder.embed(&[String::from("a")]).unwrap();
+        let together = embedder.embed(&[String::from("a"), long]).unwrap();
+        assert_eq!(alone.vectors[0], together.vectors[0]);
+    }
+}
@@ -0,0 +1,148 @@
+use std::io::Read;
+use std::path::{Path, PathBuf};
+
+use hf_hub::api::sync::ApiBuilder;
+use hf_hub::{Cache, Repo, RepoType};
+use sha2::{Digest, Sha256};
+
+#[derive(Debug)]
+pub enum HubError {
+    /// Offline and the file was never pulled
+    NotCached { repo: String, file: String },
+    Download { repo: String, file: String, reason: String },
+    ChecksumMismatch { path: PathBuf, expected: String, actual: String },
+    Io(PathBuf, std::io::Error),
+}
+
+impl std::fmt::Display for HubError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            HubError::NotCached { repo, file } => write!(
+                f,
+                "{file} of {repo} isn't in the local cache, run `cait models pull {repo} {file}` while online",
+            ),
+            HubError::Download { repo, file, reason } => write!(f, "failed to download {file} of {repo}: {reason}"),
+            HubError::ChecksumMismatch { path, expected, actual } => write!(
+                f,
+                "{} is corrupt, its SHA-256 is {actual} instead of {expected}",
+                path.display(),
+            ),
+            HubError::Io(path, e) => write!(f, "failed to read {}: {e}", path.display()),
+        }
+    }
+}
+
+impl std::error::Error for HubError {}
+
+/// A file of a model repository on the Hugging Face hub.
+#[derive(Clone, Debug)]
+pub struct HubFile {
+    pub repo: String,
+    pub file: String,
+    /// Branch, tag or commit, `main` by default
+    pub revision: Option<String>,
+    /// Expected SHA-256 in hex. Files stored with git LFS, like all model weights, are checked
+    /// against the hash the hub reports for them even without it.
+    pub sha256: Option<String>,
+}
+
+impl HubFile {
+    fn repo(&self) -> Repo {
+        let revision = self.revision.clone().unwrap_or_else(|| String::from("main"));
+        Repo::with_revision(self.repo.clone(), RepoType::Model, revision)
+    }
+
+    fn cached(&self) -> Option<PathBuf> {
+        Cache::default().repo(self.repo()).get(&self.file)
+    }
+
+    fn not_cached(&self) -> HubError {
+        HubError::NotCached { repo: self.repo.clone(), file: self.file.clone() }
+    }
+}
+
+/// Resolves model files through the hf-hub cache, `~/.cache/huggingface/hub` unless `HF_HOME`
+/// says otherwise, so they are shared with other tools using the hub.
+#[derive(Clone)]
+pub struct Hub {
+    offline: bool,
+}
+
+impl Hub {
+    /// Setting `HF_HUB_OFFLINE=1` works like passing `offline`.
+    pub fn new(offline: bool) -> Hub {
+        let offline = offline || std::env::var("HF_HUB_OFFLINE").map_or(false, |v| v == "1");
+        Hub { offline }
+    }
+
+    /// The local path of the file. Files that aren't cached yet are downloaded and verified,
+    /// when offline only the cache is used. Cached files are verified when a SHA-256 is given.
+    pub fn resolve(&self, hub_file: &HubFile) -> Result<PathBuf, HubError> {
+        match hub_file.cached() {
+            Some(path) => {
+                if hub_file.sha256.is_some() {
+                    verify(&path, hub_file.sha256.as_deref())?;
+                }
+                Ok(path)
+            },
+            None if self.offline => Err(hub_file.not_cached()),
+            None => self.pull(hub_file),
+        }
+    }
+
+    /// Downloads the file, unless it's cached already, and verifies it either way.
+    pub fn pull(&self, hub_file: &HubFile) -> Result<PathBuf, HubError> {
+        let download_error = |reason: String| HubError::Download {
+            repo: hub_file.repo.clone(),
+            file: hub_file.file.clone(),
+            reason,
+        };
+        let path = match hub_file.cached() {
+            Some(path) => path,
+            None if self.offline => return Err(hub_file.not_cached()),
+            None => {
+                tracing::info!("Downloading {} of {}", hub_file.file, hub_file.repo);
+                let api = ApiBuilder::new()
+                    .with_progress(true)
+                    .build()
+                    .map_err(|e| download_error(e.to_string()))?;
+                api.repo(hub_file.repo()).get(&hub_file.file).map_err(|e| download_error(e.to_string()))?
+            },
+        };
+        let sha256 = verify(&path, hub_file.sha256.as_deref())?;
+        tracing::info!("{} has SHA-256 {}", path.display(), sha256);
+        Ok(path)
+    }
+}
+
+/// Hashes the file and compares it with `expected`, or with the hash the hub stored it under.
+/// Returns the hash.
+pub fn verify(path: &Path, expected: Option<&str>) -> Result<String, HubError> {
+    // The cache links the file to a blob named after its etag, which is the SHA-256 for LFS files
+    let blob_sha256 = std::fs::canonicalize(path)
+        .ok()
+        .and_then(|blob| blob.file_name()?.to_str().map(String::from))
+        .filter(|name| name.len() == 64 && name.bytes().all(|b| b.is_ascii_hexdigit()));
+    let actual = sha256(path)?;
+    if let Some(expected) = expected.map(String::from).or(blob_sha256) {
+        if !actual.eq_ignore_ascii_case(&expected) {
+            return Err(HubError::ChecksumMismatch { path: path.to_path_buf(), expected, actual });
+        }
+    }
+    Ok(actual)
+}
+
+fn sha256(path: &Path) -> Result<String, HubError> {
+    let io_error = |e| HubError::Io(path.to_path_buf(), e);
+    let mut file = std::fs::File::open(path).map_err(io_error)?;
+    let mut hasher = Sha256::new();
+    let mut buffer = vec![0; 1 << 20];
+    loop {
+        let read = file.read(&mut buffer).map_err(io_error)?;
+        if read == 0 {
+            break;
+        }
+        hasher.update(&buffer[..read]);
+    }
+    Ok(hasher.finalize().iter().map(|b| format!("{b:02x}")).collect())
+}
@@ -7,43 +7,38 @@ extern crate accelerate_src;
 use tokenizers::Tokenizer;
 
 use candle_core::quantized::gguf_file;
-use candle_core::{Device, Tensor};
-use candle_transformers::generation::LogitsProcessor;
 
-use candle_transformers::models::quantized_llama as model;
-use model::ModelWeights;
 
 use futures_core::stream::Stream;
-use tokio::sync::Mutex;
+use serde::Serialize;
+use tokio::sync::mpsc;
+use tokio_stream::wrappers::UnboundedReceiverStream;
+use std::collections::HashMap;
+use std::pin::Pin;
 use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::task::{Context, Poll};
 
-fn extract_token(next_token: u32, tokenizer: &Tokenizer) -> String {
-    // Extracting the last token as a string is complicated, here we just apply some simple
-    // heuristics as it seems to work well enough for this example. See the following for more
-    // details:
-    // https://github.com/huggingface/tokenizers/issues/1141#issuecomment-1562644141
-    if let Some(text) = tokenizer.id_to_token(next_token) {
-        let text = text.replace('▁', " ");
-        let ascii = text
-            .strip_prefix("<0x")
-            .and_then(|t| t.strip_suffix('>'))
-            .and_then(|t| u8::from_str_radix(t, 16).ok());
-        match ascii {
-            None => return text,
-            Some(ascii) => {
-                if let Some(chr) = char::from_u32(ascii as u32) {
-                    if chr.is_ascii() {
-                        return chr.to_string();
-                    }
-                }
-                return String::from("");
-            }
-        }
-    }
-    String::from("")
-}
+pub mod chat;
+use chat::{ChatMessage, Role};
+mod context;
+mod decoder;
+mod grammar;
+mod model;
+mod prefix_cache;
+mod sampler;
+pub use sampler::Mirostat;
+mod speculative;
+use speculative::Draft;
+use sampler::Sampling;
+pub use prefix_cache::CacheStats;
+use prefix_cache::PrefixCache;
+mod stop;
+mod tokenizer;
+mod worker;
+use worker::Job;
 
-fn format_size(size_in_bytes: usize) -> String {
+pub fn format_size(size_in_bytes: usize) -> String {
     if size_in_bytes < 1_000 {
         format!("{}B", size_in_bytes)
     } else if size_in_bytes < 1_000_000 {
@@ -57,185 +52,612 @@ fn format_size(size_in_bytes: usize) -> String {
 
 pub struct Config {
     pub sample_len: usize,
+    pub max_sample_len: usize,
     pub top_p: Option<f64>,
-    pub seed: u64,
+    /// A random seed is drawn for every generation when not set
+    pub seed: Option<u64>,
     pub temperature: Option<f64>,
-    pub tracing: bool,
     pub verbose_prompt: bool,
     pub repeat_penalty: f32,
     pub repeat_last_n: usize,
-    //pub gqa: Option<usize>,
+    /// Subtracted from a token's logit once per occurrence in the last `repeat_last_n` tokens
+    pub frequency_penalty: f32,
+    /// Subtracted once from the logit of every token in the last `repeat_last_n` tokens
+    pub presence_penalty: f32,
+    pub top_k: Option<usize>,
+    /// Drops the tokens less likely than this fraction of the most likely one
+    pub min_p: Option<f64>,
+    pub typical_p: Option<f64>,
+    /// Replaces top-k, typical, top-p and min-p
+    pub mirostat: Option<Mirostat>,
+    /// Added to the logits of the tokens
+    pub logit_bias: HashMap<u32, f32>,
+    /// Tokens that are never sampled
+    pub banned_tokens: Vec<u32>,
+    /// The usual one of the model's architecture unless set
+    pub chat_template: Option<chat::Template>,
+    pub system_prompt: Option<String>,
+    pub stop: Vec<String>,
+    /// Generations that are decoded together in one forward pass
+    pub max_batch_size: usize,
+    /// Memory for the KV caches kept between the turns of conversations, in bytes
+    pub prefix_cache_size: usize,
+    /// Query heads per key/value head, for grouped-query models whose file doesn't record it
+    pub gqa: Option<usize>,
+    /// GGUF file of a small model with the same vocabulary that proposes tokens for the main
+    /// one to check. The answers are distributed as without it, but a seed picks other ones.
+    pub draft_model: Option<String>,
+    /// Tokens the draft model proposes per forward pass of the main one
+    pub lookahead: usize,
+    /// Called while loading with the fraction of the layers read so far
+    pub on_progress: Option<Box<dyn Fn(f32) + Send>>,
 }
 
 impl Default for Config {
     fn default() -> Self {
         Config {
             sample_len: 100,
+            max_sample_len: 1024,
             top_p: None,
-            seed: 299792458,
+            seed: None,
             temperature: Some(0.8),
-            tracing: true,
             verbose_prompt: false,
             repeat_penalty: 1.1,
             repeat_last_n: 64,
-            //gqa: None,
+            frequency_penalty: 0.0,
+            presence_penalty: 0.0,
+            top_k: None,
+            min_p: None,
+            typical_p: None,
+            mirostat: None,
+            logit_bias: HashMap::new(),
+            banned_tokens: vec![],
+            chat_template: None,
+            system_prompt: Some(String::from(
+                "You are Cait, a helpful assistant that answers employees' questions about their \
+                company's internal knowledge base. Answer concisely and say so when you don't know."
+            )),
+            stop: vec![],
+            max_batch_size: 4,
+            prefix_cache_size: 2_000_000_000,
+            gqa: None,
+            draft_model: None,
+            lookahead: 4,
+            on_progress: None,
         }
     }
 }
 
+/// Settings for a single generation, overriding the ones from `Config`.
+#[derive(Clone, Debug, Default)]
+pub struct Params {
+    pub temperature: Option<f64>,
+    pub top_p: Option<f64>,
+    pub seed: Option<u64>,
+    pub repeat_penalty: Option<f32>,
+    pub repeat_last_n: Option<usize>,
+    pub frequency_penalty: Option<f32>,
+    pub presence_penalty: Option<f32>,
+    pub top_k: Option<usize>,
+    pub min_p: Option<f64>,
+    pub typical_p: Option<f64>,
+    pub mirostat: Option<Mirostat>,
+    /// Merged into the biases from `Config`, overriding them for the same token
+    pub logit_bias: HashMap<u32, f32>,
+    /// Added to the banned tokens from `Config`
+    pub banned_tokens: Vec<u32>,
+    pub format: Option<Format>,
+    /// `format` as compiled by `Llama::validate`, so generating doesn't compile it again
+    pub compiled_format: Option<CompiledFormat>,
+    /// Reports the log probability of every generated token along with this many of the most
+    /// likely alternatives
+    pub logprobs: Option<usize>,
+    pub max_tokens: Option<usize>,
+    /// Added to the stop sequences from `Config`
+    pub stop: Vec<String>,
+}
+
+/// Restricts the generated text to a format, so tools can parse it. The generation ends as soon
+/// as the text is complete.
+#[derive(Clone, Debug)]
+pub enum Format {
+    /// A GBNF grammar as used by llama.cpp
+    Grammar(String),
+    /// JSON matching the schema
+    JsonSchema(serde_json::Value),
+}
+
+impl Format {
+    fn compile(&self) -> Result<CompiledFormat, grammar::GrammarError> {
+        let grammar = match self {
+            Format::Grammar(src) => grammar::Grammar::parse(src)?,
+            Format::JsonSchema(schema) => grammar::from_json_schema(schema)?,
+        };
+        Ok(CompiledFormat(Arc::new(grammar)))
+    }
+}
+
+/// A `Format` parsed into the grammar that constrains the generation.
+#[derive(Clone, Debug)]
+pub struct CompiledFormat(Arc<grammar::Grammar>);
+
+const MAX_TEMPERATURE: f64 = 2.0;
+const MAX_REPEAT_PENALTY: f32 = 2.0;
+const MAX_STOP_SEQUENCES: usize = 4;
+const MAX_PENALTY: f32 = 2.0;
+const MAX_TOP_LOGPROBS: usize = 20;
+const MAX_LOGIT_BIAS: f32 = 100.0;
+
+#[derive(Debug)]
+pub enum ParamsError {
+    InvalidTemperature,
+    InvalidTopP,
+    InvalidRepeatPenalty,
+    InvalidRepeatLastN,
+    InvalidFrequencyPenalty,
+    InvalidPresencePenalty,
+    InvalidTopK,
+    InvalidMinP,
+    InvalidTypicalP,
+    InvalidMirostat,
+    InvalidLogitBias,
+    UnknownToken(u32),
+    InvalidFormat(String),
+    InvalidLogprobs,
+    InvalidMaxTokens(usize),
+    TooManyStopSequences,
+}
+
+impl std::fmt::Display for ParamsError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ParamsError::InvalidTemperature => write!(f, "temperature must be between 0 and {MAX_TEMPERATURE}"),
+            ParamsError::InvalidTopP => write!(f, "top_p must be greater than 0 and at most 1"),
+            ParamsError::InvalidRepeatPenalty => write!(f, "repeat_penalty must be between 1 and {MAX_REPEAT_PENALTY}"),
+            ParamsError::InvalidRepeatLastN => write!(f, "repeat_last_n must be at most the context length"),
+            ParamsError::InvalidFrequencyPenalty => write!(f, "frequency_penalty must be between -{MAX_PENALTY} and {MAX_PENALTY}"),
+            ParamsError::InvalidPresencePenalty => write!(f, "presence_penalty must be between -{MAX_PENALTY} and {MAX_PENALTY}"),
+            ParamsError::InvalidTopK => write!(f, "top_k must be at least 1"),
+            ParamsError::InvalidMinP => write!(f, "min_p must be between 0 and 1"),
+            ParamsError::InvalidTypicalP => write!(f, "typical_p must be greater than 0 and at most 1"),
+            ParamsError::InvalidMirostat => write!(f, "mirostat tau must be positive and eta between 0 and 1"),
+            ParamsError::InvalidLogitBias => write!(f, "logit biases must be between -{MAX_LOGIT_BIAS} and {MAX_LOGIT_BIAS}"),
+            ParamsError::UnknownToken(id) => write!(f, "token {id} is not in the vocabulary"),
+            ParamsError::InvalidFormat(e) => write!(f, "{e}"),
+            ParamsError::InvalidLogprobs => write!(f, "logprobs must be at most {MAX_TOP_LOGPROBS}"),
+            ParamsError::InvalidMaxTokens(max) => write!(f, "max_tokens must be between 1 and {max}"),
+            ParamsError::TooManyStopSequences => write!(f, "at most {MAX_STOP_SEQUENCES} stop sequences are allowed"),
+        }
+    }
+}
+
+impl std::error::Error for ParamsError {}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Output {
+    /// Sent first, the seed the sampler was initialized with
+    Seed(u64),
+    Text(String),
+    /// Sent before the seed when the oldest messages of the conversation were left out to fit
+    /// into the context window
+    Compacted { dropped_messages: usize },
+    /// Sent before the text of every generated token when `Params::logprobs` is set
+    Logprob(TokenLogprob),
+    /// Sent last, unless the generation was cancelled or failed
+    Finished {
+        reason: FinishReason,
+        prompt_tokens: usize,
+        /// Prompt tokens whose keys and values came from the prefix cache
+        reused_tokens: usize,
+        completion_tokens: usize,
+        /// Time spent on the rest of the prompt
+        prompt_duration: std::time::Duration,
+        generation_duration: std::time::Duration,
+    },
+}
+
+/// How likely the model found a generated token, before any of the sampling settings.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct TokenLogprob {
+    pub token: u32,
+    pub text: String,
+    pub logprob: f32,
+    /// The most likely tokens at the same position, most likely first
+    pub top_logprobs: Vec<TopLogprob>,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct TopLogprob {
+    pub token: u32,
+    pub text: String,
+    pub logprob: f32,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum FinishReason {
+    /// The model produced an end-of-sequence token
+    Eos,
+    /// One of the stop sequences was generated
+    Stop,
+    /// The maximum number of tokens was reached
+    Length,
+}
+
+/// The stream of a running generation. Dropping it, e.g. because the client disconnected,
+/// cancels the generation before its next forward pass and releases the model.
+pub struct Generation {
+    inner: UnboundedReceiverStream<Result<Output, String>>,
+    cancelled: Arc<AtomicBool>,
+    finished: bool,
+}
+
+impl Generation {
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::Relaxed);
+    }
+}
+
+impl Stream for Generation {
+    type Item = Result<Output, String>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+        let poll = Pin::new(&mut this.inner).poll_next(cx);
+        if let Poll::Ready(None) = poll {
+            this.finished = true;
+        }
+        poll
+    }
+}
+
+impl Drop for Generation {
+    fn drop(&mut self) {
+        if !self.finished {
+            tracing::info!("Generation dropped before it finished, cancelling");
+            self.cancel();
+        }
+    }
+}
+
+// Number of tokens of the vocabulary stored in the GGUF metadata
+fn vocab_size(content: &gguf_file::Content) -> Option<usize> {
+    match content.metadata.get("tokenizer.ggml.tokens") {
+        Some(gguf_file::Value::Array(tokens)) => Some(tokens.len()),
+        _ => None,
+    }
+}
+
+fn load_draft(path: &str, vocab_size: Option<usize>) -> anyhow::Result<Box<dyn model::TextGenerator>> {
+    let start = std::time::Instant::now();
+    let file = std::fs::File::open(path)?;
+    let mmap = unsafe { memmap2::Mmap::map(&file)? };
+    let mut reader = std::io::Cursor::new(&mmap[..]);
+    let content = gguf_file::Content::read(&mut reader)?;
+    if let (Some(draft), Some(main)) = (vocab_size(&content), vocab_size) {
+        if draft != main {
+            anyhow::bail!("the draft model has {draft} tokens in its vocabulary, the main model {main}");
+        }
+    }
+    let model = model::load(content, &mut reader, None, &mut |_, _| {})?;
+    tracing::info!(
+        path,
+        architecture = model.architecture(),
+        elapsed_s = start.elapsed().as_secs_f32(),
+        "Draft model built",
+    );
+    Ok(model)
+}
+
 pub struct Llama {
-    model: Arc<Mutex<ModelWeights>>,
+    jobs: mpsc::UnboundedSender<Job>,
     tokenizer: Arc<Tokenizer>,
     sample_len: usize,
-    top_p: Option<f64>,
-    seed: u64,
-    temperature: Option<f64>,
-    verbose_prompt: bool,
-    repeat_penalty: f32,
-    repeat_last_n: usize,
-    //gqa: Option<usize>,
+    max_sample_len: usize,
+    seed: Option<u64>,
+    sampling: Sampling,
+    chat_template: chat::Template,
+    system_prompt: Option<String>,
+    stop: Vec<String>,
+    eos_token: Option<u32>,
+    cache_stats: Arc<CacheStats>,
+    context_length: usize,
 }
 
 impl Llama {
-    pub fn new(model_path: &str, tokenizer_path: &str, c: Config) -> anyhow::Result<Self> {
-        /*
-        use tracing_chrome::ChromeLayerBuilder;
-        use tracing_subscriber::prelude::*;
-        let _guard = if c.tracing {
-            let (chrome_layer, guard) = ChromeLayerBuilder::new().build();
-            tracing_subscriber::registry().with(chrome_layer).init();
-            Some(guard)
-        } else {
-            None
-        };
-        */
-        println!(
-            "avx: {}, neon: {}, simd128: {}, f16c: {}",
-            candle_core::utils::with_avx(),
-            candle_core::utils::with_neon(),
-            candle_core::utils::with_simd128(),
-            candle_core::utils::with_f16c()
+    /// Loads a GGUF model. The tokenizer is built from the GGUF metadata unless the path of a
+    /// tokenizer.json is given.
+    pub fn new(model_path: &str, tokenizer_path: Option<&str>, c: Config) -> anyhow::Result<Self> {
+        tracing::info!(
+            avx = candle_core::utils::with_avx(),
+            neon = candle_core::utils::with_neon(),
+            simd128 = candle_core::utils::with_simd128(),
+            f16c = candle_core::utils::with_f16c(),
+            "CPU features",
         );
 
-        let tokenizer_path = std::path::PathBuf::from(tokenizer_path);
-        let tokenizer = Tokenizer::from_file(tokenizer_path).map_err(anyhow::Error::msg)?;
-
-
-        let mut file = std::fs::File::open(model_path)?;
         let start = std::time::Instant::now();
-    
-        let model = gguf_file::Content::read(&mut file)?;
+        let file = std::fs::File::open(model_path)?;
+        // Tensors are read straight from the page cache instead of being copied through a
+        // buffered reader first. Model files are never written to while loaded.
+        let mmap = unsafe { memmap2::Mmap::map(&file)? };
+        let mut reader = std::io::Cursor::new(&mmap[..]);
+
+        let model = gguf_file::Content::read(&mut reader)?;
         let mut total_size_in_bytes = 0;
         for (_, tensor) in model.tensor_infos.iter() {
             let elem_count = tensor.shape.elem_count();
             total_size_in_bytes +=
                 elem_count * tensor.ggml_dtype.type_size() / tensor.ggml_dtype.blck_size();
         }
-        println!(
-            "loaded {:?} tensors ({}) in {:.2}s",
-            model.tensor_infos.len(),
-            &format_size(total_size_in_bytes),
-            start.elapsed().as_secs_f32(),
+        tracing::info!(
+            path = model_path,
+            tensors = model.tensor_infos.len(),
+            size = %format_size(total_size_in_bytes),
+            elapsed_s = start.elapsed().as_secs_f32(),
+            "Read GGUF header",
+        );
+        let tokenizer = match tokenizer_path {
+            Some(tokenizer_path) => {
+                let tokenizer_path = std::path::PathBuf::from(tokenizer_path);
+                Tokenizer::from_file(tokenizer_path).map_err(anyhow::Error::msg)?
+            },
+            None => tokenizer::from_gguf(&model.metadata)?,
+        };
+        let eos_token = model.metadata
+            .get("tokenizer.ggml.eos_token_id")
+            .and_then(|v| v.to_u32().ok())
+            .or_else(|| tokenizer.token_to_id("</s>"));
+        let on_progress = c.on_progress;
+        let vocab_size = vocab_size(&model);
+        let model = model::load(model, &mut reader, c.gqa, &mut |loaded, total| {
+            tracing::info!(layer = loaded, layers = total, "Loaded layer");
+            if let Some(on_progress) = &on_progress {
+                on_progress(loaded as f32 / total as f32);
+            }
+        })?;
+        drop(mmap);
+        let context_length = model.context_length();
+        let chat_template = c.chat_template.unwrap_or_else(|| chat::Template::for_architecture(model.architecture()));
+        tracing::info!(
+            architecture = model.architecture(),
+            chat_template = chat_template.name(),
+            context_length,
+            elapsed_s = start.elapsed().as_secs_f32(),
+            "Model built",
         );
-        let model = ModelWeights::from_gguf(model, &mut file)?;
-        
-        println!("model built");
+        let draft = match &c.draft_model {
+            Some(path) => Some(Draft { model: load_draft(path, vocab_size)?, lookahead: c.lookahead.max(1) }),
+            None => None,
+        };
+
+        let tokenizer = Arc::new(tokenizer);
+        let (jobs, jobs_rx) = mpsc::unbounded_channel();
+        let worker_tokenizer = tokenizer.clone();
+        let verbose_prompt = c.verbose_prompt;
+        let max_batch_size = c.max_batch_size;
+        let cache_stats = Arc::new(CacheStats::default());
+        let prefix_cache = PrefixCache::new(c.prefix_cache_size, cache_stats.clone());
+        std::thread::Builder::new()
+            .name(String::from("llama-compute"))
+            .spawn(move || worker::run(
+                model,
+                draft,
+                worker_tokenizer,
+                verbose_prompt,
+                max_batch_size,
+                prefix_cache,
+                jobs_rx,
+            ))?;
 
         Ok(Llama {
-            model: Arc::new(Mutex::new(model)),
-            tokenizer: Arc::new(tokenizer),
-            sample_len: c.sample_len.saturating_sub(1),
-            top_p: c.top_p,
+            jobs,
+            tokenizer,
+            sample_len: c.sample_len,
+            max_sample_len: c.max_sample_len,
             seed: c.seed,
-            temperature: c.temperature,
-            verbose_prompt: c.verbose_prompt,
-            repeat_penalty: c.repeat_penalty,
-            repeat_last_n: c.repeat_last_n,
-            //gqa: c.gqa,
+            sampling: Sampling {
+                repeat_penalty: c.repeat_penalty,
+                repeat_last_n: c.repeat_last_n,
+                frequency_penalty: c.frequency_penalty,
+                presence_penalty: c.presence_penalty,
+                logit_bias: c.logit_bias,
+                banned_tokens: c.banned_tokens,
+                temperature: c.temperature,
+                top_k: c.top_k,
+                typical_p: c.typical_p,
+                top_p: c.top_p,
+                min_p: c.min_p,
+                mirostat: c.mirostat,
+            },
+            chat_template,
+            system_prompt: c.system_prompt,
+            stop: c.stop,
+            eos_token,
+            cache_stats,
+            context_length,
         })
     }
 
-    pub fn run(&self, prompt: String) -> impl Stream<Item = Result<String, String>> {
-        let sample_len = self.sample_len;
-        let top_p = self.top_p;
-        let seed = self.seed;
-        let temperature = self.temperature;
-        let verbose_prompt = self.verbose_prompt;
-        let repeat_penalty = self.repeat_penalty;
-        let repeat_last_n = self.repeat_last_n;
-        let tokenizer = self.tokenizer.clone();
-        let model = self.model.clone();
-        async_stream::try_stream! {
-            let mut model = model.lock().await;
-            tracing::info!("Got lock on model in tokio::spawn");
-            let tokens = tokenizer.encode(prompt, true).map_err(anyhow::Error::msg)
-                .map_err(|e| format!("Error: {}", e))?;
-            if verbose_prompt {
-                for (token, id) in tokens.get_tokens().iter().zip(tokens.get_ids().iter()) {
-                    let token = token.replace('▁', " ").replace("<0x0A>", "\n");
-                    println!("{id:7} -> '{token}'");
-                }
+    /// Checks the params against the model, and compiles their format.
+    pub fn validate(&self, params: &mut Params) -> Result<(), ParamsError> {
+        if let Some(temperature) = params.temperature {
+            if !(0.0..=MAX_TEMPERATURE).contains(&temperature) {
+                return Err(ParamsError::InvalidTemperature);
+            }
+        }
+        if let Some(top_p) = params.top_p {
+            if !(top_p > 0.0 && top_p <= 1.0) {
+                return Err(ParamsError::InvalidTopP);
+            }
+        }
+        if let Some(repeat_penalty) = params.repeat_penalty {
+            if !(1.0..=MAX_REPEAT_PENALTY).contains(&repeat_penalty) {
+                return Err(ParamsError::InvalidRepeatPenalty);
+            }
+        }
+        if params.repeat_last_n.map_or(false, |n| n > self.context_length) {
+            return Err(ParamsError::InvalidRepeatLastN);
+        }
+        let penalty_range = -MAX_PENALTY..=MAX_PENALTY;
+        if params.frequency_penalty.map_or(false, |p| !penalty_range.contains(&p)) {
+            return Err(ParamsError::InvalidFrequencyPenalty);
+        }
+        if params.presence_penalty.map_or(false, |p| !penalty_range.contains(&p)) {
+            return Err(ParamsError::InvalidPresencePenalty);
+        }
+        if params.top_k == Some(0) {
+            return Err(ParamsError::InvalidTopK);
+        }
+        if params.min_p.map_or(false, |p| !(0.0..=1.0).contains(&p)) {
+            return Err(ParamsError::InvalidMinP);
+        }
+        if params.typical_p.map_or(false, |p| !(p > 0.0 && p <= 1.0)) {
+            return Err(ParamsError::InvalidTypicalP);
+        }
+        if let Some(Mirostat { tau, eta }) = params.mirostat {
+            if !(tau > 0.0 && eta > 0.0 && eta <= 1.0) {
+                return Err(ParamsError::InvalidMirostat);
             }
-            let pre_prompt_tokens = vec![];
-            let prompt_tokens = [&pre_prompt_tokens, tokens.get_ids()].concat();
-            let prompt_tokens = if prompt_tokens.len() + sample_len > model::MAX_SEQ_LEN - 10 {
-                let to_remove = prompt_tokens.len() + sample_len + 10 - model::MAX_SEQ_LEN;
-                prompt_tokens[prompt_tokens.len().saturating_sub(to_remove)..].to_vec()
-            } else {
-                prompt_tokens
-            };
-            let mut all_tokens = vec![];
-            let mut logits_processor = LogitsProcessor::new(seed, temperature, top_p);
-
-            let start_prompt_processing = std::time::Instant::now();
-            let mut next_token = {
-                let input = Tensor::new(prompt_tokens.as_slice(), &Device::Cpu)
-                    .map_err(|e| format!("Error: {}", e))?
-                    .unsqueeze(0).map_err(|e| format!("Error: {}", e))?;
-                let logits = model.forward(&input, 0)
-                    .map_err(|e| format!("Error: {}", e))?
-                    .squeeze(0).map_err(|e| format!("Error: {}", e))?;
-                logits_processor.sample(&logits).map_err(|e| format!("Error: {}", e))?
-            };
-            let prompt_dt = start_prompt_processing.elapsed();
-            all_tokens.push(next_token);
-            yield extract_token(next_token, &tokenizer);
-
-            let start_post_prompt = std::time::Instant::now();
-            tracing::info!("About to enter into for loop to generate tokens");
-            for index in 0..sample_len {
-                let input = Tensor::new(&[next_token], &Device::Cpu)
-                    .map_err(|e| format!("Error: {}", e))?
-                    .unsqueeze(0).map_err(|e| format!("Error: {}", e))?;
-                let logits = model.forward(&input, prompt_tokens.len() + index)
-                    .map_err(|e| format!("Error: {}", e))?
-                    .squeeze(0).map_err(|e| format!("Error: {}", e))?;
-                let logits = if repeat_penalty == 1. {
-                    logits
-                } else {
-                    let start_at = all_tokens.len().saturating_sub(repeat_last_n);
-                    candle_transformers::utils::apply_repeat_penalty(
-                        &logits,
-                        repeat_penalty,
-                        &all_tokens[start_at..],
-                    ).map_err(|e| format!("Error: {}", e))?
-                };
-                next_token = logits_processor.sample(&logits).map_err(|e| format!("Error: {}", e))?;
-                all_tokens.push(next_token);
-                yield extract_token(next_token, &tokenizer);
+        }
+        if params.logit_bias.values().any(|b| !(-MAX_LOGIT_BIAS..=MAX_LOGIT_BIAS).contains(b)) {
+            return Err(ParamsError::InvalidLogitBias);
+        }
+        let vocab_size = self.tokenizer.get_vocab_size(true) as u32;
+        let mut tokens = params.logit_bias.keys().chain(params.banned_tokens.iter());
+        if let Some(&id) = tokens.find(|&&id| id >= vocab_size) {
+            return Err(ParamsError::UnknownToken(id));
+        }
+        if let Some(format) = &params.format {
+            let compiled = format.compile().map_err(|e| ParamsError::InvalidFormat(e.to_string()))?;
+            params.compiled_format = Some(compiled);
+        }
+        if params.logprobs.map_or(false, |n| n > MAX_TOP_LOGPROBS) {
+            return Err(ParamsError::InvalidLogprobs);
+        }
+        if let Some(max_tokens) = params.max_tokens {
+            if max_tokens == 0 || max_tokens > self.max_sample_len {
+                return Err(ParamsError::InvalidMaxTokens(self.max_sample_len));
             }
-            let dt = start_post_prompt.elapsed();
-            println!(
-                "\n\n{:4} prompt tokens processed: {:.2} token/s",
-                prompt_tokens.len(),
-                prompt_tokens.len() as f64 / prompt_dt.as_secs_f64(),
-            );
-            println!(
-                "{:4} tokens generated: {:.2} token/s",
-                sample_len,
-                sample_len as f64 / dt.as_secs_f64(),
-            );
         }
+        if params.stop.len() > MAX_STOP_SEQUENCES {
+            return Err(ParamsError::TooManyStopSequences);
+        }
+        Ok(())
+    }
+
+    pub fn cache_stats(&self) -> &CacheStats {
+        &self.cache_stats
+    }
+
+    /// Answers the last user message of a conversation. The configured system prompt is
+    /// used unless the conversation starts with its own. Old turns that don't fit into the
+    /// context window next to the answer are left out. When a conversation id is given, the
+    /// KV cache is kept so the next turn only has to process the new messages.
+    pub fn chat(&self, conversation: Option<&str>, messages: &[ChatMessage], params: Params) -> Generation {
+        let has_system = messages.first().map_or(false, |m| m.role == Role::System);
+        let messages = match &self.system_prompt {
+            Some(system_prompt) if !has_system => {
+                let mut with_system = vec![ChatMessage::new(Role::System, system_prompt)];
+                with_system.extend_from_slice(messages);
+                with_system
+            },
+            _ => messages.to_vec(),
+        };
+        let sample_len = params.max_tokens.unwrap_or(self.sample_len);
+        let budget = self.context_length.saturating_sub(sample_len + 10);
+        let fitted = context::fit(&messages, self.chat_template, budget, |prompt| {
+            self.tokenizer.encode(prompt, false).map_or(0, |tokens| tokens.len())
+        });
+        if fitted.dropped_messages > 0 {
+            tracing::info!("Left out {} messages to fit the context window", fitted.dropped_messages);
+        }
+        let end_of_turn = self.chat_template
+            .end_of_turn()
+            .and_then(|token| self.tokenizer.token_to_id(token));
+        // The template already contains the BOS/EOS markers for every turn
+        self.generate(
+            fitted.prompt,
+            false,
+            end_of_turn,
+            conversation.map(String::from),
+            fitted.dropped_messages,
+            params,
+        )
+    }
+
+    pub fn run(&self, prompt: String, params: Params) -> Generation {
+        self.generate(prompt, true, None, None, 0, params)
+    }
+
+    // The sampling settings of `Config` with the ones of the request on top
+    fn sampling(&self, params: &Params) -> Sampling {
+        let defaults = &self.sampling;
+        let mut logit_bias = defaults.logit_bias.clone();
+        logit_bias.extend(&params.logit_bias);
+        Sampling {
+            repeat_penalty: params.repeat_penalty.unwrap_or(defaults.repeat_penalty),
+            repeat_last_n: params.repeat_last_n.unwrap_or(defaults.repeat_last_n),
+            frequency_penalty: params.frequency_penalty.unwrap_or(defaults.frequency_penalty),
+            presence_penalty: params.presence_penalty.unwrap_or(defaults.presence_penalty),
+            logit_bias,
+            banned_tokens: [defaults.banned_tokens.as_slice(), params.banned_tokens.as_slice()].concat(),
+            temperature: params.temperature.or(defaults.temperature),
+            top_k: params.top_k.or(defaults.top_k),
+            typical_p: params.typical_p.or(defaults.typical_p),
+            top_p: params.top_p.or(defaults.top_p),
+            min_p: params.min_p.or(defaults.min_p),
+            mirostat: params.mirostat.or(defaults.mirostat),
+        }
+    }
+
+    fn generate(
+        &self,
+        prompt: String,
+        add_special_tokens: bool,
+        end_of_turn: Option<u32>,
+        cache_key: Option<String>,
+        dropped_messages: usize,
+        params: Params,
+    ) -> Generation {
+        let cancelled = Arc::new(AtomicBool::new(false));
+        // Unbounded, so a client that reads slowly never stalls the compute thread and the rest
+        // of the batch. The buffer can't outgrow the outputs of `max_tokens` tokens.
+        let (output, output_rx) = mpsc::unbounded_channel();
+        if dropped_messages > 0 {
+            // Can't fail, the receiver is still here
+            let _ = output.send(Ok(Output::Compacted { dropped_messages }));
+        }
+        // Only compiled here when the params skipped `validate`
+        let compiled = match (&params.compiled_format, &params.format) {
+            (Some(compiled), _) => Ok(Some(compiled.clone())),
+            (None, format) => format.as_ref().map(Format::compile).transpose(),
+        };
+        let grammar = match compiled {
+            Ok(compiled) => compiled.map(|CompiledFormat(grammar)| grammar),
+            Err(e) => {
+                let _ = output.send(Err(format!("Error: {}", e)));
+                return Generation { inner: UnboundedReceiverStream::new(output_rx), cancelled, finished: false };
+            },
+        };
+        let job = Job {
+            prompt,
+            add_special_tokens,
+            sample_len: params.max_tokens.unwrap_or(self.sample_len),
+            seed: params.seed.or(self.seed).unwrap_or_else(rand::random),
+            sampling: self.sampling(&params),
+            grammar,
+            logprobs: params.logprobs,
+            eos_tokens: self.eos_token.into_iter().chain(end_of_turn).collect(),
+            stop: [self.stop.as_slice(), params.stop.as_slice()].concat(),
+            cache_key,
+            cancelled: cancelled.clone(),
+            output,
+        };
+        if let Err(mpsc::error::SendError(job)) = self.jobs.send(job) {
+            // The compute thread stopped, let the caller know instead of leaving it hanging
+            let _ = job.output.send(Err(String::from("Error: compute thread is not running")));
+        }
+        Generation { inner: UnboundedReceiverStream::new(output_rx), cancelled, finished: false }
     }
 
 }
\ No newline at end of file
@@ -0,0 +1,105 @@
+use super::chat::{ChatMessage, Role, Template};
+
+/// A conversation rendered to fit the context window.
+pub struct Fitted {
+    pub prompt: String,
+    /// Messages that were left out, the oldest ones
+    pub dropped_messages: usize,
+}
+
+/// Renders as much of the conversation as fits into `budget` tokens. The system prompt and the
+/// last message are always kept, older turns are dropped whole, starting with the oldest, until
+/// the rest fits. The conversation after the cut always starts with a user message, as the
+/// templates expect.
+///
+/// If the system prompt and the last message alone are too long, they are returned anyway and
+/// the compute thread cuts the prompt from the front as a last resort.
+pub fn fit(
+    messages: &[ChatMessage],
+    template: Template,
+    budget: usize,
+    count_tokens: impl Fn(&str) -> usize,
+) -> Fitted {
+    let (system, turns) = match messages.split_first() {
+        Some((first, rest)) if first.role == Role::System => (Some(first), rest),
+        _ => (None, messages),
+    };
+    // Every message is tokenized once, rendered on its own. That counts the markers around a
+    // whole prompt more than once, which only makes the cut a little earlier. Some templates
+    // only render the system prompt as part of a user turn.
+    let system_tokens = system.map_or(0, |system| {
+        count_tokens(&template.render(&[system.clone(), ChatMessage::new(Role::User, "")]))
+    });
+    let turn_tokens: Vec<usize> = turns
+        .iter()
+        .map(|turn| count_tokens(&template.render(std::slice::from_ref(turn))))
+        .collect();
+    let mut total = system_tokens + turn_tokens.iter().sum::<usize>();
+    let mut start = 0;
+    while start + 1 < turns.len() && total > budget {
+        total -= turn_tokens[start];
+        start += 1;
+        while start + 1 < turns.len() && turns[start].role != Role::User {
+            total -= turn_tokens[start];
+            start += 1;
+        }
+    }
+    let kept: Vec<ChatMessage> = system.into_iter().chain(&turns[start..]).cloned().collect();
+    Fitted { prompt: template.render(&kept), dropped_messages: start }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn words(text: &str) -> usize {
+        text.split_whitespace().count()
+    }
+
+    fn conversation() -> Vec<ChatMessage> {
+        vec![
+            ChatMessage::new(Role::System, "Be brief."),
+            ChatMessage::new(Role::User, "one two three four"),
+            ChatMessage::new(Role::Assistant, "five six seven eight"),
+            ChatMessage::new(Role::User, "nine ten"),
+            ChatMessage::new(Role::Assistant, "eleven twelve"),
+            ChatMessage::new(Role::User, "thirteen"),
+        ]
+    }
+
+    #[test]
+    fn keeps_everything_that_fits() {
+        let fitted = fit(&conversation(), Template::ChatMl, 1000, words);
+        assert_eq!(fitted.dropped_messages, 0);
+        assert_eq!(fitted.prompt, Template::ChatMl.render(&conversation()));
+    }
+
+    #[test]
+    fn drops_the_oldest_turns_and_keeps_the_system_prompt() {
+        let messages = conversation();
+        let fitted = fit(&messages, Template::ChatMl, 17, words);
+        assert_eq!(fitted.dropped_messages, 2);
+        let kept = [&messages[..1], &messages[3..]].concat();
+        assert_eq!(fitted.prompt, Template::ChatMl.render(&kept));
+    }
+
+    #[test]
+    fn kept_turns_start_with_a_user_message() {
+        let mut messages = conversation();
+        // An assistant greeting before the first question
+        messages.insert(1, ChatMessage::new(Role::Assistant, "Hi!"));
+        let fitted = fit(&messages, Template::ChatMl, 17, words);
+        assert_eq!(fitted.dropped_messages, 3);
+        assert!(fitted.prompt.contains("<|im_start|>user\nnine ten"));
+        assert!(!fitted.prompt.contains("five six"));
+    }
+
+    #[test]
+    fn an_oversized_last_message_is_returned_anyway() {
+        let messages = conversation();
+        let fitted = fit(&messages, Template::ChatMl, 1, words);
+        assert_eq!(fitted.dropped_messages, 4);
+        let kept = [&messages[..1], &messages[5..]].concat();
+        assert_eq!(fitted.prompt, Template::ChatMl.render(&kept));
+    }
+}
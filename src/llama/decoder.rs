@@ -0,0 +1,224 @@
+use std::sync::OnceLock;
+
+use tokenizers::decoders::DecoderWrapper;
+use tokenizers::pre_tokenizers::PreTokenizerWrapper;
+use tokenizers::Tokenizer;
+
+/// How the pieces of a vocabulary spell the bytes of their text.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Pieces {
+    /// Sentencepiece, as in Llama and Mistral: `▁` for a space and byte-fallback tokens like
+    /// `<0xE2>` for bytes without a piece of their own
+    SentencePiece,
+    /// GPT-2 byte-level BPE, as in Phi: every byte is one character, like `Ġ` for a space
+    ByteLevel,
+}
+
+impl Pieces {
+    pub fn of(tokenizer: &Tokenizer) -> Pieces {
+        let byte_level = matches!(tokenizer.get_decoder(), Some(DecoderWrapper::ByteLevel(_)))
+            || matches!(tokenizer.get_pre_tokenizer(), Some(PreTokenizerWrapper::ByteLevel(_)));
+        if byte_level {
+            Pieces::ByteLevel
+        } else {
+            Pieces::SentencePiece
+        }
+    }
+
+    /// The bytes of the text a piece stands for.
+    pub fn bytes(self, piece: &str) -> Vec<u8> {
+        match self {
+            Pieces::SentencePiece => match byte_fallback(piece) {
+                Some(byte) => vec![byte],
+                None => piece.replace('▁', " ").into_bytes(),
+            },
+            Pieces::ByteLevel => {
+                let mut bytes = Vec::with_capacity(piece.len());
+                for c in piece.chars() {
+                    match byte_level_byte(c) {
+                        Some(byte) => bytes.push(byte),
+                        // Not from the byte-to-unicode map, e.g. in an added token
+                        None => bytes.extend_from_slice(c.encode_utf8(&mut [0; 4]).as_bytes()),
+                    }
+                }
+                bytes
+            },
+        }
+    }
+}
+
+// GPT-2 keeps the printable bytes as the characters of the same code point and maps the others,
+// in order, to the characters from U+0100 on
+fn byte_level_byte(c: char) -> Option<u8> {
+    static UNPRINTABLE: OnceLock<Vec<u8>> = OnceLock::new();
+    let printable = |byte: u8| matches!(byte, b'!'..=b'~' | 0xA1..=0xAC | 0xAE..=0xFF);
+    let code = c as u32;
+    if code < 0x100 {
+        return Some(code as u8).filter(|&byte| printable(byte));
+    }
+    let unprintable = UNPRINTABLE.get_or_init(|| (0..=255).filter(|&byte| !printable(byte)).collect());
+    unprintable.get((code - 0x100) as usize).copied()
+}
+
+/// Turns a stream of tokens back into text. Byte tokens and multi-byte characters split across
+/// tokens are buffered until they form complete UTF-8, so every emitted string is valid text
+/// that can be sent to the client as is.
+pub struct TokenDecoder {
+    pieces: Pieces,
+    bytes: Vec<u8>,
+    at_start: bool,
+}
+
+impl TokenDecoder {
+    pub fn new(pieces: Pieces) -> TokenDecoder {
+        TokenDecoder { pieces, bytes: vec![], at_start: true }
+    }
+
+    pub fn next_token(&mut self, token: u32, tokenizer: &Tokenizer) -> Option<String> {
+        let piece = tokenizer.id_to_token(token)?;
+        if tokenizer.get_added_vocabulary().is_special_token(&piece) {
+            return None;
+        }
+        self.push_piece(&piece)
+    }
+
+    pub fn push_piece(&mut self, piece: &str) -> Option<String> {
+        self.bytes.extend(self.pieces.bytes(piece));
+        self.decode(false)
+    }
+
+    /// Returns whatever is still buffered once generation is over. Incomplete characters are
+    /// replaced with U+FFFD.
+    pub fn flush(&mut self) -> Option<String> {
+        self.decode(true)
+    }
+
+    fn decode(&mut self, flush: bool) -> Option<String> {
+        let mut text = String::new();
+        loop {
+            match std::str::from_utf8(&self.bytes) {
+                Ok(valid) => {
+                    text.push_str(valid);
+                    self.bytes.clear();
+                    break;
+                },
+                Err(e) => {
+                    let valid_up_to = e.valid_up_to();
+                    // Safe to unwrap, the bytes up to valid_up_to were just validated
+                    text.push_str(std::str::from_utf8(&self.bytes[..valid_up_to]).unwrap());
+                    match e.error_len() {
+                        Some(invalid_len) => {
+                            text.push(char::REPLACEMENT_CHARACTER);
+                            self.bytes.drain(..valid_up_to + invalid_len);
+                        },
+                        // The rest might be the start of a character that continues in the next token
+                        None if !flush => {
+                            self.bytes.drain(..valid_up_to);
+                            break;
+                        },
+                        None => {
+                            text.push(char::REPLACEMENT_CHARACTER);
+                            self.bytes.clear();
+                            break;
+                        },
+                    }
+                },
+            }
+        }
+        if self.at_start && !text.is_empty() {
+            // Sentencepiece prefixes the first word with a space, and byte-level models tend to
+            // start their answer with one
+            self.at_start = false;
+            if text.starts_with(' ') {
+                text.remove(0);
+            }
+        }
+        if text.is_empty() {
+            None
+        } else {
+            Some(text)
+        }
+    }
+}
+
+pub fn byte_fallback(piece: &str) -> Option<u8> {
+    piece
+        .strip_prefix("<0x")
+        .and_then(|t| t.strip_suffix('>'))
+        .filter(|t| t.len() == 2)
+        .and_then(|t| u8::from_str_radix(t, 16).ok())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn decode_all(pieces: &[&str]) -> Vec<String> {
+        decode_all_as(Pieces::SentencePiece, pieces)
+    }
+
+    fn decode_all_as(kind: Pieces, pieces: &[&str]) -> Vec<String> {
+        let mut decoder = TokenDecoder::new(kind);
+        let mut out: Vec<String> = pieces.iter().filter_map(|p| decoder.push_piece(p)).collect();
+        out.extend(decoder.flush());
+        out
+    }
+
+    #[test]
+    fn ascii_words_keep_spacing() {
+        assert_eq!(decode_all(&["▁Hello", ",", "▁world", "!"]), vec!["Hello", ",", " world", "!"]);
+    }
+
+    #[test]
+    fn ascii_byte_fallback() {
+        assert_eq!(decode_all(&["▁line", "<0x0A>", "next"]).concat(), "line\nnext");
+    }
+
+    #[test]
+    fn accented_name_from_byte_fallback() {
+        // "é" is 0xC3 0xA9
+        let out = decode_all(&["▁Jos", "<0xC3>", "<0xA9>", "▁said"]);
+        assert_eq!(out, vec!["Jos", "é", " said"]);
+    }
+
+    #[test]
+    fn emoji_split_across_four_tokens() {
+        // U+1F600 is 0xF0 0x9F 0x98 0x80
+        let mut decoder = TokenDecoder::new(Pieces::SentencePiece);
+        assert_eq!(decoder.push_piece("▁hi"), Some(String::from("hi")));
+        assert_eq!(decoder.push_piece("▁"), Some(String::from(" ")));
+        assert_eq!(decoder.push_piece("<0xF0>"), None);
+        assert_eq!(decoder.push_piece("<0x9F>"), None);
+        assert_eq!(decoder.push_piece("<0x98>"), None);
+        assert_eq!(decoder.push_piece("<0x80>"), Some(String::from("😀")));
+        assert_eq!(decoder.flush(), None);
+    }
+
+    #[test]
+    fn cjk_pieces() {
+        assert_eq!(decode_all(&["▁日本", "語", "<0xE3>", "<0x81>", "<0xAE>"]).concat(), "日本語の");
+    }
+
+    #[test]
+    fn invalid_bytes_are_replaced() {
+        assert_eq!(decode_all(&["a", "<0xFF>", "b"]).concat(), "a\u{FFFD}b");
+    }
+
+    #[test]
+    fn incomplete_character_at_end_is_flushed() {
+        assert_eq!(decode_all(&["▁ok", "<0xE2>", "<0x82>"]).concat(), "ok\u{FFFD}");
+    }
+
+    #[test]
+    fn only_first_leading_space_is_stripped() {
+        assert_eq!(decode_all(&["▁", "▁indented"]).concat(), " indented");
+    }
+
+    #[test]
+    fn byte_level_pieces() {
+        // "😀" is 0xF0 0x9F 0x98 0x80, which byte-level BPE spells "ðŁĺĢ"
+        let out = decode_all_as(Pieces::ByteLevel, &["ĠThe", "Ġanswer", ":", "Ċ", "ðŁĺ", "Ģ", "ĠcafÃ©"]);
+        assert_eq!(out, vec!["The", " answer", ":", "\n", "😀", " café"]);
+        assert_eq!(Pieces::ByteLevel.bytes("Ġ\u{0100}\u{0143}"), [0x20, 0x00, 0xAD]);
+    }
+}
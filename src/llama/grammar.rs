@@ -0,0 +1,698 @@
+// A subset of the GBNF grammars of llama.cpp, used to keep generations within a format:
+//
+//   root   ::= "{" ws pair ("," ws pair)* "}"
+//   pair   ::= [a-z]+ ws ":" ws ("yes" | "no") ws
+//   ws     ::= [ \t\n]*
+//
+// Rules end at the end of the line unless a parenthesis is still open. Literals and character
+// classes take the escapes `\n`, `\r`, `\t`, `\\`, `\"`, `\]`, `\xHH` and `\uHHHH`, `.` matches
+// any character and `*`, `+` and `?` repeat the item before them. Left recursion isn't
+// supported, right recursion is.
+
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+
+use tokenizers::Tokenizer;
+
+use super::decoder::Pieces;
+
+mod json_schema;
+pub use json_schema::from_json_schema;
+
+#[derive(Debug)]
+pub struct GrammarError(String);
+
+impl std::fmt::Display for GrammarError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "invalid grammar: {}", self.0)
+    }
+}
+
+impl std::error::Error for GrammarError {}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Element {
+    /// A character in one of the ranges, or in none of them when negated
+    Chars { ranges: Vec<(char, char)>, negated: bool },
+    Rule(usize),
+}
+
+impl Element {
+    fn char(c: char) -> Element {
+        Element::Chars { ranges: vec![(c, c)], negated: false }
+    }
+
+    fn matches(&self, c: char) -> bool {
+        match self {
+            Element::Chars { ranges, negated } => {
+                ranges.iter().any(|&(from, to)| (from..=to).contains(&c)) != *negated
+            },
+            Element::Rule(_) => false,
+        }
+    }
+}
+
+type Alternative = Vec<Element>;
+
+/// A parsed grammar, starting at the rule named `root`.
+#[derive(Debug)]
+pub struct Grammar {
+    // The alternatives of every rule, rules added for repetitions and groups have no name
+    rules: Vec<Vec<Alternative>>,
+    root: usize,
+}
+
+impl Grammar {
+    pub fn parse(src: &str) -> Result<Grammar, GrammarError> {
+        let mut parser = Parser {
+            src: src.chars().collect(),
+            pos: 0,
+            names: HashMap::new(),
+            rules: vec![],
+        };
+        parser.grammar()?;
+        let Some(&root) = parser.names.get("root") else {
+            return Err(GrammarError(String::from("there is no root rule")));
+        };
+        let mut rules = Vec::with_capacity(parser.rules.len());
+        for (id, rule) in parser.rules.into_iter().enumerate() {
+            match rule {
+                Some(rule) => rules.push(rule),
+                None => {
+                    let name = parser.names.iter().find(|(_, &i)| i == id).map_or("", |(n, _)| n);
+                    return Err(GrammarError(format!("rule {name} is used but never defined")));
+                },
+            }
+        }
+        let grammar = Grammar { rules, root };
+        grammar.check_left_recursion()?;
+        Ok(grammar)
+    }
+
+    // The matcher would expand a left-recursive rule forever
+    fn check_left_recursion(&self) -> Result<(), GrammarError> {
+        let mut nullable = vec![false; self.rules.len()];
+        loop {
+            let mut changed = false;
+            for (id, rule) in self.rules.iter().enumerate() {
+                let matches_nothing = |alt: &Alternative| {
+                    alt.iter().all(|e| matches!(e, Element::Rule(r) if nullable[*r]))
+                };
+                if !nullable[id] && rule.iter().any(matches_nothing) {
+                    nullable[id] = true;
+                    changed = true;
+                }
+            }
+            if !changed {
+                break;
+            }
+        }
+        // The rules each rule can start with, skipping over the ones that may match nothing
+        let leftmost: Vec<HashSet<usize>> = self.rules
+            .iter()
+            .map(|rule| {
+                let mut leftmost = HashSet::new();
+                for alt in rule {
+                    for element in alt {
+                        match element {
+                            Element::Rule(r) => {
+                                leftmost.insert(*r);
+                                if !nullable[*r] {
+                                    break;
+                                }
+                            },
+                            Element::Chars { .. } => break,
+                        }
+                    }
+                }
+                leftmost
+            })
+            .collect();
+        for start in 0..self.rules.len() {
+            let mut seen = HashSet::new();
+            let mut todo: Vec<usize> = leftmost[start].iter().copied().collect();
+            while let Some(rule) = todo.pop() {
+                if rule == start {
+                    return Err(GrammarError(String::from("left recursion isn't supported")));
+                }
+                if seen.insert(rule) {
+                    todo.extend(leftmost[rule].iter().copied());
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+struct Parser {
+    src: Vec<char>,
+    pos: usize,
+    names: HashMap<String, usize>,
+    rules: Vec<Option<Vec<Alternative>>>,
+}
+
+impl Parser {
+    fn error<T>(&self, message: &str) -> Result<T, GrammarError> {
+        let line = self.src[..self.pos.min(self.src.len())].iter().filter(|&&c| c == '\n').count() + 1;
+        Err(GrammarError(format!("{message} on line {line}")))
+    }
+
+    fn peek(&self) -> Option<char> {
+        self.src.get(self.pos).copied()
+    }
+
+    // Skips spaces and comments, newlines only when `newlines` is set
+    fn skip_space(&mut self, newlines: bool) {
+        while let Some(c) = self.peek() {
+            if c == '#' {
+                while self.peek().map_or(false, |c| c != '\n') {
+                    self.pos += 1;
+                }
+            } else if c == ' ' || c == '\t' || c == '\r' || (newlines && c == '\n') {
+                self.pos += 1;
+            } else {
+                break;
+            }
+        }
+    }
+
+    fn rule_id(&mut self, name: &str) -> usize {
+        if let Some(&id) = self.names.get(name) {
+            return id;
+        }
+        self.rules.push(None);
+        self.names.insert(name.to_string(), self.rules.len() - 1);
+        self.rules.len() - 1
+    }
+
+    fn add_rule(&mut self, alternatives: Vec<Alternative>) -> usize {
+        self.rules.push(Some(alternatives));
+        self.rules.len() - 1
+    }
+
+    fn grammar(&mut self) -> Result<(), GrammarError> {
+        loop {
+            self.skip_space(true);
+            if self.peek().is_none() {
+                return Ok(());
+            }
+            let name = self.name()?;
+            self.skip_space(false);
+            if !self.src[self.pos..].starts_with(&[':', ':', '=']) {
+                return self.error("expected ::=");
+            }
+            self.pos += 3;
+            self.skip_space(false);
+            let alternatives = self.alternatives(false)?;
+            let id = self.rule_id(&name);
+            if self.rules[id].is_some() {
+                return self.error(&format!("rule {name} is defined twice"));
+            }
+            self.rules[id] = Some(alternatives);
+            match self.peek() {
+                None | Some('\n') => {},
+                Some(c) => return self.error(&format!("unexpected {c:?}")),
+            }
+        }
+    }
+
+    fn name(&mut self) -> Result<String, GrammarError> {
+        let start = self.pos;
+        while self.peek().map_or(false, |c| c.is_ascii_alphanumeric() || c == '-' || c == '_') {
+            self.pos += 1;
+        }
+        if start == self.pos {
+            return self.error("expected a rule name");
+        }
+        Ok(self.src[start..self.pos].iter().collect())
+    }
+
+    fn alternatives(&mut self, nested: bool) -> Result<Vec<Alternative>, GrammarError> {
+        let mut alternatives = vec![self.sequence(nested)?];
+        while self.peek() == Some('|') {
+            self.pos += 1;
+            self.skip_space(true);
+            alternatives.push(self.sequence(nested)?);
+        }
+        Ok(alternatives)
+    }
+
+    fn sequence(&mut self, nested: bool) -> Result<Alternative, GrammarError> {
+        let mut sequence: Alternative = vec![];
+        // Where the last item starts, repetitions apply to everything after it
+        let mut last_item = 0;
+        loop {
+            let start = sequence.len();
+            match self.peek() {
+                Some('"') => {
+                    self.pos += 1;
+                    while self.peek() != Some('"') {
+                        let c = self.char()?;
+                        sequence.push(Element::char(c));
+                    }
+                    self.pos += 1;
+                },
+                Some('[') => {
+                    self.pos += 1;
+                    let negated = self.peek() == Some('^');
+                    if negated {
+                        self.pos += 1;
+                    }
+                    let mut ranges = vec![];
+                    while self.peek() != Some(']') {
+                        let from = self.char()?;
+                        let to = if self.peek() == Some('-') && self.src.get(self.pos + 1) != Some(&']') {
+                            self.pos += 1;
+                            self.char()?
+                        } else {
+                            from
+                        };
+                        ranges.push((from, to));
+                    }
+                    self.pos += 1;
+                    sequence.push(Element::Chars { ranges, negated });
+                },
+                Some('.') => {
+                    self.pos += 1;
+                    sequence.push(Element::Chars { ranges: vec![], negated: true });
+                },
+                Some('(') => {
+                    self.pos += 1;
+                    self.skip_space(true);
+                    let alternatives = self.alternatives(true)?;
+                    if self.peek() != Some(')') {
+                        return self.error("expected )");
+                    }
+                    self.pos += 1;
+                    let id = self.add_rule(alternatives);
+                    sequence.push(Element::Rule(id));
+                },
+                Some(c @ ('*' | '+' | '?')) => {
+                    if sequence.is_empty() {
+                        return self.error(&format!("{c} must follow an item"));
+                    }
+                    self.pos += 1;
+                    let item = sequence.split_off(last_item);
+                    let id = self.add_rule(vec![]);
+                    let mut repeated = item.clone();
+                    repeated.push(Element::Rule(id));
+                    self.rules[id] = Some(match c {
+                        '*' => vec![repeated, vec![]],
+                        '+' => vec![repeated, item],
+                        _ => vec![item, vec![]],
+                    });
+                    sequence.push(Element::Rule(id));
+                    self.skip_space(nested);
+                    continue;
+                },
+                Some(c) if c.is_ascii_alphanumeric() || c == '-' || c == '_' => {
+                    let name = self.name()?;
+                    let id = self.rule_id(&name);
+                    sequence.push(Element::Rule(id));
+                },
+                _ => return Ok(sequence),
+            }
+            last_item = start;
+            self.skip_space(nested);
+        }
+    }
+
+    // A character of a literal or a character class, with its escapes
+    fn char(&mut self) -> Result<char, GrammarError> {
+        let Some(c) = self.peek() else {
+            return self.error("unexpected end");
+        };
+        self.pos += 1;
+        if c != '\\' {
+            return Ok(c);
+        }
+        let Some(escaped) = self.peek() else {
+            return self.error("unexpected end");
+        };
+        self.pos += 1;
+        let hex_len = match escaped {
+            'n' => return Ok('\n'),
+            'r' => return Ok('\r'),
+            't' => return Ok('\t'),
+            'x' => 2,
+            'u' => 4,
+            _ => return Ok(escaped),
+        };
+        let hex: String = self.src.iter().skip(self.pos).take(hex_len).collect();
+        self.pos += hex_len;
+        match u32::from_str_radix(&hex, 16).ok().and_then(char::from_u32) {
+            Some(c) if hex.len() == hex_len => Ok(c),
+            _ => self.error("invalid escape"),
+        }
+    }
+}
+
+// The next element to match in an alternative of a rule
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+struct Pos {
+    rule: usize,
+    alt: usize,
+    at: usize,
+}
+
+/// Where a text is in a grammar. Every stack is one way to parse the text so far, with the
+/// element to match next on top. An empty stack means the text is complete.
+#[derive(Clone)]
+pub struct Matcher {
+    grammar: Arc<Grammar>,
+    stacks: Vec<Vec<Pos>>,
+}
+
+impl Matcher {
+    pub fn new(grammar: Arc<Grammar>) -> Matcher {
+        let mut stacks = vec![];
+        for alt in 0..grammar.rules[grammar.root].len() {
+            expand(&grammar, vec![Pos { rule: grammar.root, alt, at: 0 }], &mut stacks);
+        }
+        stacks.sort();
+        stacks.dedup();
+        Matcher { grammar, stacks }
+    }
+
+    /// The matcher after `text`, or None when the grammar doesn't allow it.
+    pub fn accept(&self, text: &str) -> Option<Matcher> {
+        let mut stacks = self.stacks.clone();
+        for c in text.chars() {
+            stacks = self.step(&stacks, c);
+            if stacks.is_empty() {
+                return None;
+            }
+        }
+        Some(Matcher { grammar: self.grammar.clone(), stacks })
+    }
+
+    /// Whether the text so far is a complete match.
+    pub fn is_complete(&self) -> bool {
+        self.stacks.iter().any(|stack| stack.is_empty())
+    }
+
+    fn step(&self, stacks: &[Vec<Pos>], c: char) -> Vec<Vec<Pos>> {
+        let mut next = vec![];
+        for stack in stacks {
+            let Some(&top) = stack.last() else { continue };
+            if !self.grammar.rules[top.rule][top.alt][top.at].matches(c) {
+                continue;
+            }
+            let mut stack = stack.clone();
+            stack.last_mut().unwrap().at += 1;
+            expand(&self.grammar, stack, &mut next);
+        }
+        next.sort();
+        next.dedup();
+        next
+    }
+
+    // Marks the tokens below `node` that the grammar allows after the stacks. Children are only
+    // visited while some stack still matches, so tokens sharing a prefix share its steps.
+    fn allow(&self, trie: &Trie, node: usize, stacks: &[Vec<Pos>], strip_space: bool, allowed: &mut [bool]) {
+        for &token in &trie.nodes[node].tokens {
+            if let Some(allowed) = allowed.get_mut(token as usize) {
+                *allowed = true;
+            }
+        }
+        for &(c, child) in &trie.nodes[node].children {
+            if strip_space && c == ' ' {
+                self.allow(trie, child, stacks, false, allowed);
+                continue;
+            }
+            let next = self.step(stacks, c);
+            if !next.is_empty() {
+                self.allow(trie, child, &next, false, allowed);
+            }
+        }
+    }
+}
+
+// Resolves rule references on top of the stack until a character class is on top, forking
+// the stack for every alternative
+fn expand(grammar: &Grammar, mut stack: Vec<Pos>, out: &mut Vec<Vec<Pos>>) {
+    let Some(&top) = stack.last() else {
+        out.push(stack);
+        return;
+    };
+    let alternative = &grammar.rules[top.rule][top.alt];
+    match alternative.get(top.at) {
+        None => {
+            stack.pop();
+            expand(grammar, stack, out);
+        },
+        Some(Element::Chars { .. }) => out.push(stack),
+        Some(&Element::Rule(rule)) => {
+            // Continue after the reference once the rule is done, leaving out finished ones
+            stack.last_mut().unwrap().at += 1;
+            if top.at + 1 == alternative.len() {
+                stack.pop();
+            }
+            for alt in 0..grammar.rules[rule].len() {
+                let mut stack = stack.clone();
+                stack.push(Pos { rule, alt, at: 0 });
+                expand(grammar, stack, out);
+            }
+        },
+    }
+}
+
+/// The text every token adds to a generation, None for special tokens and bytes that aren't
+/// text on their own, which constrained generations never pick.
+pub struct Vocabulary {
+    texts: Vec<Option<String>>,
+    trie: Trie,
+    pieces: Pieces,
+}
+
+impl Vocabulary {
+    pub fn new(tokenizer: &Tokenizer) -> Vocabulary {
+        let added = tokenizer.get_added_vocabulary();
+        let pieces = Pieces::of(tokenizer);
+        let texts = (0..tokenizer.get_vocab_size(true) as u32)
+            .map(|id| {
+                let piece = tokenizer.id_to_token(id)?;
+                if added.is_special_token(&piece) {
+                    return None;
+                }
+                String::from_utf8(pieces.bytes(&piece)).ok()
+            })
+            .collect();
+        Vocabulary::from_texts(texts, pieces)
+    }
+
+    fn from_texts(texts: Vec<Option<String>>, pieces: Pieces) -> Vocabulary {
+        let trie = Trie::new(&texts);
+        Vocabulary { texts, trie, pieces }
+    }
+
+    /// How the tokenizer spells the bytes of its tokens.
+    pub fn pieces(&self) -> Pieces {
+        self.pieces
+    }
+}
+
+// The token texts character by character, the root is the empty text
+struct Trie {
+    nodes: Vec<TrieNode>,
+}
+
+#[derive(Default)]
+struct TrieNode {
+    children: Vec<(char, usize)>,
+    // The tokens whose text ends here
+    tokens: Vec<u32>,
+}
+
+impl Trie {
+    fn new(texts: &[Option<String>]) -> Trie {
+        let mut nodes = vec![TrieNode::default()];
+        let mut edges: HashMap<(usize, char), usize> = HashMap::new();
+        for (token, text) in texts.iter().enumerate() {
+            let Some(text) = text else { continue };
+            let mut node = 0;
+            for c in text.chars() {
+                node = *edges.entry((node, c)).or_insert_with(|| {
+                    nodes.push(TrieNode::default());
+                    let child = nodes.len() - 1;
+                    nodes[node].children.push((c, child));
+                    child
+                });
+            }
+            nodes[node].tokens.push(token as u32);
+        }
+        Trie { nodes }
+    }
+}
+
+/// Keeps a generation within a grammar by masking the tokens that would leave it.
+#[derive(Clone)]
+pub struct Constraint {
+    matcher: Matcher,
+    // The decoder strips the space the first word starts with
+    at_start: bool,
+}
+
+impl Constraint {
+    pub fn new(grammar: Arc<Grammar>) -> Constraint {
+        Constraint { matcher: Matcher::new(grammar), at_start: true }
+    }
+
+    /// Sets the logits of the tokens the grammar doesn't allow next to -inf. The end of
+    /// sequence tokens are allowed once the text is complete, or when nothing else fits.
+    pub fn mask(&self, logits: &mut [f32], vocabulary: &Vocabulary, eos_tokens: &[u32]) {
+        let mut allowed = vec![false; logits.len()];
+        let matcher = &self.matcher;
+        matcher.allow(&vocabulary.trie, 0, &matcher.stacks, self.at_start, &mut allowed);
+        for &token in eos_tokens {
+            if let Some(allowed) = allowed.get_mut(token as usize) {
+                *allowed = matcher.is_complete();
+            }
+        }
+        for (logit, &allowed) in logits.iter_mut().zip(&allowed) {
+            if !allowed {
+                *logit = f32::NEG_INFINITY;
+            }
+        }
+        if !allowed.contains(&true) {
+            tracing::warn!("No token fits the grammar, ending the generation");
+            for &token in eos_tokens {
+                if let Some(logit) = logits.get_mut(token as usize) {
+                    *logit = 0.0;
+                }
+            }
+        }
+    }
+
+    /// Moves past a sampled token.
+    pub fn accept(&mut self, token: u32, vocabulary: &Vocabulary) {
+        let Some(text) = self.text(token, vocabulary) else { return };
+        if let Some(matcher) = self.matcher.accept(text) {
+            self.matcher = matcher;
+        }
+        if vocabulary.texts[token as usize].as_ref().map_or(false, |raw| !raw.is_empty()) {
+            self.at_start = false;
+        }
+    }
+
+    fn text<'a>(&self, token: u32, vocabulary: &'a Vocabulary) -> Option<&'a str> {
+        let text = vocabulary.texts.get(token as usize)?.as_deref()?;
+        if self.at_start {
+            Some(text.strip_prefix(' ').unwrap_or(text))
+        } else {
+            Some(text)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn matches(grammar: &str, text: &str) -> bool {
+        let grammar = Arc::new(Grammar::parse(grammar).unwrap());
+        Matcher::new(grammar).accept(text).map_or(false, |m| m.is_complete())
+    }
+
+    fn is_prefix(grammar: &str, text: &str) -> bool {
+        let grammar = Arc::new(Grammar::parse(grammar).unwrap());
+        Matcher::new(grammar).accept(text).is_some()
+    }
+
+    #[test]
+    fn literals_and_alternatives() {
+        let grammar = r#"root ::= "yes" | "no""#;
+        assert!(matches(grammar, "yes"));
+        assert!(matches(grammar, "no"));
+        assert!(!matches(grammar, "maybe"));
+        assert!(is_prefix(grammar, "ye"));
+        assert!(!matches(grammar, "ye"));
+        assert!(!is_prefix(grammar, "yess"));
+    }
+
+    #[test]
+    fn repetitions_and_classes() {
+        let grammar = r#"
+            root ::= [a-z]+ ("-" [0-9]*)? # a name with an optional number
+        "#;
+        assert!(matches(grammar, "ticket"));
+        assert!(matches(grammar, "ticket-"));
+        assert!(matches(grammar, "ticket-42"));
+        assert!(!matches(grammar, ""));
+        assert!(!matches(grammar, "Ticket"));
+        assert!(!matches(grammar, "ticket-4a"));
+    }
+
+    #[test]
+    fn negated_classes_and_escapes() {
+        let grammar = r#"root ::= "\"" [^"\\\x00-\x1f]* "\"""#;
+        assert!(matches(grammar, r#""plain text""#));
+        assert!(matches(grammar, r#""accents like é are fine""#));
+        assert!(!matches(grammar, r#""unescaped " quote""#));
+        assert!(!matches(grammar, "\"line\nbreak\""));
+    }
+
+    #[test]
+    fn rules_reference_each_other() {
+        let grammar = "
+root ::= list
+list ::= \"[\" (item (\",\" item)*)? \"]\"
+item ::= [0-9]+ | list
+";
+        assert!(matches(grammar, "[]"));
+        assert!(matches(grammar, "[1,[2,[]],3]"));
+        assert!(!matches(grammar, "[1,]"));
+        assert!(!matches(grammar, "[[1]"));
+    }
+
+    #[test]
+    fn invalid_grammars() {
+        assert!(Grammar::parse(r#"item ::= "a""#).is_err());
+        assert!(Grammar::parse(r#"root ::= missing"#).is_err());
+        assert!(Grammar::parse(r#"root ::= root "a" | "b""#).is_err());
+        assert!(Grammar::parse(r#"root ::= ("a""#).is_err());
+        assert!(Grammar::parse("root ::= \"a\"\nroot ::= \"b\"").is_err());
+    }
+
+    #[test]
+    fn mask_only_allows_fitting_tokens() {
+        let grammar = Arc::new(Grammar::parse(r#"root ::= "{" [0-9]+ "}""#).unwrap());
+        let texts = [None, Some(" {"), Some("1"), Some("}"), Some("x")];
+        let vocabulary = Vocabulary::from_texts(texts.map(|t| t.map(String::from)).to_vec(), Pieces::SentencePiece);
+        let eos = [0];
+        let mut constraint = Constraint::new(grammar);
+        let allowed = |constraint: &Constraint| {
+            let mut logits = vec![1.0; 5];
+            constraint.mask(&mut logits, &vocabulary, &eos);
+            logits.iter().enumerate().filter(|(_, l)| l.is_finite()).map(|(i, _)| i).collect::<Vec<_>>()
+        };
+        // The leading space of the first token is stripped like the decoder does
+        assert_eq!(allowed(&constraint), vec![1]);
+        constraint.accept(1, &vocabulary);
+        assert_eq!(allowed(&constraint), vec![2]);
+        constraint.accept(2, &vocabulary);
+        assert_eq!(allowed(&constraint), vec![2, 3]);
+        constraint.accept(3, &vocabulary);
+        assert_eq!(allowed(&constraint), vec![0]);
+    }
+
+    #[test]
+    fn mask_agrees_with_matching_every_token() {
+        let grammar = Arc::new(Grammar::parse(r#"root ::= "{" ws "\"id\":" ws [0-9]+ ws "}"
+ws ::= [ ]*"#).unwrap());
+        let texts = ["{", " {", "  {", "\"", "\"id", "\":", "id", " ", "  ", "1", "12", "3}", "}", " }", "", "x", "{\""];
+        let vocabulary = Vocabulary::from_texts(texts.iter().map(|t| Some(t.to_string())).collect(), Pieces::SentencePiece);
+        let mut constraint = Constraint::new(grammar);
+        for token in [1, 3, 6, 5, 7, 10, 13] {
+            let mut logits = vec![0.0; texts.len()];
+            constraint.mask(&mut logits, &vocabulary, &[]);
+            for (id, logit) in logits.iter().enumerate() {
+                let fits = constraint.text(id as u32, &vocabulary).map_or(false, |t| constraint.matcher.accept(t).is_some());
+                assert_eq!(logit.is_finite(), fits, "token {:?}", texts[id]);
+            }
+            assert!(logits[token].is_finite());
+            constraint.accept(token as u32, &vocabulary);
+        }
+        assert!(constraint.matcher.is_complete());
+    }
+}
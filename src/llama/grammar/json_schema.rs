@@ -0,0 +1,293 @@
+// Turns a JSON schema into a grammar for the JSON documents it describes. Every property of
+// an object is generated, optional ones included, in the order of their names. Formats,
+// patterns and length limits aren't enforced.
+
+use std::collections::{HashMap, HashSet};
+
+use serde_json::Value;
+
+use super::{Grammar, GrammarError};
+
+const PRIMITIVES: &str = r#"ws ::= [ \t\n]*
+string ::= "\"" ([^"\\\x00-\x1f] | "\\" (["\\/bfnrt] | "u" [0-9a-fA-F] [0-9a-fA-F] [0-9a-fA-F] [0-9a-fA-F]))* "\"" ws
+number ::= "-"? ("0" | [1-9] [0-9]*) ("." [0-9]+)? ([eE] [-+]? [0-9]+)? ws
+integer ::= "-"? ("0" | [1-9] [0-9]*) ws
+boolean ::= ("true" | "false") ws
+null ::= "null" ws
+value ::= object | array | string | number | boolean | null
+object ::= "{" ws (string ":" ws value ("," ws string ":" ws value)*)? "}" ws
+array ::= "[" ws (value ("," ws value)*)? "]" ws
+"#;
+
+/// The grammar of the JSON documents matching `schema`, with optional whitespace around them.
+pub fn from_json_schema(schema: &Value) -> Result<Grammar, GrammarError> {
+    let mut converter = Converter { root: schema, rules: vec![], names: HashSet::new(), refs: HashMap::new() };
+    for name in ["ws", "string", "number", "integer", "boolean", "null", "value", "object", "array"] {
+        converter.names.insert(name.to_string());
+    }
+    let value = converter.visit(schema, "root-value")?;
+    let mut src = format!("root ::= ws {value}\n{PRIMITIVES}");
+    for (name, body) in &converter.rules {
+        src.push_str(&format!("{name} ::= {body}\n"));
+    }
+    Grammar::parse(&src)
+}
+
+struct Converter<'a> {
+    root: &'a Value,
+    rules: Vec<(String, String)>,
+    names: HashSet<String>,
+    // Rules of the definitions `$ref` pointed to so far, recursive schemas reuse them
+    refs: HashMap<String, String>,
+}
+
+impl<'a> Converter<'a> {
+    fn rule(&mut self, name: &str, body: String) -> String {
+        let name = self.reserve(name);
+        self.rules.push((name.clone(), body));
+        name
+    }
+
+    fn reserve(&mut self, name: &str) -> String {
+        let base: String = name
+            .chars()
+            .map(|c| if c.is_ascii_alphanumeric() || c == '-' { c } else { '-' })
+            .collect();
+        let mut name = base.clone();
+        let mut n = 1;
+        while !self.names.insert(name.clone()) {
+            n += 1;
+            name = format!("{base}{n}");
+        }
+        name
+    }
+
+    // Returns the rule name or expression matching `schema` followed by whitespace
+    fn visit(&mut self, schema: &'a Value, name: &str) -> Result<String, GrammarError> {
+        let schema = match schema {
+            Value::Bool(true) => return Ok(String::from("value")),
+            Value::Bool(false) => return Err(unsupported("a schema that is always false")),
+            Value::Object(schema) => schema,
+            _ => return Err(unsupported("a schema that isn't an object")),
+        };
+        if let Some(reference) = schema.get("$ref") {
+            return self.reference(reference);
+        }
+        if let Some(value) = schema.get("const") {
+            return Ok(format!("{} ws", literal(value)));
+        }
+        if let Some(values) = schema.get("enum") {
+            let Some(values) = values.as_array().filter(|v| !v.is_empty()) else {
+                return Err(unsupported("an enum that isn't a list of values"));
+            };
+            let alternatives: Vec<String> = values.iter().map(literal).collect();
+            return Ok(self.rule(name, format!("({}) ws", alternatives.join(" | "))));
+        }
+        if let Some(schemas) = schema.get("anyOf").or_else(|| schema.get("oneOf")) {
+            let Some(schemas) = schemas.as_array().filter(|s| !s.is_empty()) else {
+                return Err(unsupported("anyOf or oneOf that isn't a list of schemas"));
+            };
+            let mut alternatives = vec![];
+            for (i, schema) in schemas.iter().enumerate() {
+                alternatives.push(self.visit(schema, &format!("{name}-{i}"))?);
+            }
+            return Ok(self.rule(name, alternatives.join(" | ")));
+        }
+        match schema.get("type") {
+            None if schema.contains_key("properties") => self.object(schema, name),
+            None => Ok(String::from("value")),
+            Some(Value::String(ty)) => self.typed(ty, schema, name),
+            Some(Value::Array(types)) => {
+                let mut alternatives = vec![];
+                for ty in types {
+                    let Some(ty) = ty.as_str() else {
+                        return Err(unsupported("a type that isn't a string"));
+                    };
+                    alternatives.push(self.typed(ty, schema, &format!("{name}-{ty}"))?);
+                }
+                Ok(self.rule(name, alternatives.join(" | ")))
+            },
+            Some(_) => Err(unsupported("a type that isn't a string")),
+        }
+    }
+
+    fn typed(
+        &mut self,
+        ty: &str,
+        schema: &'a serde_json::Map<String, Value>,
+        name: &str,
+    ) -> Result<String, GrammarError> {
+        match ty {
+            "object" => self.object(schema, name),
+            "array" => {
+                let item = match schema.get("items") {
+                    Some(items) => self.visit(items, &format!("{name}-item"))?,
+                    None => String::from("value"),
+                };
+                let body = format!(r#""[" ws ({item} ("," ws {item})*)? "]" ws"#);
+                Ok(self.rule(name, body))
+            },
+            "string" | "number" | "integer" | "boolean" | "null" => Ok(ty.to_string()),
+            _ => Err(unsupported(&format!("type {ty}"))),
+        }
+    }
+
+    fn object(&mut self, schema: &'a serde_json::Map<String, Value>, name: &str) -> Result<String, GrammarError> {
+        let Some(properties) = schema.get("properties").and_then(|p| p.as_object()) else {
+            return Ok(String::from("object"));
+        };
+        if properties.is_empty() {
+            return Ok(String::from(r#""{" ws "}" ws"#));
+        }
+        let mut pairs = vec![];
+        for (key, property) in properties {
+            let value = self.visit(property, &format!("{name}-{key}"))?;
+            pairs.push(format!(r#"{} ws ":" ws {value}"#, literal(&Value::String(key.clone()))));
+        }
+        let body = format!(r#""{{" ws {} "}}" ws"#, pairs.join(r#" "," ws "#));
+        Ok(self.rule(name, body))
+    }
+
+    fn reference(&mut self, reference: &Value) -> Result<String, GrammarError> {
+        let Some(pointer) = reference.as_str().and_then(|r| r.strip_prefix('#')) else {
+            return Err(unsupported("$ref outside of the schema"));
+        };
+        if let Some(name) = self.refs.get(pointer) {
+            return Ok(name.clone());
+        }
+        let Some(schema) = self.root.pointer(pointer) else {
+            return Err(GrammarError(format!("$ref #{pointer} points nowhere")));
+        };
+        // Reserved before visiting, so references back to it end up here
+        let name = self.reserve(&format!("ref{}", pointer.replace('/', "-")));
+        self.refs.insert(pointer.to_string(), name.clone());
+        let body = self.visit(schema, &format!("{name}-value"))?;
+        self.rules.push((name.clone(), body));
+        Ok(name)
+    }
+}
+
+fn unsupported(what: &str) -> GrammarError {
+    GrammarError(format!("JSON schemas with {what} aren't supported"))
+}
+
+// A grammar literal matching the JSON text of `value`
+fn literal(value: &Value) -> String {
+    let mut literal = String::from("\"");
+    for c in value.to_string().chars() {
+        match c {
+            '"' => literal.push_str("\\\""),
+            '\\' => literal.push_str("\\\\"),
+            '\n' => literal.push_str("\\n"),
+            c => literal.push(c),
+        }
+    }
+    literal.push('"');
+    literal
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use serde_json::json;
+
+    use super::super::Matcher;
+    use super::*;
+
+    fn matches(schema: &Value, text: &str) -> bool {
+        let grammar = Arc::new(from_json_schema(schema).unwrap());
+        Matcher::new(grammar).accept(text).map_or(false, |m| m.is_complete())
+    }
+
+    #[test]
+    fn nested_objects() {
+        let schema = json!({
+            "type": "object",
+            "properties": {
+                "ticket": {
+                    "type": "object",
+                    "properties": {
+                        "id": { "type": "integer" },
+                        "title": { "type": "string" },
+                        "reporter": {
+                            "type": "object",
+                            "properties": { "email": { "type": "string" } },
+                        },
+                    },
+                },
+                "tags": { "type": "array", "items": { "type": "string" } },
+            },
+        });
+        assert!(matches(&schema, r#"{"tags": [], "ticket": {"id": 7, "reporter": {"email": "a@b.c"}, "title": "VPN \"down\""}}"#));
+        assert!(matches(&schema, "{\n  \"tags\": [\"vpn\", \"urgent\"],\n  \"ticket\": {\"id\": -12, \"reporter\": {\"email\": \"\"}, \"title\": \"x\"}\n}\n"));
+        // Missing nested property
+        assert!(!matches(&schema, r#"{"tags": [], "ticket": {"id": 7, "reporter": {}, "title": "x"}}"#));
+        // Wrong type deep down
+        assert!(!matches(&schema, r#"{"tags": [], "ticket": {"id": "7", "reporter": {"email": "a"}, "title": "x"}}"#));
+        assert!(!matches(&schema, r#"{"tags": [1], "ticket": {"id": 7, "reporter": {"email": "a"}, "title": "x"}}"#));
+        // Unclosed and trailing text
+        assert!(!matches(&schema, r#"{"tags": [], "ticket": {"id": 7, "reporter": {"email": "a"}, "title": "x"}"#));
+        assert!(!matches(&schema, r#"{"tags": [], "ticket": {"id": 7, "reporter": {"email": "a"}, "title": "x"}} ok"#));
+    }
+
+    #[test]
+    fn enums() {
+        let schema = json!({
+            "type": "object",
+            "properties": {
+                "priority": { "enum": ["low", "medium", "high"] },
+                "status": { "type": "string", "enum": ["open", "closed"] },
+                "escalated": { "enum": [true, null, 2] },
+            },
+        });
+        assert!(matches(&schema, r#"{"escalated": true, "priority": "low", "status": "open"}"#));
+        assert!(matches(&schema, r#"{"escalated": null, "priority": "high", "status": "closed"}"#));
+        assert!(matches(&schema, r#"{"escalated": 2, "priority": "medium", "status": "open"}"#));
+        assert!(!matches(&schema, r#"{"escalated": false, "priority": "low", "status": "open"}"#));
+        assert!(!matches(&schema, r#"{"escalated": true, "priority": "urgent", "status": "open"}"#));
+        assert!(!matches(&schema, r#"{"escalated": true, "priority": "low", "status": "pending"}"#));
+        assert!(!matches(&schema, r#"{"escalated": true, "priority": "LOW", "status": "open"}"#));
+    }
+
+    #[test]
+    fn enum_of_nested_objects() {
+        let schema = json!({ "enum": [{ "a": [1, "x\"y"] }, "plain"] });
+        assert!(matches(&schema, r#"{"a":[1,"x\"y"]}"#));
+        assert!(matches(&schema, r#""plain""#));
+        assert!(!matches(&schema, r#"{"a":[1]}"#));
+    }
+
+    #[test]
+    fn recursive_references() {
+        let schema = json!({
+            "$defs": {
+                "node": {
+                    "type": "object",
+                    "properties": {
+                        "name": { "type": "string" },
+                        "children": { "type": "array", "items": { "$ref": "#/$defs/node" } },
+                    },
+                },
+            },
+            "$ref": "#/$defs/node",
+        });
+        assert!(matches(&schema, r#"{"children": [{"children": [], "name": "b"}], "name": "a"}"#));
+        assert!(!matches(&schema, r#"{"children": [{"name": "b"}], "name": "a"}"#));
+    }
+
+    #[test]
+    fn unions_and_untyped_values() {
+        let schema = json!({
+            "type": "object",
+            "properties": {
+                "assignee": { "type": ["string", "null"] },
+                "extra": {},
+                "score": { "anyOf": [{ "type": "number" }, { "type": "boolean" }] },
+            },
+        });
+        assert!(matches(&schema, r#"{"assignee": null, "extra": {"any": [1, {}]}, "score": 1.5e3}"#));
+        assert!(matches(&schema, r#"{"assignee": "sam", "extra": "x", "score": false}"#));
+        assert!(!matches(&schema, r#"{"assignee": 3, "extra": 1, "score": 1}"#));
+    }
+}
@@ -0,0 +1,244 @@
+// The model architectures the compute thread can run. The implementations are adapted from
+// candle_transformers' quantized models: the weights are immutable and the KV cache lives in a
+// separate `Cache` per sequence, so several sequences can share the weights and their decoding
+// steps can be batched into a single forward pass.
+
+use candle_core::quantized::gguf_file;
+use candle_core::{DType, Device, Result, Tensor, D};
+
+mod quantized_llama;
+mod quantized_phi;
+
+pub const MAX_SEQ_LEN: usize = 4096;
+
+/// A language model that turns tokens into the logits of the next token.
+pub trait TextGenerator: Send {
+    /// Name of the architecture, as in the GGUF metadata
+    fn architecture(&self) -> &'static str;
+
+    /// Number of tokens the model can attend to, prompt and answer together
+    fn context_length(&self) -> usize;
+
+    /// An empty KV cache for one sequence
+    fn new_cache(&self) -> Cache;
+
+    /// Feeds `seq_len` new tokens for every sequence of the batch, shape (batch, seq_len), each
+    /// sequence with its own cache. Returns the logits for the token following each sequence,
+    /// shape (batch, vocab).
+    fn forward_batch(&self, x: &Tensor, caches: &mut [&mut Cache]) -> Result<Tensor>;
+
+    /// Feeds new tokens of a single sequence, shape (1, seq_len), and returns the logits for
+    /// the token that follows them.
+    fn forward(&self, x: &Tensor, cache: &mut Cache) -> Result<Tensor> {
+        self.forward_batch(x, &mut [cache])?.squeeze(0)
+    }
+
+    /// Feeds new tokens of a single sequence, shape (1, seq_len), and returns the logits for
+    /// the token following each of them, shape (seq_len, vocab).
+    fn forward_all(&self, x: &Tensor, cache: &mut Cache) -> Result<Tensor>;
+}
+
+/// Builds the model for the architecture named in the GGUF metadata. `gqa` is the number of
+/// query heads per key/value head, used when the file doesn't say.
+pub fn load<R: std::io::Seek + std::io::Read>(
+    ct: gguf_file::Content,
+    reader: &mut R,
+    gqa: Option<usize>,
+    progress: &mut dyn FnMut(usize, usize),
+) -> Result<Box<dyn TextGenerator>> {
+    let architecture = match ct.metadata.get("general.architecture") {
+        Some(v) => v.to_string()?.clone(),
+        // Files converted before the architecture was recorded are all llama
+        None => String::from("llama"),
+    };
+    match architecture.as_str() {
+        "llama" => Ok(Box::new(quantized_llama::ModelWeights::from_gguf(ct, reader, "llama", gqa, progress)?)),
+        "mistral" => Ok(Box::new(quantized_llama::ModelWeights::from_gguf(ct, reader, "mistral", gqa, progress)?)),
+        "phi2" => Ok(Box::new(quantized_phi::ModelWeights::from_gguf(ct, reader, progress)?)),
+        _ => candle_core::bail!("unsupported model architecture {architecture}"),
+    }
+}
+
+/// The keys and values of every layer for one sequence.
+#[derive(Clone)]
+pub struct Cache {
+    kvs: Vec<Option<(Tensor, Tensor)>>,
+    len: usize,
+}
+
+impl Cache {
+    fn new(layers: usize) -> Cache {
+        Cache { kvs: vec![None; layers], len: 0 }
+    }
+
+    /// Number of tokens of the sequence that went through the model
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Forgets everything after the first `len` tokens.
+    pub fn truncate(&mut self, len: usize) -> Result<()> {
+        if len >= self.len {
+            return Ok(());
+        }
+        for kv in self.kvs.iter_mut() {
+            *kv = match kv.take() {
+                // Copy the kept part so the memory of the rest is released
+                Some((k, v)) if len > 0 => Some((
+                    k.narrow(2, 0, len)?.contiguous()?,
+                    v.narrow(2, 0, len)?.contiguous()?,
+                )),
+                _ => None,
+            };
+        }
+        self.len = len;
+        Ok(())
+    }
+
+    // A single layer cache of `len` tokens with `width` zeros per token for keys and values each
+    #[cfg(test)]
+    pub(super) fn zeros(len: usize, width: usize) -> Result<Cache> {
+        let kv = Tensor::zeros((1, 1, len, width), DType::F32, &Device::Cpu)?;
+        Ok(Cache { kvs: vec![Some((kv.clone(), kv))], len })
+    }
+
+    pub fn size_in_bytes(&self) -> usize {
+        self.kvs
+            .iter()
+            .flatten()
+            .map(|(k, v)| (k.elem_count() + v.elem_count()) * k.dtype().size_in_bytes())
+            .sum()
+    }
+}
+
+// Reads an architecture's hyperparameter, e.g. `llama.block_count`
+fn md_get<'a>(ct: &'a gguf_file::Content, arch: &str, key: &str) -> Result<&'a gguf_file::Value> {
+    match ct.metadata.get(&format!("{arch}.{key}")) {
+        None => candle_core::bail!("cannot find {arch}.{key} in metadata"),
+        Some(v) => Ok(v),
+    }
+}
+
+fn context_length(ct: &gguf_file::Content, arch: &str) -> usize {
+    md_get(ct, arch, "context_length")
+        .and_then(|v| v.to_u32())
+        .map_or(MAX_SEQ_LEN, |n| (n as usize).min(MAX_SEQ_LEN))
+}
+
+fn precomput_freqs_cis(head_dim: usize, freq_base: f32) -> Result<(Tensor, Tensor)> {
+    let theta: Vec<_> = (0..head_dim)
+        .step_by(2)
+        .map(|i| 1f32 / freq_base.powf(i as f32 / head_dim as f32))
+        .collect();
+    let theta = Tensor::new(theta.as_slice(), &Device::Cpu)?;
+    let idx_theta = Tensor::arange(0, MAX_SEQ_LEN as u32, &Device::Cpu)?
+        .to_dtype(DType::F32)?
+        .reshape((MAX_SEQ_LEN, 1))?
+        .matmul(&theta.reshape((1, theta.elem_count()))?)?;
+    let cos = idx_theta.cos()?;
+    let sin = idx_theta.sin()?;
+    Ok((cos, sin))
+}
+
+fn masked_fill(on_false: &Tensor, mask: &Tensor, on_true: f32) -> Result<Tensor> {
+    let shape = mask.shape();
+    let on_true = Tensor::new(on_true, on_false.device())?.broadcast_as(shape.dims())?;
+    mask.where_cond(&on_true, on_false)
+}
+
+// Masks the future for `seq_len` new tokens that follow `index_pos` cached ones
+fn causal_mask(seq_len: usize, index_pos: usize) -> Result<Tensor> {
+    let total = index_pos + seq_len;
+    let mask: Vec<u8> = (0..seq_len)
+        .flat_map(|i| (0..total).map(move |j| u8::from(j > i + index_pos)))
+        .collect();
+    Tensor::from_slice(&mask, (seq_len, total), &Device::Cpu)
+}
+
+fn repeat_kv(x: Tensor, n_rep: usize) -> Result<Tensor> {
+    if n_rep == 1 {
+        Ok(x)
+    } else {
+        let (b_sz, n_kv_head, seq_len, head_dim) = x.dims4()?;
+        x.unsqueeze(2)?
+            .expand((b_sz, n_kv_head, n_rep, seq_len, head_dim))?
+            .reshape((b_sz, n_kv_head * n_rep, seq_len, head_dim))
+    }
+}
+
+struct Heads {
+    n_head: usize,
+    n_kv_head: usize,
+    head_dim: usize,
+}
+
+// Attention of every sequence of the batch over its own cached keys and values. q has shape
+// (batch, seq_len, n_head * head_dim), k and v (batch, seq_len, n_kv_head * head_dim). The
+// rotary embedding differs between the architectures, `rotate` applies it to the queries and
+// keys of shape (1, heads, seq_len, head_dim) for the given position.
+fn attention(
+    q: &Tensor,
+    k: &Tensor,
+    v: &Tensor,
+    caches: &mut [&mut Cache],
+    layer_idx: usize,
+    heads: &Heads,
+    rotate: impl Fn(&Tensor, usize) -> Result<Tensor>,
+) -> Result<Tensor> {
+    let (_b_sz, seq_len, n_embd) = q.dims3()?;
+    let mut ys = Vec::with_capacity(caches.len());
+    for (i, cache) in caches.iter_mut().enumerate() {
+        let index_pos = cache.len;
+        let q = q
+            .narrow(0, i, 1)?
+            .reshape((1, seq_len, heads.n_head, heads.head_dim))?
+            .transpose(1, 2)?;
+        let k = k
+            .narrow(0, i, 1)?
+            .reshape((1, seq_len, heads.n_kv_head, heads.head_dim))?
+            .transpose(1, 2)?;
+        let v = v
+            .narrow(0, i, 1)?
+            .reshape((1, seq_len, heads.n_kv_head, heads.head_dim))?
+            .transpose(1, 2)?;
+
+        let q = rotate(&q.contiguous()?, index_pos)?;
+        let k = rotate(&k.contiguous()?, index_pos)?;
+
+        let kv_cache = &mut cache.kvs[layer_idx];
+        let (k, v) = match kv_cache.as_ref() {
+            Some((k_cache, v_cache)) if index_pos > 0 => {
+                let k = Tensor::cat(&[k_cache, &k], 2)?.contiguous()?;
+                let v = Tensor::cat(&[v_cache, &v], 2)?.contiguous()?;
+                (k, v)
+            },
+            _ => (k.contiguous()?, v.contiguous()?),
+        };
+        *kv_cache = Some((k.clone(), v.clone()));
+
+        // Support for MQA/GQA, useful for 70B models and mistral.
+        let n_rep = heads.n_head / heads.n_kv_head;
+        let k = repeat_kv(k, n_rep)?;
+        let v = repeat_kv(v, n_rep)?;
+
+        let att = (q.matmul(&k.t()?)? / (heads.head_dim as f64).sqrt())?;
+        let att = if seq_len == 1 {
+            att
+        } else {
+            let mask = causal_mask(seq_len, index_pos)?.broadcast_as(att.shape())?;
+            masked_fill(&att, &mask, f32::NEG_INFINITY)?
+        };
+        let att = candle_nn::ops::softmax(&att, D::Minus1)?;
+        // Convert to contiguous as matmul doesn't support strided vs for now.
+        let y = att.matmul(&v.contiguous()?)?;
+        ys.push(y.transpose(1, 2)?.reshape(&[1, seq_len, n_embd])?);
+    }
+    Tensor::cat(&ys, 0)
+}
+
+// The sequences' caches hold the new tokens now
+fn advance(caches: &mut [&mut Cache], seq_len: usize) {
+    for cache in caches.iter_mut() {
+        cache.len += seq_len;
+    }
+}
@@ -0,0 +1,220 @@
+// Llama and the models sharing its architecture, like Mistral.
+
+use candle_core::quantized::{gguf_file, QMatMul, QTensor};
+use candle_core::{Device, IndexOp, Result, Tensor, D};
+use candle_nn::{Embedding, Module};
+
+use super::{Cache, Heads, TextGenerator};
+
+struct RmsNorm {
+    inner: candle_nn::LayerNorm,
+}
+
+impl RmsNorm {
+    fn new(scale: QTensor, eps: f32) -> Result<Self> {
+        let scale = scale.dequantize(&Device::Cpu)?;
+        let inner = candle_nn::LayerNorm::rms_norm(scale, eps as f64);
+        Ok(Self { inner })
+    }
+
+    fn forward(&self, x: &Tensor) -> Result<Tensor> {
+        self.inner.forward(x)
+    }
+}
+
+struct LayerWeights {
+    attention_wq: QMatMul,
+    attention_wk: QMatMul,
+    attention_wv: QMatMul,
+    attention_wo: QMatMul,
+    attention_norm: RmsNorm,
+    feed_forward_w1: QMatMul,
+    feed_forward_w2: QMatMul,
+    feed_forward_w3: QMatMul,
+    ffn_norm: RmsNorm,
+}
+
+pub struct ModelWeights {
+    architecture: &'static str,
+    context_length: usize,
+    tok_embeddings: Embedding,
+    layers: Vec<LayerWeights>,
+    norm: RmsNorm,
+    output: QMatMul,
+    heads: Heads,
+    cos: Tensor,
+    sin: Tensor,
+}
+
+impl ModelWeights {
+    /// `arch` is the prefix of the hyperparameters in the metadata. Older files don't record
+    /// the number of key/value heads, `gqa` is used to derive it for grouped-query models.
+    /// `progress` is called with the number of layers loaded so far and the total.
+    pub fn from_gguf<R: std::io::Seek + std::io::Read>(
+        ct: gguf_file::Content,
+        reader: &mut R,
+        arch: &'static str,
+        gqa: Option<usize>,
+        progress: &mut dyn FnMut(usize, usize),
+    ) -> Result<Self> {
+        let md_get = |key: &str| super::md_get(&ct, arch, key);
+
+        let head_count = md_get("attention.head_count")?.to_u32()? as usize;
+        let head_count_kv = match md_get("attention.head_count_kv") {
+            Ok(v) => {
+                let head_count_kv = v.to_u32()? as usize;
+                if gqa.map_or(false, |gqa| gqa * head_count_kv != head_count) {
+                    tracing::warn!("Ignoring gqa, the model has {head_count_kv} key/value heads");
+                }
+                head_count_kv
+            },
+            Err(_) => head_count / gqa.unwrap_or(1),
+        };
+        if head_count_kv == 0 || head_count % head_count_kv != 0 {
+            candle_core::bail!("{head_count} heads can't be grouped into {head_count_kv} key/value heads");
+        }
+        let block_count = md_get("block_count")?.to_u32()? as usize;
+        let embedding_length = md_get("embedding_length")?.to_u32()? as usize;
+        let rope_dim = md_get("rope.dimension_count")?.to_u32()? as usize;
+        let rms_norm_eps = md_get("attention.layer_norm_rms_epsilon")?.to_f32()?;
+        let rope_freq_base = md_get("rope.freq_base")
+            .and_then(|m| m.to_f32())
+            .unwrap_or(10000f32);
+        let (cos, sin) = super::precomput_freqs_cis(rope_dim, rope_freq_base)?;
+        let context_length = super::context_length(&ct, arch);
+
+        let tok_embeddings = ct.tensor(reader, "token_embd.weight")?;
+        let tok_embeddings = tok_embeddings.dequantize(&Device::Cpu)?;
+        let norm = RmsNorm::new(ct.tensor(reader, "output_norm.weight")?, rms_norm_eps)?;
+        let output = ct.tensor(reader, "output.weight")?;
+        let mut layers = Vec::with_capacity(block_count);
+        for layer_idx in 0..block_count {
+            let prefix = format!("blk.{layer_idx}");
+            let attention_wq = ct.tensor(reader, &format!("{prefix}.attn_q.weight"))?;
+            let attention_wk = ct.tensor(reader, &format!("{prefix}.attn_k.weight"))?;
+            let attention_wv = ct.tensor(reader, &format!("{prefix}.attn_v.weight"))?;
+            let attention_wo = ct.tensor(reader, &format!("{prefix}.attn_output.weight"))?;
+            let feed_forward_w1 = ct.tensor(reader, &format!("{prefix}.ffn_gate.weight"))?;
+            let feed_forward_w2 = ct.tensor(reader, &format!("{prefix}.ffn_down.weight"))?;
+            let feed_forward_w3 = ct.tensor(reader, &format!("{prefix}.ffn_up.weight"))?;
+            let attention_norm = ct.tensor(reader, &format!("{prefix}.attn_norm.weight"))?;
+            let ffn_norm = ct.tensor(reader, &format!("{prefix}.ffn_norm.weight"))?;
+            layers.push(LayerWeights {
+                attention_wq: QMatMul::from_qtensor(attention_wq),
+                attention_wk: QMatMul::from_qtensor(attention_wk),
+                attention_wv: QMatMul::from_qtensor(attention_wv),
+                attention_wo: QMatMul::from_qtensor(attention_wo),
+                attention_norm: RmsNorm::new(attention_norm, rms_norm_eps)?,
+                feed_forward_w1: QMatMul::from_qtensor(feed_forward_w1),
+                feed_forward_w2: QMatMul::from_qtensor(feed_forward_w2),
+                feed_forward_w3: QMatMul::from_qtensor(feed_forward_w3),
+                ffn_norm: RmsNorm::new(ffn_norm, rms_norm_eps)?,
+            });
+            progress(layer_idx + 1, block_count);
+        }
+        Ok(Self {
+            architecture: arch,
+            context_length,
+            tok_embeddings: Embedding::new(tok_embeddings, embedding_length),
+            layers,
+            norm,
+            output: QMatMul::from_qtensor(output),
+            heads: Heads {
+                n_head: head_count,
+                n_kv_head: head_count_kv,
+                head_dim: embedding_length / head_count,
+            },
+            cos,
+            sin,
+        })
+    }
+
+    fn apply_rotary_emb(&self, x: &Tensor, index_pos: usize) -> Result<Tensor> {
+        let (b_sz, n_head, seq_len, n_embd) = x.dims4()?;
+        let cos = self.cos
+            .narrow(0, index_pos, seq_len)?
+            .reshape((seq_len, n_embd / 2, 1))?;
+        let sin = self.sin
+            .narrow(0, index_pos, seq_len)?
+            .reshape((seq_len, n_embd / 2, 1))?;
+        let cos = cos.broadcast_as((b_sz, n_head, seq_len, n_embd / 2, 1))?;
+        let sin = sin.broadcast_as((b_sz, n_head, seq_len, n_embd / 2, 1))?;
+        // This mimics the llama.cpp behavior.
+        // https://github.com/ggerganov/llama.cpp/blob/1f0bccb27929e261744c979bc75114955da49e98/ggml.c#L12104-L12105
+        // The x0 and x1 value are interleaved on the n_embd (= head_dim) dimension.
+        // The resulting y0 and y1 are also interleaved with:
+        //   y0 = x0*cos - x1*sin
+        //   y1 = x0*sin + x1*cos
+        let x = x.reshape((b_sz, n_head, seq_len, n_embd / 2, 2))?;
+        let x0 = x.narrow(D::Minus1, 0, 1)?;
+        let x1 = x.narrow(D::Minus1, 1, 1)?;
+        let y0 = (x0.broadcast_mul(&cos)? - x1.broadcast_mul(&sin)?)?;
+        let y1 = (x0.broadcast_mul(&sin)? + x1.broadcast_mul(&cos)?)?;
+        let rope = Tensor::cat(&[y0, y1], D::Minus1)?;
+        let rope = rope.flatten_from(D::Minus2)?;
+        Ok(rope)
+    }
+
+    // Runs the layers and returns the normalized output of the last one, before the logits
+    fn hidden_states(&self, x: &Tensor, caches: &mut [&mut Cache]) -> Result<Tensor> {
+        let (b_sz, seq_len) = x.dims2()?;
+        if b_sz != caches.len() {
+            candle_core::bail!("got {b_sz} sequences but {} caches", caches.len());
+        }
+        let _span = tracing::trace_span!("forward", batch = b_sz, seq_len).entered();
+        let mut layer_in = self.tok_embeddings.forward(x)?;
+        for (layer_idx, layer) in self.layers.iter().enumerate() {
+            let _span = tracing::trace_span!("layer", layer_idx).entered();
+            let x = layer_in;
+            let residual = &x;
+            let x = layer.attention_norm.forward(&x)?;
+            let q = layer.attention_wq.forward(&x)?;
+            let k = layer.attention_wk.forward(&x)?;
+            let v = layer.attention_wv.forward(&x)?;
+            let y = super::attention(&q, &k, &v, caches, layer_idx, &self.heads, |x, index_pos| {
+                self.apply_rotary_emb(x, index_pos)
+            })?;
+            let attn = layer.attention_wo.forward(&y)?;
+            let x = (attn + residual)?;
+
+            // MLP
+            let residual = &x;
+            let x = layer.ffn_norm.forward(&x)?;
+            let w1 = layer.feed_forward_w1.forward(&x)?;
+            let w3 = layer.feed_forward_w3.forward(&x)?;
+            let mlp = layer.feed_forward_w2.forward(&(candle_nn::ops::silu(&w1)? * w3)?)?;
+            layer_in = (mlp + residual)?;
+        }
+        super::advance(caches, seq_len);
+        self.norm.forward(&layer_in)
+    }
+}
+
+impl TextGenerator for ModelWeights {
+    fn architecture(&self) -> &'static str {
+        self.architecture
+    }
+
+    fn context_length(&self) -> usize {
+        self.context_length
+    }
+
+    fn new_cache(&self) -> Cache {
+        Cache::new(self.layers.len())
+    }
+
+    // The linear layers, where most of the time goes, run once for the whole batch while
+    // attention runs per sequence as their caches differ in length.
+    fn forward_batch(&self, x: &Tensor, caches: &mut [&mut Cache]) -> Result<Tensor> {
+        let seq_len = x.dim(1)?;
+        let x = self.hidden_states(x, caches)?.i((.., seq_len - 1, ..))?;
+        let _span = tracing::trace_span!("output").entered();
+        self.output.forward(&x)
+    }
+
+    fn forward_all(&self, x: &Tensor, cache: &mut Cache) -> Result<Tensor> {
+        let x = self.hidden_states(x, &mut [cache])?.squeeze(0)?;
+        let _span = tracing::trace_span!("output").entered();
+        self.output.forward(&x)
+    }
+}
@@ -0,0 +1,191 @@
+// Phi-2, with attention and MLP running in parallel off the same layer norm.
+
+use candle_core::quantized::{gguf_file, QMatMul, QTensor};
+use candle_core::{Device, IndexOp, Result, Tensor, D};
+use candle_nn::{Embedding, LayerNorm, Module};
+
+use super::{Cache, Heads, TextGenerator};
+
+const ARCH: &str = "phi2";
+
+struct QLinear {
+    inner: QMatMul,
+    bias: Tensor,
+}
+
+impl QLinear {
+    fn new<R: std::io::Read + std::io::Seek>(
+        ct: &gguf_file::Content,
+        r: &mut R,
+        name: &str,
+    ) -> Result<Self> {
+        let w = ct.tensor(r, &format!("{name}.weight"))?;
+        let b = ct.tensor(r, &format!("{name}.bias"))?;
+        Ok(Self { inner: QMatMul::from_qtensor(w), bias: b.dequantize(&Device::Cpu)? })
+    }
+
+    fn forward(&self, x: &Tensor) -> Result<Tensor> {
+        self.inner.forward(x)?.broadcast_add(&self.bias)
+    }
+}
+
+struct LayerWeights {
+    attn_qkv: QLinear,
+    attn_output: QLinear,
+    attn_norm: LayerNorm,
+    ffn_up: QLinear,
+    ffn_down: QLinear,
+}
+
+fn layer_norm(w: QTensor, b: QTensor, eps: f64) -> Result<LayerNorm> {
+    let w = w.dequantize(&Device::Cpu)?;
+    let b = b.dequantize(&Device::Cpu)?;
+    Ok(LayerNorm::new(w, b, eps))
+}
+
+pub struct ModelWeights {
+    context_length: usize,
+    tok_embeddings: Embedding,
+    layers: Vec<LayerWeights>,
+    output_norm: LayerNorm,
+    output: QLinear,
+    heads: Heads,
+    rope_dim: usize,
+    cos: Tensor,
+    sin: Tensor,
+}
+
+impl ModelWeights {
+    pub fn from_gguf<R: std::io::Seek + std::io::Read>(
+        ct: gguf_file::Content,
+        reader: &mut R,
+        progress: &mut dyn FnMut(usize, usize),
+    ) -> Result<Self> {
+        let md_get = |key: &str| super::md_get(&ct, ARCH, key);
+
+        let head_count = md_get("attention.head_count")?.to_u32()? as usize;
+        let block_count = md_get("block_count")?.to_u32()? as usize;
+        let embedding_length = md_get("embedding_length")?.to_u32()? as usize;
+        let rope_dim = md_get("rope.dimension_count")?.to_u32()? as usize;
+        let ln_eps = md_get("attention.layer_norm_epsilon")?.to_f32()? as f64;
+        let (cos, sin) = super::precomput_freqs_cis(rope_dim, 10_000.)?;
+        let context_length = super::context_length(&ct, ARCH);
+
+        let tok_embeddings = ct.tensor(reader, "token_embd.weight")?;
+        let tok_embeddings = tok_embeddings.dequantize(&Device::Cpu)?;
+        let output_norm = layer_norm(
+            ct.tensor(reader, "output_norm.weight")?,
+            ct.tensor(reader, "output_norm.bias")?,
+            ln_eps,
+        )?;
+        let output = QLinear::new(&ct, reader, "output")?;
+        let mut layers = Vec::with_capacity(block_count);
+        for layer_idx in 0..block_count {
+            let prefix = format!("blk.{layer_idx}");
+            layers.push(LayerWeights {
+                attn_qkv: QLinear::new(&ct, reader, &format!("{prefix}.attn_qkv"))?,
+                attn_output: QLinear::new(&ct, reader, &format!("{prefix}.attn_output"))?,
+                attn_norm: layer_norm(
+                    ct.tensor(reader, &format!("{prefix}.attn_norm.weight"))?,
+                    ct.tensor(reader, &format!("{prefix}.attn_norm.bias"))?,
+                    ln_eps,
+                )?,
+                ffn_up: QLinear::new(&ct, reader, &format!("{prefix}.ffn_up"))?,
+                ffn_down: QLinear::new(&ct, reader, &format!("{prefix}.ffn_down"))?,
+            });
+            progress(layer_idx + 1, block_count);
+        }
+        Ok(Self {
+            context_length,
+            tok_embeddings: Embedding::new(tok_embeddings, embedding_length),
+            layers,
+            output_norm,
+            output,
+            heads: Heads {
+                n_head: head_count,
+                // The fused qkv projection has as many key/value heads as query heads
+                n_kv_head: head_count,
+                head_dim: embedding_length / head_count,
+            },
+            rope_dim,
+            cos,
+            sin,
+        })
+    }
+
+    // Only the first `rope_dim` dimensions of every head are rotated, with the two halves of
+    // them paired up rather than neighbouring values as in llama.
+    fn apply_rotary_emb(&self, x: &Tensor, index_pos: usize) -> Result<Tensor> {
+        let (_b_sz, _n_head, seq_len, head_dim) = x.dims4()?;
+        let half = self.rope_dim / 2;
+        let cos = self.cos.narrow(0, index_pos, seq_len)?;
+        let sin = self.sin.narrow(0, index_pos, seq_len)?;
+        let cos = Tensor::cat(&[&cos, &cos], D::Minus1)?;
+        let sin = Tensor::cat(&[&sin, &sin], D::Minus1)?;
+        let x_rot = x.narrow(D::Minus1, 0, self.rope_dim)?;
+        let x1 = x_rot.narrow(D::Minus1, 0, half)?;
+        let x2 = x_rot.narrow(D::Minus1, half, half)?;
+        let rotated = Tensor::cat(&[&x2.neg()?, &x1], D::Minus1)?;
+        let x_rot = (x_rot.broadcast_mul(&cos)? + rotated.broadcast_mul(&sin)?)?;
+        if self.rope_dim == head_dim {
+            return Ok(x_rot);
+        }
+        let x_pass = x.narrow(D::Minus1, self.rope_dim, head_dim - self.rope_dim)?;
+        Tensor::cat(&[&x_rot, &x_pass], D::Minus1)
+    }
+
+    // Runs the layers and returns the normalized output of the last one, before the logits
+    fn hidden_states(&self, x: &Tensor, caches: &mut [&mut Cache]) -> Result<Tensor> {
+        let (b_sz, seq_len) = x.dims2()?;
+        if b_sz != caches.len() {
+            candle_core::bail!("got {b_sz} sequences but {} caches", caches.len());
+        }
+        let _span = tracing::trace_span!("forward", batch = b_sz, seq_len).entered();
+        let n_embd = self.heads.n_head * self.heads.head_dim;
+        let mut xs = self.tok_embeddings.forward(x)?;
+        for (layer_idx, layer) in self.layers.iter().enumerate() {
+            let _span = tracing::trace_span!("layer", layer_idx).entered();
+            let residual = &xs;
+            let xs_norm = layer.attn_norm.forward(&xs)?;
+            let qkv = layer.attn_qkv.forward(&xs_norm)?;
+            let q = qkv.narrow(D::Minus1, 0, n_embd)?;
+            let k = qkv.narrow(D::Minus1, n_embd, n_embd)?;
+            let v = qkv.narrow(D::Minus1, 2 * n_embd, n_embd)?;
+            let y = super::attention(&q, &k, &v, caches, layer_idx, &self.heads, |x, index_pos| {
+                self.apply_rotary_emb(x, index_pos)
+            })?;
+            let attn = layer.attn_output.forward(&y)?;
+            let mlp = layer.ffn_down.forward(&layer.ffn_up.forward(&xs_norm)?.gelu()?)?;
+            xs = ((attn + mlp)? + residual)?;
+        }
+        super::advance(caches, seq_len);
+        self.output_norm.forward(&xs)
+    }
+}
+
+impl TextGenerator for ModelWeights {
+    fn architecture(&self) -> &'static str {
+        ARCH
+    }
+
+    fn context_length(&self) -> usize {
+        self.context_length
+    }
+
+    fn new_cache(&self) -> Cache {
+        Cache::new(self.layers.len())
+    }
+
+    fn forward_batch(&self, x: &Tensor, caches: &mut [&mut Cache]) -> Result<Tensor> {
+        let seq_len = x.dim(1)?;
+        let xs = self.hidden_states(x, caches)?.i((.., seq_len - 1, ..))?;
+        let _span = tracing::trace_span!("output").entered();
+        self.output.forward(&xs)
+    }
+
+    fn forward_all(&self, x: &Tensor, cache: &mut Cache) -> Result<Tensor> {
+        let xs = self.hidden_states(x, &mut [cache])?.squeeze(0)?;
+        let _span = tracing::trace_span!("output").entered();
+        self.output.forward(&xs)
+    }
+}
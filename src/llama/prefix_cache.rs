@@ -0,0 +1,197 @@
+use std::collections::VecDeque;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+
+use super::model::Cache;
+
+/// Counters of the prefix cache, shared between the compute thread and the web handlers.
+#[derive(Default)]
+pub struct CacheStats {
+    lookups: AtomicU64,
+    hits: AtomicU64,
+    prompt_tokens: AtomicU64,
+    reused_tokens: AtomicU64,
+    evictions: AtomicU64,
+    entries: AtomicUsize,
+    size_in_bytes: AtomicUsize,
+}
+
+impl CacheStats {
+    pub fn lookups(&self) -> u64 {
+        self.lookups.load(Ordering::Relaxed)
+    }
+
+    /// Lookups that could reuse at least one token
+    pub fn hits(&self) -> u64 {
+        self.hits.load(Ordering::Relaxed)
+    }
+
+    pub fn hit_rate(&self) -> f64 {
+        match self.lookups() {
+            0 => 0.0,
+            lookups => self.hits() as f64 / lookups as f64,
+        }
+    }
+
+    pub fn prompt_tokens(&self) -> u64 {
+        self.prompt_tokens.load(Ordering::Relaxed)
+    }
+
+    /// Prompt tokens that didn't have to go through the model again
+    pub fn reused_tokens(&self) -> u64 {
+        self.reused_tokens.load(Ordering::Relaxed)
+    }
+
+    pub fn evictions(&self) -> u64 {
+        self.evictions.load(Ordering::Relaxed)
+    }
+
+    pub fn entries(&self) -> usize {
+        self.entries.load(Ordering::Relaxed)
+    }
+
+    pub fn size_in_bytes(&self) -> usize {
+        self.size_in_bytes.load(Ordering::Relaxed)
+    }
+}
+
+struct Entry {
+    key: String,
+    tokens: Vec<u32>,
+    cache: Cache,
+    size_in_bytes: usize,
+}
+
+/// The KV caches of finished generations, by conversation. The next turn of a conversation
+/// starts from the longest prefix its prompt shares with the cached tokens, so only the new
+/// messages go through the model. The least recently used caches are evicted to stay within
+/// the memory budget.
+pub struct PrefixCache {
+    budget: usize,
+    size_in_bytes: usize,
+    // Least recently used first
+    entries: VecDeque<Entry>,
+    stats: Arc<CacheStats>,
+}
+
+impl PrefixCache {
+    pub fn new(budget: usize, stats: Arc<CacheStats>) -> PrefixCache {
+        PrefixCache { budget, size_in_bytes: 0, entries: VecDeque::new(), stats }
+    }
+
+    /// Removes the cache of a conversation and truncates it to the prefix it shares with
+    /// `prompt`. At least the last prompt token is left to be forwarded, it's needed for the
+    /// logits of the first generated token.
+    pub fn take(&mut self, key: &str, prompt: &[u32]) -> Option<Cache> {
+        self.stats.lookups.fetch_add(1, Ordering::Relaxed);
+        self.stats.prompt_tokens.fetch_add(prompt.len() as u64, Ordering::Relaxed);
+        let at = self.entries.iter().position(|e| e.key == key)?;
+        let entry = self.remove(at);
+        let shared = entry.tokens
+            .iter()
+            .zip(prompt)
+            .take_while(|(a, b)| a == b)
+            .count()
+            .min(prompt.len().saturating_sub(1));
+        if shared == 0 {
+            return None;
+        }
+        let mut cache = entry.cache;
+        if let Err(e) = cache.truncate(shared) {
+            tracing::error!("Failed to truncate cached prefix: {}", e);
+            return None;
+        }
+        self.stats.hits.fetch_add(1, Ordering::Relaxed);
+        self.stats.reused_tokens.fetch_add(shared as u64, Ordering::Relaxed);
+        Some(cache)
+    }
+
+    /// Stores the cache of a conversation, `tokens` are the ones that went through the model.
+    pub fn put(&mut self, key: String, tokens: Vec<u32>, cache: Cache) {
+        if let Some(at) = self.entries.iter().position(|e| e.key == key) {
+            self.remove(at);
+        }
+        let size_in_bytes = cache.size_in_bytes();
+        if size_in_bytes > self.budget {
+            return;
+        }
+        while self.size_in_bytes + size_in_bytes > self.budget {
+            self.remove(0);
+            self.stats.evictions.fetch_add(1, Ordering::Relaxed);
+        }
+        self.size_in_bytes += size_in_bytes;
+        self.entries.push_back(Entry { key, tokens, cache, size_in_bytes });
+        self.update_stats();
+    }
+
+    fn remove(&mut self, at: usize) -> Entry {
+        let entry = self.entries.remove(at).expect("Index of an existing entry");
+        self.size_in_bytes -= entry.size_in_bytes;
+        self.update_stats();
+        entry
+    }
+
+    fn update_stats(&self) {
+        self.stats.entries.store(self.entries.len(), Ordering::Relaxed);
+        self.stats.size_in_bytes.store(self.size_in_bytes, Ordering::Relaxed);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // 8 bytes per token, a key and a value of one f32 each
+    fn cache(len: usize) -> Cache {
+        Cache::zeros(len, 1).unwrap()
+    }
+
+    #[test]
+    fn take_truncates_to_the_shared_prefix() {
+        let stats = Arc::new(CacheStats::default());
+        let mut prefix_cache = PrefixCache::new(1024, stats.clone());
+        prefix_cache.put(String::from("a"), vec![1, 2, 3, 4], cache(4));
+        assert_eq!((stats.entries(), stats.size_in_bytes()), (1, 32));
+
+        assert!(prefix_cache.take("b", &[1, 2, 3]).is_none());
+        let taken = prefix_cache.take("a", &[1, 2, 9, 9, 9]).unwrap();
+        assert_eq!((taken.len(), taken.size_in_bytes()), (2, 16));
+        // Taking removes the entry until the generation puts it back
+        assert_eq!((stats.entries(), stats.size_in_bytes()), (0, 0));
+        assert!(prefix_cache.take("a", &[1, 2, 9]).is_none());
+        assert_eq!((stats.lookups(), stats.hits(), stats.reused_tokens()), (3, 1, 2));
+        assert_eq!(stats.prompt_tokens(), 3 + 5 + 3);
+    }
+
+    #[test]
+    fn take_leaves_the_last_prompt_token() {
+        let mut prefix_cache = PrefixCache::new(1024, Arc::default());
+        prefix_cache.put(String::from("a"), vec![1, 2, 3], cache(3));
+        assert_eq!(prefix_cache.take("a", &[1, 2, 3]).unwrap().len(), 2);
+        prefix_cache.put(String::from("a"), vec![1, 2, 3], cache(3));
+        assert!(prefix_cache.take("a", &[1]).is_none());
+    }
+
+    #[test]
+    fn least_recently_used_are_evicted_within_the_budget() {
+        let stats = Arc::new(CacheStats::default());
+        let mut prefix_cache = PrefixCache::new(100, stats.clone());
+        prefix_cache.put(String::from("a"), vec![1; 4], cache(4));
+        prefix_cache.put(String::from("b"), vec![2; 4], cache(4));
+        prefix_cache.put(String::from("c"), vec![3; 4], cache(4));
+        // Putting "a" again makes "b" the least recently used
+        prefix_cache.put(String::from("a"), vec![1; 4], cache(4));
+        assert_eq!((stats.entries(), stats.size_in_bytes(), stats.evictions()), (3, 96, 0));
+        prefix_cache.put(String::from("d"), vec![4; 4], cache(4));
+        assert_eq!((stats.entries(), stats.size_in_bytes(), stats.evictions()), (3, 96, 1));
+        assert!(prefix_cache.take("b", &[2; 4]).is_none());
+
+        // A cache larger than the whole budget isn't kept, and doesn't evict the others
+        prefix_cache.put(String::from("e"), vec![5; 20], cache(20));
+        assert_eq!((stats.entries(), stats.evictions()), (3, 1));
+        assert!(prefix_cache.take("e", &[5; 20]).is_none());
+        for (key, token) in [("a", 1), ("c", 3), ("d", 4)] {
+            assert_eq!(prefix_cache.take(key, &[token; 4]).unwrap().len(), 3);
+        }
+    }
+}
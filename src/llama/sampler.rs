@@ -0,0 +1,306 @@
+use std::collections::HashMap;
+
+use rand::distributions::{Distribution, WeightedIndex};
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+
+/// Mirostat v2 keeps the surprise of the sampled tokens close to `tau` by adapting the
+/// truncation threshold at rate `eta`. It replaces top-k, typical, top-p and min-p.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Mirostat {
+    pub tau: f32,
+    pub eta: f32,
+}
+
+impl Mirostat {
+    /// The learning rate suggested by the paper
+    pub const DEFAULT_ETA: f32 = 0.1;
+}
+
+/// How the next token is picked from the logits. The steps run in the order of the fields:
+/// penalties and biases on the raw logits, then temperature, then the truncations. Without a
+/// temperature the most likely token is taken.
+#[derive(Clone, Debug, Default)]
+pub struct Sampling {
+    pub repeat_penalty: f32,
+    /// The penalties only look at this many of the last generated tokens
+    pub repeat_last_n: usize,
+    /// Subtracted once per occurrence of a token
+    pub frequency_penalty: f32,
+    /// Subtracted once from every token that occurred
+    pub presence_penalty: f32,
+    pub logit_bias: HashMap<u32, f32>,
+    pub banned_tokens: Vec<u32>,
+    pub temperature: Option<f64>,
+    pub top_k: Option<usize>,
+    pub typical_p: Option<f64>,
+    pub top_p: Option<f64>,
+    pub min_p: Option<f64>,
+    pub mirostat: Option<Mirostat>,
+}
+
+/// A token the next one may be, with its probability after all the sampling steps.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Candidate {
+    pub id: u32,
+    pub logit: f32,
+    pub p: f32,
+}
+
+pub struct Sampler {
+    sampling: Sampling,
+    rng: StdRng,
+    // The surprise threshold of mirostat, starts at twice the target
+    mu: f32,
+}
+
+impl Sampler {
+    pub fn new(seed: u64, sampling: Sampling) -> Sampler {
+        let mu = sampling.mirostat.map_or(0.0, |m| 2.0 * m.tau);
+        Sampler { sampling, rng: StdRng::seed_from_u64(seed), mu }
+    }
+
+    /// Picks the next token. `history` are the tokens generated so far.
+    pub fn sample(&mut self, logits: &[f32], history: &[u32]) -> u32 {
+        let candidates = self.candidates(logits, history);
+        let id = candidates[pick(&candidates, &mut self.rng)].id;
+        self.observe(&candidates, id);
+        id
+    }
+
+    /// The tokens the next one is drawn from, most likely first. Without a temperature that's
+    /// only the most likely token.
+    pub fn candidates(&self, logits: &[f32], history: &[u32]) -> Vec<Candidate> {
+        let mut logits = logits.to_vec();
+        self.penalize(&mut logits, history);
+        for (&id, &bias) in &self.sampling.logit_bias {
+            if let Some(logit) = logits.get_mut(id as usize) {
+                *logit += bias;
+            }
+        }
+        for &id in &self.sampling.banned_tokens {
+            if let Some(logit) = logits.get_mut(id as usize) {
+                *logit = f32::NEG_INFINITY;
+            }
+        }
+
+        let temperature = match self.sampling.temperature {
+            Some(temperature) if temperature > 0.0 => temperature as f32,
+            _ => return vec![most_likely(&logits)],
+        };
+        let mut candidates: Vec<Candidate> = logits
+            .iter()
+            .enumerate()
+            .filter(|(_, logit)| logit.is_finite())
+            .map(|(id, &logit)| Candidate { id: id as u32, logit: logit / temperature, p: 0.0 })
+            .collect();
+        if candidates.is_empty() {
+            // Everything is banned, fall back to the least bad token
+            return vec![most_likely(&logits)];
+        }
+        candidates.sort_by(|a, b| b.logit.total_cmp(&a.logit));
+        softmax(&mut candidates);
+
+        if self.sampling.mirostat.is_some() {
+            let keep = candidates.iter().take_while(|c| -c.p.log2() <= self.mu).count();
+            candidates.truncate(keep.max(1));
+            softmax(&mut candidates);
+            return candidates;
+        }
+        if let Some(k) = self.sampling.top_k {
+            candidates.truncate(k.max(1));
+            softmax(&mut candidates);
+        }
+        if let Some(p) = self.sampling.typical_p {
+            candidates = typical(candidates, p as f32);
+            softmax(&mut candidates);
+        }
+        if let Some(p) = self.sampling.top_p {
+            top_p(&mut candidates, p as f32);
+            softmax(&mut candidates);
+        }
+        if let Some(p) = self.sampling.min_p {
+            let threshold = candidates[0].p * p as f32;
+            let keep = candidates.iter().take_while(|c| c.p >= threshold).count();
+            candidates.truncate(keep.max(1));
+            softmax(&mut candidates);
+        }
+        candidates
+    }
+
+    /// Tells mirostat which of the `candidates` was taken, so it can adapt its threshold.
+    pub fn observe(&mut self, candidates: &[Candidate], id: u32) {
+        let Some(mirostat) = self.sampling.mirostat else { return };
+        if let Some(taken) = candidates.iter().find(|c| c.id == id) {
+            let surprise = -taken.p.log2();
+            self.mu -= mirostat.eta * (surprise - mirostat.tau);
+        }
+    }
+
+    pub fn rng(&mut self) -> &mut StdRng {
+        &mut self.rng
+    }
+
+    // Repetition penalty as in the CTRL paper, frequency and presence penalties as in the
+    // OpenAI API, all over the same window of recent tokens
+    fn penalize(&self, logits: &mut [f32], history: &[u32]) {
+        let s = &self.sampling;
+        if s.repeat_penalty == 1.0 && s.frequency_penalty == 0.0 && s.presence_penalty == 0.0 {
+            return;
+        }
+        let recent = &history[history.len().saturating_sub(s.repeat_last_n)..];
+        let mut counts: HashMap<u32, usize> = HashMap::new();
+        for &id in recent {
+            *counts.entry(id).or_default() += 1;
+        }
+        for (id, count) in counts {
+            let Some(logit) = logits.get_mut(id as usize) else { continue };
+            if *logit >= 0.0 {
+                *logit /= s.repeat_penalty;
+            } else {
+                *logit *= s.repeat_penalty;
+            }
+            *logit -= count as f32 * s.frequency_penalty + s.presence_penalty;
+        }
+    }
+}
+
+/// Draws the index of one of the candidates according to their probabilities.
+pub fn pick(candidates: &[Candidate], rng: &mut impl Rng) -> usize {
+    match WeightedIndex::new(candidates.iter().map(|c| c.p)) {
+        Ok(distribution) => distribution.sample(rng),
+        // Only when the probabilities degenerate, the first one is the most likely
+        Err(_) => 0,
+    }
+}
+
+fn most_likely(logits: &[f32]) -> Candidate {
+    let (id, &logit) = logits
+        .iter()
+        .enumerate()
+        .max_by(|(_, a), (_, b)| a.total_cmp(b))
+        .unwrap_or((0, &0.0));
+    Candidate { id: id as u32, logit, p: 1.0 }
+}
+
+// Fills in the probabilities of candidates sorted by logit
+fn softmax(candidates: &mut [Candidate]) {
+    let max = candidates[0].logit;
+    let mut sum = 0.0;
+    for c in candidates.iter_mut() {
+        c.p = (c.logit - max).exp();
+        sum += c.p;
+    }
+    for c in candidates.iter_mut() {
+        c.p /= sum;
+    }
+}
+
+// Keeps the smallest set of the most likely candidates covering `p` of the probability mass
+fn top_p(candidates: &mut Vec<Candidate>, p: f32) {
+    let mut cumulative = 0.0;
+    let mut keep = 0;
+    for c in candidates.iter() {
+        cumulative += c.p;
+        keep += 1;
+        if cumulative >= p {
+            break;
+        }
+    }
+    candidates.truncate(keep);
+}
+
+// Locally typical sampling: keeps the candidates whose surprise is closest to the entropy,
+// until they cover `p` of the probability mass
+fn typical(mut candidates: Vec<Candidate>, p: f32) -> Vec<Candidate> {
+    let entropy: f32 = candidates.iter().filter(|c| c.p > 0.0).map(|c| -c.p * c.p.ln()).sum();
+    candidates.sort_by(|a, b| {
+        let a = (-a.p.ln() - entropy).abs();
+        let b = (-b.p.ln() - entropy).abs();
+        a.total_cmp(&b)
+    });
+    top_p(&mut candidates, p);
+    // The later steps expect the most likely candidate first
+    candidates.sort_by(|a, b| b.logit.total_cmp(&a.logit));
+    candidates
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Logits whose softmax is `probabilities`
+    fn logits(probabilities: &[f32]) -> Vec<f32> {
+        probabilities.iter().map(|p| p.ln()).collect()
+    }
+
+    fn probabilities(candidates: &[Candidate]) -> Vec<(u32, f32)> {
+        candidates.iter().map(|c| (c.id, (c.p * 1000.0).round() / 1000.0)).collect()
+    }
+
+    #[test]
+    fn truncations_renormalize_in_order() {
+        let logits = logits(&[0.05, 0.4, 0.1, 0.3, 0.15]);
+        let sampling = Sampling {
+            temperature: Some(1.0),
+            top_k: Some(4),
+            top_p: Some(0.8),
+            min_p: Some(0.5),
+            ..Default::default()
+        };
+        // Top-k leaves 0.95 of the mass, so top-p needs three tokens to cover 0.8 of it. Min-p
+        // then drops the one below half of the most likely.
+        let candidates = Sampler::new(0, sampling).candidates(&logits, &[]);
+        assert_eq!(probabilities(&candidates), [(1, 0.571), (3, 0.429)]);
+
+        // Top-k runs before top-p: after keeping two, the first alone covers half the mass
+        let sampling = Sampling { temperature: Some(1.0), top_k: Some(2), top_p: Some(0.5), ..Default::default() };
+        let candidates = Sampler::new(0, sampling).candidates(&logits, &[]);
+        assert_eq!(probabilities(&candidates), [(1, 1.0)]);
+    }
+
+    #[test]
+    fn banned_tokens_are_never_sampled() {
+        let logits = logits(&[0.1, 0.7, 0.1, 0.1]);
+        let sampling = Sampling {
+            logit_bias: HashMap::from([(1, 50.0)]),
+            banned_tokens: vec![1, 3],
+            temperature: Some(1.5),
+            ..Default::default()
+        };
+        let mut sampler = Sampler::new(7, sampling.clone());
+        for _ in 0..200 {
+            assert!([0, 2].contains(&sampler.sample(&logits, &[])));
+        }
+        let greedy = Sampler::new(7, Sampling { temperature: None, ..sampling });
+        assert_ne!(greedy.candidates(&logits, &[])[0].id, 1);
+    }
+
+    #[test]
+    fn mirostat_moves_toward_tau() {
+        let mirostat = Mirostat { tau: 3.0, eta: Mirostat::DEFAULT_ETA };
+        let sampling = Sampling { temperature: Some(1.0), mirostat: Some(mirostat), ..Default::default() };
+        let mut sampler = Sampler::new(3, sampling);
+        assert_eq!(sampler.mu, 6.0);
+        // Less surprising than tau allows more tokens, more surprising fewer
+        sampler.observe(&[Candidate { id: 0, logit: 0.0, p: 0.5 }], 0);
+        assert!(sampler.mu > 6.0);
+        let mu = sampler.mu;
+        sampler.observe(&[Candidate { id: 0, logit: 0.0, p: 1.0 / 64.0 }], 0);
+        assert!(sampler.mu < mu);
+
+        // Over many draws from a long-tailed distribution, the surprise averages out at tau
+        let weights: Vec<f32> = (1..=1000).map(|rank| 1.0 / rank as f32).collect();
+        let logits = logits(&weights);
+        let mut surprises = vec![];
+        for _ in 0..1000 {
+            let candidates = sampler.candidates(&logits, &[]);
+            let taken = pick(&candidates, &mut sampler.rng);
+            surprises.push(-candidates[taken].p.log2());
+            let id = candidates[taken].id;
+            sampler.observe(&candidates, id);
+        }
+        let mean = surprises[500..].iter().sum::<f32>() / 500.0;
+        assert!((mean - mirostat.tau).abs() < 0.1, "mean surprise {mean}");
+    }
+}
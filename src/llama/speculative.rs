@@ -0,0 +1,111 @@
+// Speculative sampling as in Leviathan et al. and Chen et al. (2023): a small draft model
+// proposes a few tokens and the main model scores all of them in one forward pass. A drafted
+// token is kept with probability min(1, p / q), where p and q are the probabilities the main and
+// the draft model give it after the sampling steps. The first rejected one is replaced by a
+// draw from the leftover distribution max(0, p - q), so the kept tokens are distributed exactly
+// as if the main model had sampled them one at a time.
+
+use std::collections::HashMap;
+
+use rand::Rng;
+
+use super::model::TextGenerator;
+use super::sampler::{self, Candidate};
+
+/// A small model sharing the vocabulary of the main one.
+pub struct Draft {
+    pub model: Box<dyn TextGenerator>,
+    /// Tokens proposed per forward pass of the main model
+    pub lookahead: usize,
+}
+
+/// Checks a `token` drawn from the `draft` candidates against the `target` ones. Returns None
+/// when it is kept, otherwise the token to take instead.
+pub fn verify(target: &[Candidate], draft: &[Candidate], token: u32, rng: &mut impl Rng) -> Option<u32> {
+    // Both lists hold the whole vocabulary without truncating samplers
+    let q: HashMap<u32, f32> = draft.iter().map(|c| (c.id, c.p)).collect();
+    let probability = |token: u32| q.get(&token).copied().unwrap_or(0.0);
+    let p = target.iter().find(|c| c.id == token).map_or(0.0, |c| c.p);
+    let q_token = probability(token);
+    if q_token > 0.0 && rng.gen::<f32>() < p / q_token {
+        return None;
+    }
+    let leftover: Vec<Candidate> = target
+        .iter()
+        .map(|c| Candidate { p: (c.p - probability(c.id)).max(0.0), ..c.clone() })
+        .collect();
+    if leftover.iter().all(|c| c.p <= 0.0) {
+        // Only through rounding, the distributions are the same then
+        return Some(target[sampler::pick(target, rng)].id);
+    }
+    Some(leftover[sampler::pick(&leftover, rng)].id)
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
+
+    use super::*;
+
+    fn distribution(ps: &[f32]) -> Vec<Candidate> {
+        ps.iter()
+            .enumerate()
+            .filter(|(_, &p)| p > 0.0)
+            .map(|(id, &p)| Candidate { id: id as u32, logit: p.ln(), p })
+            .collect()
+    }
+
+    // Draws a token from the draft, verifies it and counts what ends up being taken
+    fn frequencies(target: &[f32], draft: &[f32], draws: usize) -> Vec<f32> {
+        let target = distribution(target);
+        let draft = distribution(draft);
+        let mut rng = StdRng::seed_from_u64(42);
+        let mut counts = [0; 4];
+        for _ in 0..draws {
+            let token = draft[sampler::pick(&draft, &mut rng)].id;
+            let taken = verify(&target, &draft, token, &mut rng).unwrap_or(token);
+            counts[taken as usize] += 1;
+        }
+        counts.iter().map(|&n| n as f32 / draws as f32).collect()
+    }
+
+    #[test]
+    fn keeps_the_target_distribution() {
+        let target = [0.5, 0.3, 0.2, 0.0];
+        for draft in [[0.1, 0.1, 0.1, 0.7], [0.5, 0.3, 0.2, 0.0], [0.0, 0.0, 0.0, 1.0], [0.25; 4]] {
+            let frequencies = frequencies(&target, &draft, 100_000);
+            for (f, p) in frequencies.iter().zip(target) {
+                assert!((f - p).abs() < 0.01, "{frequencies:?} drafted from {draft:?}");
+            }
+        }
+    }
+
+    #[test]
+    fn greedy_drafts_only_pass_when_they_agree() {
+        let target = distribution(&[0.0, 1.0]);
+        let mut rng = StdRng::seed_from_u64(0);
+        assert_eq!(verify(&target, &distribution(&[0.0, 1.0]), 1, &mut rng), None);
+        assert_eq!(verify(&target, &distribution(&[1.0]), 0, &mut rng), Some(1));
+    }
+
+    #[test]
+    fn vocabulary_sized_distributions() {
+        // The target only allows even tokens, the draft proposes any of them
+        let vocabulary = 32_000;
+        let even: Vec<f32> = (0..vocabulary)
+            .map(|id| if id % 2 == 0 { 2.0 / vocabulary as f32 } else { 0.0 })
+            .collect();
+        let target = distribution(&even);
+        let draft = distribution(&vec![1.0 / vocabulary as f32; vocabulary]);
+        let mut rng = StdRng::seed_from_u64(7);
+        for _ in 0..200 {
+            let token = draft[sampler::pick(&draft, &mut rng)].id;
+            let taken = verify(&target, &draft, token, &mut rng).unwrap_or(token);
+            assert_eq!(taken % 2, 0, "drafted {token}");
+            if token % 2 == 1 {
+                assert_ne!(taken, token);
+            }
+        }
+    }
+}
@@ -0,0 +1,111 @@
+/// Watches the generated text for stop sequences. Text that could be the beginning of a stop
+/// sequence is held back until it's clear whether it matches, so the stop sequence itself never
+/// reaches the client even when it is split across several tokens.
+pub struct StopSequences {
+    stops: Vec<String>,
+    pending: String,
+}
+
+pub struct Scanned {
+    pub text: String,
+    pub stopped: bool,
+}
+
+impl StopSequences {
+    pub fn new(stops: Vec<String>) -> StopSequences {
+        let stops = stops.into_iter().filter(|s| !s.is_empty()).collect();
+        StopSequences { stops, pending: String::new() }
+    }
+
+    pub fn push(&mut self, text: &str) -> Scanned {
+        self.pending.push_str(text);
+        let first_match = self.stops
+            .iter()
+            .filter_map(|stop| self.pending.find(stop.as_str()))
+            .min();
+        if let Some(at) = first_match {
+            let text = self.pending[..at].to_string();
+            self.pending.clear();
+            return Scanned { text, stopped: true };
+        }
+        let held_back = self.partial_match_start();
+        let text = self.pending[..held_back].to_string();
+        self.pending.drain(..held_back);
+        Scanned { text, stopped: false }
+    }
+
+    /// Releases the held back text once generation ended without a match.
+    pub fn flush(&mut self) -> String {
+        std::mem::take(&mut self.pending)
+    }
+
+    // Start of the longest suffix of the pending text that is a prefix of a stop sequence
+    fn partial_match_start(&self) -> usize {
+        self.pending
+            .char_indices()
+            .map(|(i, _)| i)
+            .find(|&i| {
+                let suffix = &self.pending[i..];
+                self.stops.iter().any(|stop| stop.starts_with(suffix))
+            })
+            .unwrap_or(self.pending.len())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn watch(stops: &[&str]) -> StopSequences {
+        StopSequences::new(stops.iter().map(|s| s.to_string()).collect())
+    }
+
+    fn push(stops: &mut StopSequences, text: &str) -> (String, bool) {
+        let scanned = stops.push(text);
+        (scanned.text, scanned.stopped)
+    }
+
+    #[test]
+    fn stop_split_across_pushes() {
+        let mut stops = watch(&["\nUser:"]);
+        assert_eq!(push(&mut stops, "Fine, thanks."), (String::from("Fine, thanks."), false));
+        assert_eq!(push(&mut stops, "\nUs"), (String::new(), false));
+        assert_eq!(push(&mut stops, "er"), (String::new(), false));
+        assert_eq!(push(&mut stops, ": and you?"), (String::new(), true));
+        assert_eq!(stops.flush(), "");
+    }
+
+    #[test]
+    fn false_partial_match_is_released() {
+        let mut stops = watch(&["###"]);
+        assert_eq!(push(&mut stops, "Step #"), (String::from("Step "), false));
+        assert_eq!(push(&mut stops, "#"), (String::new(), false));
+        assert_eq!(push(&mut stops, "2 done"), (String::from("##2 done"), false));
+    }
+
+    #[test]
+    fn earliest_match_wins() {
+        let mut stops = watch(&["b", "ab"]);
+        assert_eq!(push(&mut stops, "xabc"), (String::from("x"), true));
+    }
+
+    #[test]
+    fn multibyte_text_before_a_match() {
+        let mut stops = watch(&["Ende"]);
+        assert_eq!(push(&mut stops, "Grüße, "), (String::from("Grüße, "), false));
+        assert_eq!(push(&mut stops, "日本語 En"), (String::from("日本語 "), false));
+        assert_eq!(push(&mut stops, "de"), (String::new(), true));
+
+        let mut stops = watch(&["é!"]);
+        assert_eq!(push(&mut stops, "café"), (String::from("caf"), false));
+        assert_eq!(push(&mut stops, "?"), (String::from("é?"), false));
+    }
+
+    #[test]
+    fn flush_releases_held_back_text() {
+        let mut stops = watch(&["</answer>"]);
+        assert_eq!(push(&mut stops, "42 </ans"), (String::from("42 "), false));
+        assert_eq!(stops.flush(), "</ans");
+        assert_eq!(stops.flush(), "");
+    }
+}
@@ -0,0 +1,341 @@
+use std::collections::HashMap;
+
+use anyhow::{bail, Context};
+use candle_core::quantized::gguf_file::Value;
+use tokenizers::decoders::byte_fallback::ByteFallback;
+use tokenizers::decoders::fuse::Fuse;
+use tokenizers::decoders::sequence::Sequence as DecoderSequence;
+use tokenizers::decoders::strip::Strip;
+use tokenizers::models::bpe::BPE;
+use tokenizers::normalizers::{Prepend, Replace, Sequence as NormalizerSequence};
+use tokenizers::processors::template::TemplateProcessing;
+use tokenizers::{AddedToken, Tokenizer};
+
+// Values of tokenizer.ggml.token_type
+const TOKEN_TYPE_NORMAL: i32 = 1;
+const TOKEN_TYPE_UNKNOWN: i32 = 2;
+const TOKEN_TYPE_CONTROL: i32 = 3;
+
+/// Builds the tokenizer from the `tokenizer.ggml.*` metadata of a GGUF file, the same way the
+/// `tokenizer.json` of the original model was converted from its sentencepiece model.
+pub fn from_gguf(metadata: &HashMap<String, Value>) -> anyhow::Result<Tokenizer> {
+    let get = |key: &str| metadata.get(key).with_context(|| format!("cannot find {key} in metadata"));
+
+    let model = get("tokenizer.ggml.model")?.to_string()?;
+    if model != "llama" {
+        bail!("building a {model} tokenizer from GGUF metadata isn't supported, provide a tokenizer.json");
+    }
+    let tokens = get("tokenizer.ggml.tokens")?
+        .to_vec()?
+        .iter()
+        .map(|v| v.to_string().cloned())
+        .collect::<Result<Vec<_>, _>>()?;
+    let scores = get("tokenizer.ggml.scores")?
+        .to_vec()?
+        .iter()
+        .map(|v| v.to_f32())
+        .collect::<Result<Vec<_>, _>>()?;
+    let token_types = match metadata.get("tokenizer.ggml.token_type") {
+        Some(types) => types.to_vec()?.iter().map(|v| v.to_i32()).collect::<Result<Vec<_>, _>>()?,
+        None => vec![TOKEN_TYPE_NORMAL; tokens.len()],
+    };
+    if scores.len() != tokens.len() || token_types.len() != tokens.len() {
+        bail!("tokenizer metadata has {} tokens but {} scores and {} types", tokens.len(), scores.len(), token_types.len());
+    }
+    let id = |key: &str| metadata.get(key).map(|v| v.to_u32()).transpose();
+    let unk_id = id("tokenizer.ggml.unknown_token_id")?.unwrap_or(0);
+    let bos_id = id("tokenizer.ggml.bos_token_id")?.unwrap_or(1);
+    let add_bos = match metadata.get("tokenizer.ggml.add_bos_token") {
+        Some(v) => v.to_bool()?,
+        None => true,
+    };
+    let token = |id: u32| tokens.get(id as usize).cloned().with_context(|| format!("token id {id} is out of range"));
+
+    let vocab: HashMap<String, u32> = tokens
+        .iter()
+        .enumerate()
+        .map(|(id, token)| (token.clone(), id as u32))
+        .collect();
+    let merges = merges(&tokens, &scores, &token_types, &vocab);
+    let bpe = BPE::builder()
+        .vocab_and_merges(vocab, merges)
+        .unk_token(token(unk_id)?)
+        .fuse_unk(true)
+        .byte_fallback(true)
+        .build()
+        .map_err(anyhow::Error::msg)?;
+
+    let mut tokenizer = Tokenizer::new(bpe);
+    tokenizer.with_normalizer(NormalizerSequence::new(vec![
+        Prepend::new(String::from("▁")).into(),
+        Replace::new(" ", "▁").map_err(anyhow::Error::msg)?.into(),
+    ]));
+    tokenizer.with_decoder(DecoderSequence::new(vec![
+        Replace::new("▁", " ").map_err(anyhow::Error::msg)?.into(),
+        ByteFallback::new().into(),
+        Fuse::new().into(),
+        Strip::new(' ', 1, 0).into(),
+    ]));
+    if add_bos {
+        let bos = token(bos_id)?;
+        let processor = TemplateProcessing::builder()
+            .try_single(format!("{bos} $A"))
+            .map_err(anyhow::Error::msg)?
+            .try_pair(format!("{bos} $A {bos} $B"))
+            .map_err(anyhow::Error::msg)?
+            .special_tokens(vec![(bos, bos_id)])
+            .build()
+            .map_err(anyhow::Error::msg)?;
+        tokenizer.with_post_processor(processor);
+    }
+    let special: Vec<AddedToken> = tokens
+        .iter()
+        .zip(&token_types)
+        .filter(|(_, &t)| t == TOKEN_TYPE_UNKNOWN || t == TOKEN_TYPE_CONTROL)
+        .map(|(token, _)| AddedToken::from(token.clone(), true))
+        .collect();
+    tokenizer.add_special_tokens(&special);
+    Ok(tokenizer)
+}
+
+// Sentencepiece only stores a score per token, BPE wants the merges in the order they are
+// applied. Every way of splitting a token into two others is a merge, ranked by the score of
+// the token it produces.
+fn merges(
+    tokens: &[String],
+    scores: &[f32],
+    token_types: &[i32],
+    vocab: &HashMap<String, u32>,
+) -> Vec<(String, String)> {
+    let mut merges = vec![];
+    for (id, token) in tokens.iter().enumerate() {
+        if token_types[id] != TOKEN_TYPE_NORMAL {
+            continue;
+        }
+        let mut local: Vec<(u32, u32)> = token
+            .char_indices()
+            .skip(1)
+            .filter_map(|(at, _)| Some((*vocab.get(&token[..at])?, *vocab.get(&token[at..])?)))
+            .collect();
+        local.sort();
+        merges.extend(local.into_iter().map(|(left, right)| (left, right, scores[id])));
+    }
+    // Stable, so merges with the same score keep the order of their tokens
+    merges.sort_by(|a, b| b.2.total_cmp(&a.2));
+    merges
+        .into_iter()
+        .map(|(left, right, _)| (tokens[left as usize].clone(), tokens[right as usize].clone()))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // (piece, score), after <unk>, <s>, </s> and the 256 byte tokens
+    const PIECES: [(&str, f32); 20] = [
+        ("▁", -1000.0),
+        ("h", -1000.0),
+        ("e", -1000.0),
+        ("l", -1000.0),
+        ("o", -1000.0),
+        ("w", -1000.0),
+        ("r", -1000.0),
+        ("d", -1000.0),
+        ("ll", -1.0),
+        ("▁h", -2.0),
+        ("he", -3.0),
+        ("▁he", -4.0),
+        ("llo", -5.0),
+        ("▁hello", -6.0),
+        ("or", -7.0),
+        ("▁w", -8.0),
+        ("▁wor", -9.0),
+        ("ld", -10.0),
+        ("▁world", -11.0),
+        ("é", -1000.0),
+    ];
+
+    // The merges the Hugging Face converter produces for PIECES
+    const MERGES: [&str; 12] = [
+        "l l", "▁ h", "h e", "▁ he", "▁h e", "ll o", "▁he llo", "o r", "▁ w", "▁w or", "l d", "▁wor ld",
+    ];
+
+    fn tokens() -> Vec<(String, f32, i32)> {
+        let mut tokens = vec![
+            (String::from("<unk>"), 0.0, TOKEN_TYPE_UNKNOWN),
+            (String::from("<s>"), 0.0, TOKEN_TYPE_CONTROL),
+            (String::from("</s>"), 0.0, TOKEN_TYPE_CONTROL),
+        ];
+        tokens.extend((0..=255u8).map(|b| (format!("<0x{b:02X}>"), 0.0, 6)));
+        tokens.extend(PIECES.iter().map(|(piece, score)| (piece.to_string(), *score, TOKEN_TYPE_NORMAL)));
+        tokens
+    }
+
+    fn gguf_metadata() -> HashMap<String, Value> {
+        let tokens = tokens();
+        HashMap::from([
+            (String::from("tokenizer.ggml.model"), Value::String(String::from("llama"))),
+            (
+                String::from("tokenizer.ggml.tokens"),
+                Value::Array(tokens.iter().map(|t| Value::String(t.0.clone())).collect()),
+            ),
+            (
+                String::from("tokenizer.ggml.scores"),
+                Value::Array(tokens.iter().map(|t| Value::F32(t.1)).collect()),
+            ),
+            (
+                String::from("tokenizer.ggml.token_type"),
+                Value::Array(tokens.iter().map(|t| Value::I32(t.2)).collect()),
+            ),
+            (String::from("tokenizer.ggml.unknown_token_id"), Value::U32(0)),
+            (String::from("tokenizer.ggml.bos_token_id"), Value::U32(1)),
+            (String::from("tokenizer.ggml.eos_token_id"), Value::U32(2)),
+        ])
+    }
+
+    // Laid out like the tokenizer.json of the Llama 2 models
+    fn tokenizer_json() -> String {
+        let tokens = tokens();
+        let vocab: serde_json::Map<String, serde_json::Value> = tokens
+            .iter()
+            .enumerate()
+            .map(|(id, t)| (t.0.clone(), serde_json::json!(id)))
+            .collect();
+        let added_tokens: Vec<_> = tokens[..3]
+            .iter()
+            .enumerate()
+            .map(|(id, t)| serde_json::json!({
+                "id": id,
+                "content": t.0,
+                "single_word": false,
+                "lstrip": false,
+                "rstrip": false,
+                "normalized": false,
+                "special": true,
+            }))
+            .collect();
+        serde_json::json!({
+            "version": "1.0",
+            "truncation": null,
+            "padding": null,
+            "added_tokens": added_tokens,
+            "normalizer": {
+                "type": "Sequence",
+                "normalizers": [
+                    { "type": "Prepend", "prepend": "▁" },
+                    { "type": "Replace", "pattern": { "String": " " }, "content": "▁" },
+                ],
+            },
+            "pre_tokenizer": null,
+            "post_processor": {
+                "type": "TemplateProcessing",
+                "single": [
+                    { "SpecialToken": { "id": "<s>", "type_id": 0 } },
+                    { "Sequence": { "id": "A", "type_id": 0 } },
+                ],
+                "pair": [
+                    { "SpecialToken": { "id": "<s>", "type_id": 0 } },
+                    { "Sequence": { "id": "A", "type_id": 0 } },
+                    { "SpecialToken": { "id": "<s>", "type_id": 1 } },
+                    { "Sequence": { "id": "B", "type_id": 1 } },
+                ],
+                "special_tokens": {
+                    "<s>": { "id": "<s>", "ids": [1], "tokens": ["<s>"] },
+                },
+            },
+            "decoder": {
+                "type": "Sequence",
+                "decoders": [
+                    { "type": "Replace", "pattern": { "String": "▁" }, "content": " " },
+                    { "type": "ByteFallback" },
+                    { "type": "Fuse" },
+                    { "type": "Strip", "content": " ", "start": 1, "stop": 0 },
+                ],
+            },
+            "model": {
+                "type": "BPE",
+                "dropout": null,
+                "unk_token": "<unk>",
+                "continuing_subword_prefix": null,
+                "end_of_word_suffix": null,
+                "fuse_unk": true,
+                "byte_fallback": true,
+                "vocab": vocab,
+                "merges": MERGES,
+            },
+        })
+        .to_string()
+    }
+
+    const TEXTS: [&str; 8] = [
+        "hello world",
+        "hello",
+        "world hello hello",
+        "held",
+        "hi there!\n",
+        "café ☕",
+        "<s>[INST] hello [/INST] world </s>",
+        "",
+    ];
+
+    fn assert_same_encoding(from_gguf: &Tokenizer, from_json: &Tokenizer) {
+        for text in TEXTS {
+            for add_special_tokens in [true, false] {
+                let a = from_gguf.encode(text, add_special_tokens).unwrap();
+                let b = from_json.encode(text, add_special_tokens).unwrap();
+                assert_eq!(a.get_ids(), b.get_ids(), "{text:?}, add_special_tokens: {add_special_tokens}");
+            }
+        }
+    }
+
+    #[test]
+    fn merges_follow_scores() {
+        let tokens = tokens();
+        let pieces: Vec<String> = tokens.iter().map(|t| t.0.clone()).collect();
+        let scores: Vec<f32> = tokens.iter().map(|t| t.1).collect();
+        let types: Vec<i32> = tokens.iter().map(|t| t.2).collect();
+        let vocab = pieces.iter().enumerate().map(|(id, p)| (p.clone(), id as u32)).collect();
+        let merges: Vec<String> = merges(&pieces, &scores, &types, &vocab)
+            .into_iter()
+            .map(|(left, right)| format!("{left} {right}"))
+            .collect();
+        assert_eq!(merges, MERGES);
+    }
+
+    #[test]
+    fn encodes_like_tokenizer_json() {
+        let from_gguf = from_gguf(&gguf_metadata()).unwrap();
+        let from_json: Tokenizer = tokenizer_json().parse().unwrap();
+        assert_same_encoding(&from_gguf, &from_json);
+    }
+
+    #[test]
+    fn known_encoding() {
+        let tokenizer = from_gguf(&gguf_metadata()).unwrap();
+        let piece = |p: &str| tokenizer.token_to_id(p).unwrap();
+        let ids = tokenizer.encode("hello world", true).unwrap();
+        assert_eq!(ids.get_ids(), [1, piece("▁hello"), piece("▁world")]);
+        // "i" isn't in the vocabulary and falls back to its byte
+        let ids = tokenizer.encode("hi", false).unwrap();
+        assert_eq!(ids.get_ids(), [piece("▁h"), piece("<0x69>")]);
+    }
+
+    #[test]
+    fn rejects_unsupported_models() {
+        let mut metadata = gguf_metadata();
+        metadata.insert(String::from("tokenizer.ggml.model"), Value::String(String::from("gpt2")));
+        assert!(from_gguf(&metadata).is_err());
+    }
+
+    // Needs the model files, run with `cargo test -- --ignored`
+    #[test]
+    #[ignore]
+    fn encodes_like_tokenizer_json_of_llama_2() {
+        let mut file = std::fs::File::open("models/llama-2-7b.Q2_K.gguf").unwrap();
+        let content = candle_core::quantized::gguf_file::Content::read(&mut file).unwrap();
+        let from_gguf = from_gguf(&content.metadata).unwrap();
+        let from_json = Tokenizer::from_file("models/tokenizer.json").unwrap();
+        assert_same_encoding(&from_gguf, &from_json);
+    }
+}
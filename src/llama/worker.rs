@@ -0,0 +1,615 @@
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
+
+use candle_core::{DType, Device, IndexOp, Tensor};
+use tokenizers::Tokenizer;
+use tokio::sync::mpsc;
+use tokio::sync::mpsc::error::TryRecvError;
+
+use super::model::{Cache, TextGenerator};
+use super::decoder::TokenDecoder;
+use super::grammar::{Constraint, Grammar, Vocabulary};
+use super::prefix_cache::PrefixCache;
+use super::sampler::{self, Candidate, Sampler, Sampling};
+use super::speculative::{self, Draft};
+use super::stop::StopSequences;
+use super::{FinishReason, Output, TokenLogprob, TopLogprob};
+
+/// A generation request for the compute thread, with every setting already resolved.
+pub struct Job {
+    pub prompt: String,
+    pub add_special_tokens: bool,
+    pub sample_len: usize,
+    pub seed: u64,
+    pub sampling: Sampling,
+    /// Output format the tokens are restricted to
+    pub grammar: Option<Arc<Grammar>>,
+    /// Number of alternatives to report with the log probability of every token
+    pub logprobs: Option<usize>,
+    pub eos_tokens: Vec<u32>,
+    pub stop: Vec<String>,
+    /// Conversation whose KV cache is kept for the next turn
+    pub cache_key: Option<String>,
+    pub cancelled: Arc<AtomicBool>,
+    pub output: mpsc::UnboundedSender<Result<Output, String>>,
+}
+
+/// Runs on the compute thread and owns the model weights, so the forward passes never block
+/// the tokio workers. Up to `max_batch_size` generations are decoded together, new jobs join
+/// the batch as soon as a slot frees up. With a draft model, a generation that runs alone is
+/// decoded speculatively, several tokens per forward pass of the main model; batching already
+/// spreads the cost of reading the weights otherwise. Returns once the `Llama` holding the
+/// sender is dropped and the last generation finished.
+pub fn run(
+    model: Box<dyn TextGenerator>,
+    draft: Option<Draft>,
+    tokenizer: Arc<Tokenizer>,
+    verbose_prompt: bool,
+    max_batch_size: usize,
+    mut prefix_cache: PrefixCache,
+    mut jobs: mpsc::UnboundedReceiver<Job>,
+) {
+    let max_batch_size = max_batch_size.max(1);
+    let vocabulary = Vocabulary::new(&tokenizer);
+    let mut batch: Vec<Sequence> = Vec::with_capacity(max_batch_size);
+    loop {
+        // Only wait for work when there is nothing to decode
+        if batch.is_empty() {
+            match jobs.blocking_recv() {
+                Some(job) => start(job, &model, draft.as_ref(), &tokenizer, &vocabulary, verbose_prompt, &mut prefix_cache, &mut batch),
+                None => break,
+            }
+        }
+        while batch.len() < max_batch_size {
+            match jobs.try_recv() {
+                Ok(job) => start(job, &model, draft.as_ref(), &tokenizer, &vocabulary, verbose_prompt, &mut prefix_cache, &mut batch),
+                Err(TryRecvError::Empty | TryRecvError::Disconnected) => break,
+            }
+        }
+
+        for mut sequence in std::mem::take(&mut batch) {
+            if sequence.advance(&tokenizer, &vocabulary) {
+                batch.push(sequence);
+            } else if let Some(key) = sequence.job.cache_key.take() {
+                prefix_cache.put(key, sequence.tokens, sequence.cache);
+            }
+        }
+        if batch.is_empty() {
+            continue;
+        }
+        let speculative = match (&draft, batch.as_slice()) {
+            (Some(draft), [sequence]) if sequence.can_speculate(draft) => Some(draft),
+            _ => None,
+        };
+        let result = match speculative {
+            Some(draft) => batch[0].speculate(&model, draft, &vocabulary),
+            None => step(&model, &vocabulary, &mut batch),
+        };
+        if let Err(e) = result {
+            // The caches might be half updated, so they aren't kept
+            for sequence in batch.drain(..) {
+                fail(&sequence.job.output, &e);
+            }
+        }
+    }
+    tracing::info!("Compute thread shutting down");
+}
+
+// Processes the prompt of a new job and adds it to the batch. This pauses the other
+// generations for as long as the prompt takes.
+#[allow(clippy::too_many_arguments)]
+fn start(
+    job: Job,
+    model: &dyn TextGenerator,
+    draft: Option<&Draft>,
+    tokenizer: &Tokenizer,
+    vocabulary: &Vocabulary,
+    verbose_prompt: bool,
+    prefix_cache: &mut PrefixCache,
+    batch: &mut Vec<Sequence>,
+) {
+    if job.is_cancelled() {
+        return;
+    }
+    let output = job.output.clone();
+    match Sequence::new(job, model, draft, tokenizer, vocabulary, verbose_prompt, prefix_cache) {
+        Ok(Some(sequence)) => batch.push(sequence),
+        Ok(None) => {},
+        Err(e) => fail(&output, &e),
+    }
+}
+
+// Feeds the last sampled token of every sequence through the model in one forward pass
+fn step(model: &dyn TextGenerator, vocabulary: &Vocabulary, batch: &mut [Sequence]) -> anyhow::Result<()> {
+    let _span = tracing::trace_span!("step", batch = batch.len()).entered();
+    let tokens: Vec<u32> = batch.iter().map(|s| s.next_token).collect();
+    let input = Tensor::new(tokens.as_slice(), &Device::Cpu)?.unsqueeze(1)?;
+    let start = Instant::now();
+    let logits = {
+        let mut caches: Vec<&mut Cache> = batch.iter_mut().map(|s| &mut s.cache).collect();
+        model.forward_batch(&input, &mut caches)?
+    };
+    let dt = start.elapsed();
+    for (i, sequence) in batch.iter_mut().enumerate() {
+        sequence.tokens.push(sequence.next_token);
+        sequence.generation_dt += dt;
+        sequence.sample(&logits.i(i)?, vocabulary)?;
+    }
+    Ok(())
+}
+
+fn to_vec(logits: &Tensor) -> anyhow::Result<Vec<f32>> {
+    Ok(logits.to_dtype(DType::F32)?.to_vec1()?)
+}
+
+impl Job {
+    fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::Relaxed) || self.output.is_closed()
+    }
+
+    // Returns false once the receiving end is gone. Never waits for the receiver, a slow client
+    // mustn't hold up the other sequences of the batch.
+    fn emit(&self, output: Output) -> bool {
+        self.output.send(Ok(output)).is_ok()
+    }
+}
+
+fn log_softmax(logits: &[f32]) -> Vec<f32> {
+    let max = logits.iter().copied().fold(f32::NEG_INFINITY, f32::max);
+    let log_sum = logits.iter().map(|l| (l - max).exp()).sum::<f32>().ln() + max;
+    logits.iter().map(|l| l - log_sum).collect()
+}
+
+// The `n` tokens with the highest log probability, most likely first
+fn most_likely(logprobs: &[f32], n: usize) -> Vec<(u32, f32)> {
+    let mut tokens: Vec<(u32, f32)> = logprobs.iter().enumerate().map(|(id, &l)| (id as u32, l)).collect();
+    let n = n.min(tokens.len());
+    if n == 0 {
+        return vec![];
+    }
+    tokens.select_nth_unstable_by(n - 1, |a, b| b.1.total_cmp(&a.1));
+    tokens.truncate(n);
+    tokens.sort_by(|a, b| b.1.total_cmp(&a.1));
+    tokens
+}
+
+fn fail(output: &mpsc::UnboundedSender<Result<Output, String>>, e: &anyhow::Error) {
+    // Nobody might be listening anymore, which is fine
+    let _ = output.send(Err(format!("Error: {}", e)));
+}
+
+// A token that was sampled but not handed out yet
+struct Sampled {
+    token: u32,
+    // Its log probability and the most likely alternatives
+    logprobs: Option<(f32, Vec<(u32, f32)>)>,
+}
+
+// The draft model's side of a sequence, with a sampler of its own so its draws don't correlate
+// with the ones that accept them
+struct DraftSequence {
+    cache: Cache,
+    sampler: Sampler,
+    drafted: usize,
+    accepted: usize,
+}
+
+// A generation in the batch, with its own KV cache and sampler
+struct Sequence {
+    job: Job,
+    cache: Cache,
+    // The tokens that went through the model so far, i.e. the contents of the cache
+    tokens: Vec<u32>,
+    sampler: Sampler,
+    constraint: Option<Constraint>,
+    draft: Option<DraftSequence>,
+    decoder: TokenDecoder,
+    stops: StopSequences,
+    prompt_len: usize,
+    reused_len: usize,
+    all_tokens: Vec<u32>,
+    // The last sampled token, the next one to go through the model
+    next_token: u32,
+    sampled: Vec<Sampled>,
+    prompt_dt: Duration,
+    generation_dt: Duration,
+}
+
+impl Sequence {
+    // Processes the prompt, returns None when the receiver is already gone
+    fn new(
+        job: Job,
+        model: &dyn TextGenerator,
+        draft: Option<&Draft>,
+        tokenizer: &Tokenizer,
+        vocabulary: &Vocabulary,
+        verbose_prompt: bool,
+        prefix_cache: &mut PrefixCache,
+    ) -> anyhow::Result<Option<Sequence>> {
+        let tokens = tokenizer.encode(job.prompt.as_str(), job.add_special_tokens).map_err(anyhow::Error::msg)?;
+        if verbose_prompt {
+            for (token, id) in tokens.get_tokens().iter().zip(tokens.get_ids().iter()) {
+                let token = String::from_utf8_lossy(&vocabulary.pieces().bytes(token)).into_owned();
+                println!("{id:7} -> '{token}'");
+            }
+        }
+        let sample_len = job.sample_len;
+        let prompt_tokens = tokens.get_ids();
+        if prompt_tokens.is_empty() {
+            // The models need at least one token for the logits of the first generated one
+            anyhow::bail!("the prompt has no tokens");
+        }
+        let context_length = model.context_length();
+        let prompt_tokens = if prompt_tokens.len() + sample_len + 10 > context_length {
+            // Drop from the front, keeping at least the last token to answer
+            let to_remove = prompt_tokens.len() + sample_len + 10 - context_length;
+            &prompt_tokens[to_remove.min(prompt_tokens.len().saturating_sub(1))..]
+        } else {
+            prompt_tokens
+        };
+        let sampler = Sampler::new(job.seed, job.sampling.clone());
+        let constraint = job.grammar.clone().map(Constraint::new);
+        if !job.emit(Output::Seed(job.seed)) {
+            return Ok(None);
+        }
+
+        let _span = tracing::trace_span!("prompt", tokens = prompt_tokens.len()).entered();
+        let start_prompt_processing = Instant::now();
+        let mut cache = job.cache_key
+            .as_ref()
+            .and_then(|key| prefix_cache.take(key, prompt_tokens))
+            .unwrap_or_else(|| model.new_cache());
+        let reused_len = cache.len();
+        if reused_len > 0 {
+            tracing::info!("Reusing {} of {} prompt tokens", reused_len, prompt_tokens.len());
+        }
+        let input = Tensor::new(&prompt_tokens[reused_len..], &Device::Cpu)?.unsqueeze(0)?;
+        let logits = model.forward(&input, &mut cache)?;
+        // The draft model only sees the prompt once the sequence speculates, which it doesn't
+        // while it shares the batch
+        let draft = draft.map(|draft| DraftSequence {
+            cache: draft.model.new_cache(),
+            sampler: Sampler::new(job.seed.wrapping_add(1), job.sampling.clone()),
+            drafted: 0,
+            accepted: 0,
+        });
+        let mut sequence = Sequence {
+            stops: StopSequences::new(job.stop.clone()),
+            job,
+            cache,
+            tokens: prompt_tokens.to_vec(),
+            sampler,
+            constraint,
+            draft,
+            decoder: TokenDecoder::new(vocabulary.pieces()),
+            prompt_len: prompt_tokens.len(),
+            reused_len,
+            all_tokens: vec![],
+            next_token: 0,
+            sampled: vec![],
+            prompt_dt: start_prompt_processing.elapsed(),
+            generation_dt: Duration::ZERO,
+        };
+        sequence.sample(&logits, vocabulary)?;
+        Ok(Some(sequence))
+    }
+
+    // Hands out the tokens sampled since the last call, returns whether the sequence needs
+    // another forward pass
+    fn advance(&mut self, tokenizer: &Tokenizer, vocabulary: &Vocabulary) -> bool {
+        std::mem::take(&mut self.sampled)
+            .into_iter()
+            .all(|sampled| self.hand_out(sampled, tokenizer, vocabulary))
+    }
+
+    fn hand_out(&mut self, sampled: Sampled, tokenizer: &Tokenizer, vocabulary: &Vocabulary) -> bool {
+        let next_token = sampled.token;
+        if self.job.eos_tokens.contains(&next_token) {
+            self.finish(FinishReason::Eos);
+            return false;
+        }
+        self.all_tokens.push(next_token);
+        if let Some((logprob, top)) = sampled.logprobs {
+            let text = |token| {
+                let piece = tokenizer.id_to_token(token).unwrap_or_default();
+                String::from_utf8_lossy(&vocabulary.pieces().bytes(&piece)).into_owned()
+            };
+            let top_logprobs = top
+                .into_iter()
+                .map(|(token, logprob)| TopLogprob { token, text: text(token), logprob })
+                .collect();
+            let logprob = TokenLogprob { token: next_token, text: text(next_token), logprob, top_logprobs };
+            if !self.job.emit(Output::Logprob(logprob)) {
+                tracing::info!("Receiver dropped, cancelling generation");
+                return false;
+            }
+        }
+        if let Some(text) = self.decoder.next_token(next_token, tokenizer) {
+            let scanned = self.stops.push(&text);
+            if !scanned.text.is_empty() && !self.job.emit(Output::Text(scanned.text)) {
+                tracing::info!("Receiver dropped, cancelling generation");
+                return false;
+            }
+            if scanned.stopped {
+                self.finish(FinishReason::Stop);
+                return false;
+            }
+        }
+        if self.all_tokens.len() >= self.job.sample_len {
+            self.finish(FinishReason::Length);
+            return false;
+        }
+        if self.job.is_cancelled() {
+            tracing::info!("Generation cancelled after {} tokens", self.all_tokens.len());
+            return false;
+        }
+        true
+    }
+
+    fn sample(&mut self, logits: &Tensor, vocabulary: &Vocabulary) -> anyhow::Result<()> {
+        let logits = to_vec(logits)?;
+        let candidates = self.candidates(&logits, &self.all_tokens, vocabulary);
+        let token = candidates[sampler::pick(&candidates, self.sampler.rng())].id;
+        self.take(token, &logits, &candidates, vocabulary);
+        Ok(())
+    }
+
+    // What the next token is drawn from, after the grammar and the sampling steps
+    fn candidates(&self, logits: &[f32], history: &[u32], vocabulary: &Vocabulary) -> Vec<Candidate> {
+        match &self.constraint {
+            Some(constraint) => {
+                let mut logits = logits.to_vec();
+                constraint.mask(&mut logits, vocabulary, &self.job.eos_tokens);
+                self.sampler.candidates(&logits, history)
+            },
+            None => self.sampler.candidates(logits, history),
+        }
+    }
+
+    // Makes `token`, drawn from `candidates`, the next one
+    fn take(&mut self, token: u32, logits: &[f32], candidates: &[Candidate], vocabulary: &Vocabulary) {
+        self.sampler.observe(candidates, token);
+        if let Some(constraint) = &mut self.constraint {
+            constraint.accept(token, vocabulary);
+        }
+        let logprobs = self.job.logprobs.map(|n| {
+            let logprobs = log_softmax(logits);
+            (logprobs[token as usize], most_likely(&logprobs, n))
+        });
+        self.sampled.push(Sampled { token, logprobs });
+        self.next_token = token;
+    }
+
+    // Whether a speculative step fits into the context of both models and the sample length. The
+    // draft model catches up on all of `tokens` first, so they have to fit its context too.
+    fn can_speculate(&self, draft: &Draft) -> bool {
+        let context_length = draft.model.context_length();
+        self.lookahead(draft) > 0 && self.tokens.len() + draft.lookahead + 1 < context_length
+    }
+
+    // Drafted tokens per step, at most as many as can still be handed out after `next_token`
+    fn lookahead(&self, draft: &Draft) -> usize {
+        let remaining = self.job.sample_len.saturating_sub(self.all_tokens.len() + 1);
+        draft.lookahead.min(remaining)
+    }
+
+    // Lets the draft model propose tokens after `next_token` and checks them with a single
+    // forward pass of the main model. Samples between one and lookahead + 1 tokens.
+    fn speculate(&mut self, model: &dyn TextGenerator, draft: &Draft, vocabulary: &Vocabulary) -> anyhow::Result<()> {
+        let start = Instant::now();
+        let lookahead = self.lookahead(draft);
+        let _span = tracing::trace_span!("speculate", lookahead).entered();
+        let Some(state) = self.draft.as_mut() else {
+            anyhow::bail!("the sequence was started without the draft model");
+        };
+
+        // The draft cache misses the tokens of batched steps and the last drafted one
+        let mut input = self.tokens[state.cache.len()..].to_vec();
+        input.push(self.next_token);
+        let mut history = self.all_tokens.clone();
+        let mut constraint = self.constraint.clone();
+        let mut drafted: Vec<(u32, Vec<Candidate>)> = Vec::with_capacity(lookahead);
+        while drafted.len() < lookahead {
+            let input_tensor = Tensor::new(input.as_slice(), &Device::Cpu)?.unsqueeze(0)?;
+            let mut logits = to_vec(&draft.model.forward(&input_tensor, &mut state.cache)?)?;
+            if let Some(constraint) = &constraint {
+                constraint.mask(&mut logits, vocabulary, &self.job.eos_tokens);
+            }
+            let candidates = state.sampler.candidates(&logits, &history);
+            let token = candidates[sampler::pick(&candidates, state.sampler.rng())].id;
+            state.sampler.observe(&candidates, token);
+            if let Some(constraint) = &mut constraint {
+                constraint.accept(token, vocabulary);
+            }
+            drafted.push((token, candidates));
+            if self.job.eos_tokens.contains(&token) {
+                break;
+            }
+            history.push(token);
+            input = vec![token];
+        }
+
+        let input: Vec<u32> = std::iter::once(self.next_token).chain(drafted.iter().map(|(t, _)| *t)).collect();
+        let input = Tensor::new(input.as_slice(), &Device::Cpu)?.unsqueeze(0)?;
+        let logits = model.forward_all(&input, &mut self.cache)?;
+        self.tokens.push(self.next_token);
+        let mut history = self.all_tokens.clone();
+        let mut accepted = 0;
+        for (i, (token, draft_candidates)) in drafted.iter().enumerate() {
+            let logits = to_vec(&logits.i(i)?)?;
+            let candidates = self.candidates(&logits, &history, vocabulary);
+            match speculative::verify(&candidates, draft_candidates, *token, self.sampler.rng()) {
+                None => {
+                    self.take(*token, &logits, &candidates, vocabulary);
+                    self.tokens.push(*token);
+                    history.push(*token);
+                    accepted += 1;
+                },
+                Some(replacement) => {
+                    self.take(replacement, &logits, &candidates, vocabulary);
+                    break;
+                },
+            }
+        }
+        let ended = drafted.last().map_or(false, |(t, _)| self.job.eos_tokens.contains(t));
+        if accepted == drafted.len() && !ended {
+            // The main model's logits after the last drafted token give one more for free
+            let logits = to_vec(&logits.i(accepted)?)?;
+            let candidates = self.candidates(&logits, &history, vocabulary);
+            let token = candidates[sampler::pick(&candidates, self.sampler.rng())].id;
+            self.take(token, &logits, &candidates, vocabulary);
+        }
+        // Forget the rejected drafts
+        self.cache.truncate(self.tokens.len())?;
+        if let Some(state) = self.draft.as_mut() {
+            state.cache.truncate(self.tokens.len())?;
+            state.drafted += drafted.len();
+            state.accepted += accepted;
+        }
+        self.generation_dt += start.elapsed();
+        Ok(())
+    }
+
+    fn finish(&mut self, mut finish_reason: FinishReason) {
+        if finish_reason != FinishReason::Stop {
+            let scanned = self.stops.push(&self.decoder.flush().unwrap_or_default());
+            let text = if scanned.stopped {
+                finish_reason = FinishReason::Stop;
+                scanned.text
+            } else {
+                scanned.text + &self.stops.flush()
+            };
+            if !text.is_empty() {
+                self.job.emit(Output::Text(text));
+            }
+        }
+        self.job.emit(Output::Finished {
+            reason: finish_reason,
+            prompt_tokens: self.prompt_len,
+            reused_tokens: self.reused_len,
+            completion_tokens: self.all_tokens.len(),
+            prompt_duration: self.prompt_dt,
+            generation_duration: self.generation_dt,
+        });
+        let processed = self.prompt_len - self.reused_len;
+        tracing::info!(
+            reason = ?finish_reason,
+            prompt_tokens = processed,
+            reused_tokens = self.reused_len,
+            prompt_tokens_per_s = processed as f64 / self.prompt_dt.as_secs_f64(),
+            generated_tokens = self.all_tokens.len(),
+            generated_tokens_per_s = self.all_tokens.len() as f64 / self.generation_dt.as_secs_f64(),
+            "Finished generating",
+        );
+        if let Some(draft) = self.draft.as_ref().filter(|d| d.drafted > 0) {
+            let acceptance_rate = draft.accepted as f64 / draft.drafted as f64;
+            tracing::info!(drafted = draft.drafted, accepted = draft.accepted, acceptance_rate, "Speculative decoding");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use tokenizers::models::wordlevel::WordLevel;
+
+    use super::*;
+
+    const VOCAB: [&str; 3] = ["<unk>", "a", "b"];
+
+    // Every next token is equally likely, so greedy sampling keeps picking the last one
+    struct Uniform;
+
+    impl TextGenerator for Uniform {
+        fn architecture(&self) -> &'static str {
+            "uniform"
+        }
+
+        fn context_length(&self) -> usize {
+            256
+        }
+
+        fn new_cache(&self) -> Cache {
+            Cache::zeros(0, 1).unwrap()
+        }
+
+        fn forward_batch(&self, x: &Tensor, _caches: &mut [&mut Cache]) -> candle_core::Result<Tensor> {
+            Tensor::zeros((x.dim(0)?, VOCAB.len()), DType::F32, &Device::Cpu)
+        }
+
+        fn forward_all(&self, x: &Tensor, _cache: &mut Cache) -> candle_core::Result<Tensor> {
+            Tensor::zeros((x.dim(1)?, VOCAB.len()), DType::F32, &Device::Cpu)
+        }
+    }
+
+    fn tokenizer() -> Tokenizer {
+        let vocab: HashMap<String, u32> = VOCAB.iter().enumerate().map(|(id, t)| (t.to_string(), id as u32)).collect();
+        Tokenizer::new(WordLevel::builder().vocab(vocab).unk_token(String::from("<unk>")).build().unwrap())
+    }
+
+    fn job(output: mpsc::UnboundedSender<Result<Output, String>>) -> Job {
+        Job {
+            prompt: String::from("a"),
+            add_special_tokens: false,
+            sample_len: 100,
+            seed: 0,
+            sampling: Sampling::default(),
+            grammar: None,
+            logprobs: None,
+            eos_tokens: vec![],
+            stop: vec![],
+            cache_key: None,
+            cancelled: Arc::default(),
+            output,
+        }
+    }
+
+    #[test]
+    fn a_client_that_never_reads_does_not_hold_up_the_batch() {
+        let (jobs_tx, jobs) = mpsc::unbounded_channel();
+        let (stalled, mut stalled_rx) = mpsc::unbounded_channel();
+        let (reading, mut reading_rx) = mpsc::unbounded_channel();
+        jobs_tx.send(job(stalled)).unwrap();
+        jobs_tx.send(job(reading)).unwrap();
+        drop(jobs_tx);
+        let prefix_cache = PrefixCache::new(0, Arc::default());
+        let compute = std::thread::spawn(move || {
+            run(Box::new(Uniform), None, Arc::new(tokenizer()), false, 2, prefix_cache, jobs);
+        });
+
+        let mut completion_tokens = None;
+        while let Some(output) = reading_rx.blocking_recv() {
+            if let Output::Finished { completion_tokens: n, .. } = output.unwrap() {
+                completion_tokens = Some(n);
+            }
+        }
+        assert_eq!(completion_tokens, Some(100));
+        compute.join().unwrap();
+        // The other client can still read its whole generation
+        let stalled: Vec<_> = std::iter::from_fn(|| stalled_rx.try_recv().ok()).collect();
+        assert!(matches!(stalled.last(), Some(Ok(Output::Finished { completion_tokens: 100, .. }))));
+    }
+
+    #[test]
+    fn log_softmax_normalizes() {
+        let logprobs = log_softmax(&[2.0, 1.0, 0.0, f32::NEG_INFINITY]);
+        let total: f32 = logprobs.iter().map(|l| l.exp()).sum();
+        assert!((total - 1.0).abs() < 1e-6);
+        assert!((logprobs[0] - logprobs[1] - 1.0).abs() < 1e-6);
+        assert_eq!(logprobs[3], f32::NEG_INFINITY);
+        // Large logits don't overflow
+        let logprobs = log_softmax(&[1000.0, 1000.0]);
+        assert!((logprobs[0] - 0.5f32.ln()).abs() < 1e-3);
+    }
+
+    #[test]
+    fn most_likely_tokens_in_order() {
+        let logprobs = [-3.0, -0.5, -2.0, -1.0, f32::NEG_INFINITY];
+        assert_eq!(most_likely(&logprobs, 3), [(1, -0.5), (3, -1.0), (2, -2.0)]);
+        assert_eq!(most_likely(&logprobs, 1), [(1, -0.5)]);
+        assert_eq!(most_likely(&logprobs, 10).len(), 5);
+        assert!(most_likely(&logprobs, 0).is_empty());
+    }
+}
@@ -1,5 +1,5 @@
 use axum::{
-    routing::{get, put},
+    routing::{get, post, put},
     Router,
     extract::{self, Query},
     Extension,
@@ -7,13 +7,14 @@ use axum::{
     response::{
         AppendHeaders, 
         IntoResponse,
-        sse::{Sse, Event},
+        sse::{Sse, Event, KeepAlive},
     }, 
-    http::{StatusCode, header::SET_COOKIE},
+    http::{StatusCode, header::{CONTENT_DISPOSITION, CONTENT_TYPE, SET_COOKIE}},
 };
 use futures_core::stream::Stream;
 
 use axum_extra::extract::{cookie::Cookie, CookieJar};
+use clap::{Parser, Subcommand};
 use maud::html;
 use serde_json;
 use serde::Deserialize;
@@ -23,15 +24,17 @@ use tower_http::{
     services::ServeDir,
 };
 use tracing::{Level, log::error};
+use tracing_subscriber::{filter::LevelFilter, prelude::*};
 
 use std::{
+    collections::HashMap,
     convert::Infallible, 
-    time::Duration,
+    time::{Duration, Instant},
     env,
     fs,
+    pin::Pin,
     sync::Arc,
     net::SocketAddr,
-    sync::Mutex,
 };
 
 mod icon;
@@ -40,48 +43,183 @@ mod template;
 mod page;
 mod theme;
 mod llama;
+mod conversation;
+mod scheduler;
+mod registry;
+mod router;
+mod hub;
+mod metrics;
+mod profiler;
+mod openai;
+mod embedder;
+mod bench;
+
+#[derive(Parser)]
+#[command(version, about)]
+struct Cli {
+    /// Only use models that are in the local hf-hub cache, never download
+    #[arg(long, global = true)]
+    offline: bool,
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Serves the web app, the default
+    Serve {
+        /// Captures a Chrome trace of the first seconds, at most 300, loading the models included
+        #[arg(long, value_name = "SECONDS")]
+        profile: Option<u64>,
+    },
+    /// Compares the throughput of serial and batched generations
+    Bench(bench::Args),
+    /// Manages the models in the hf-hub cache
+    #[command(subcommand)]
+    Models(ModelsCommand),
+}
+
+#[derive(Subcommand)]
+enum ModelsCommand {
+    /// Downloads a file of a model repository and verifies its SHA-256
+    Pull {
+        /// e.g. TheBloke/Llama-2-7B-Chat-GGUF
+        repo: String,
+        /// e.g. llama-2-7b-chat.Q4_K_M.gguf
+        file: String,
+        #[arg(long)]
+        revision: Option<String>,
+        /// Expected SHA-256, LFS files are checked against the hub's hash without it
+        #[arg(long)]
+        sha256: Option<String>,
+    },
+}
 
 #[tokio::main]
 async fn main() {
+    let cli = Cli::parse();
+    let hub = hub::Hub::new(cli.offline);
+    match cli.command {
+        Some(Command::Bench(args)) => {
+            if let Err(e) = bench::run(args).await {
+                panic!("Benchmark failed: {:?}", e);
+            }
+        },
+        Some(Command::Models(ModelsCommand::Pull { repo, file, revision, sha256 })) => {
+            let hub_file = hub::HubFile { repo, file, revision, sha256 };
+            match tokio::task::spawn_blocking(move || hub.pull(&hub_file)).await {
+                Ok(Ok(path)) => println!("{}", path.display()),
+                Ok(Err(e)) => {
+                    eprintln!("{}", e);
+                    std::process::exit(1);
+                },
+                Err(e) => panic!("Pull failed: {:?}", e),
+            }
+        },
+        Some(Command::Serve { profile }) => serve(hub, profile.map(Duration::from_secs)).await,
+        None => serve(hub, None).await,
+    }
+}
+
+async fn serve(hub: hub::Hub, profile: Option<Duration>) {
     let out_path = env!("OUT_DIR");
     let assets_path = format!("{out_path}/assets");
 
-    let llama = match llama::Llama::new(
-        "models/llama-2-7b.Q2_K.gguf",
-        "models/tokenizer.json",
-        llama::Config { ..Default::default()},
-    ) {
-        Ok(llama) => llama,
+    let metrics = Arc::new(metrics::Metrics::new().expect("Should be able to register the metrics"));
+    let models = read_models().unwrap_or_else(|e| panic!("{}", e));
+    let embedding_model = read_embedding()
+        .unwrap_or_else(|e| panic!("{}", e))
+        .map(|config| Arc::new(embedder::EmbeddingModel::new(config, hub.clone(), metrics.clone())));
+    let registry = match registry::Registry::new(models, hub, metrics.clone()) {
+        Ok(registry) => registry,
+        Err(e) => {
+            panic!("Invalid models.json: {}", e);
+        },
+    };
+    let routing = read_routing().unwrap_or_else(|e| panic!("{}", e));
+    let router = match router::Router::new(routing, &registry.names()) {
+        Ok(router) => router,
         Err(e) => {
-            panic!("Failed to load llama: {:?}", e);
+            panic!("Invalid routing.json: {}", e);
         },
     };
-    let shared_llama_mutex = Arc::new(Mutex::new(llama));
 
     // Will eventually remove and store actual message in postgres
     let fake_messages = fs::read_to_string("./fake-messages.json")
         .expect("Should be able to read fake-messages.json to string");
     let fm_list: Vec<page::FakeMessage> = serde_json::from_str(&fake_messages)
         .expect("Should be able to parse fake-message json from string");
+    let shared_conversations = Arc::new(conversation::Conversations::new(fm_list.clone()));
     let shared_fm_list = Arc::new(fm_list);
 
     let (non_blocking, _guard) = tracing_appender::non_blocking(std::io::stdout());
-    tracing_subscriber::fmt()
-        .compact()
-        .with_max_level(tracing::Level::INFO)
-        .with_writer(non_blocking)
+    let (profiler_layer, profiler) = profiler::Profiler::new("./traces");
+    tracing_subscriber::registry()
+        .with(profiler_layer)
+        .with(tracing_subscriber::fmt::layer()
+            .compact()
+            .with_writer(non_blocking)
+            .with_filter(LevelFilter::INFO))
         .init();
-    
+    let profiler = Arc::new(profiler);
+    if let Some(window) = profile {
+        if let Err(e) = profiler.capture_for(window) {
+            error!("Failed to start profiling: {}", e);
+        }
+    }
+
+    // Serve right away, conversations wait for the model they need while it loads
+    let shared_registry = Arc::new(registry);
+    tokio::spawn({
+        let registry = shared_registry.clone();
+        async move { registry.load_eager().await }
+    });
+    if let Some(embedding_model) = embedding_model.clone() {
+        tokio::spawn(async move {
+            if let Err(e) = embedding_model.get().await {
+                error!("Failed to load the embedding model: {}", e);
+            }
+        });
+    }
+
+    let api_keys = openai::ApiKeys::from_env();
+    if api_keys.is_empty() {
+        tracing::warn!("CAIT_API_KEYS is not set, the OpenAI API and the admin routes are open to everyone");
+    }
+    let openai_api = Router::new()
+        .route("/v1/chat/completions", post(openai::chat_completions))
+        .route("/v1/embeddings", post(openai::embeddings))
+        .route_layer(axum::middleware::from_fn_with_state(api_keys.clone(), openai::authenticate));
+    // Reloads and traces take a lot of memory and disk, so they take the same keys as the API
+    let admin_api = Router::new()
+        .route("/admin/models/:name/reload", post(reload_model))
+        .route("/admin/routing/reload", post(reload_routing))
+        .route("/admin/profiling", post(start_profiling).delete(stop_profiling))
+        .route("/admin/traces/:file", get(download_trace))
+        .route_layer(axum::middleware::from_fn_with_state(api_keys, openai::authenticate));
+
     let app = Router::new()
         .route("/", get(home))
         .route("/admin", get(admin))
+        .route("/ready", get(ready))
+        .route("/metrics", get(render_metrics))
         .route("/conversations", get(conversations))
         .route("/conversations/:id", get(conversation).post(message))
+        .route("/conversations/:id/model", put(conversation_model))
         .layer(axum::Extension(shared_fm_list))
         .route("/chatbot", get(chatbot))
-        .layer(axum::Extension(shared_llama_mutex))
+        .merge(openai_api)
+        .merge(admin_api)
+        .layer(axum::Extension(shared_registry))
+        .layer(axum::Extension(embedding_model))
+        .layer(axum::Extension(Arc::new(router)))
+        .layer(axum::Extension(metrics.clone()))
+        .layer(axum::Extension(profiler))
+        .layer(axum::Extension(shared_conversations))
         .route("/settings", get(settings))
         .route("/settings/theme", put(settings_theme))
+        .route("/settings/confidence", put(settings_confidence))
+        .layer(axum::middleware::from_fn_with_state(metrics, metrics::track_http))
         .layer(
             TraceLayer::new_for_http()
                 .make_span_with(trace::DefaultMakeSpan::new()
@@ -100,6 +238,32 @@ async fn main() {
         .unwrap();
 }
 
+fn read_models() -> Result<Vec<registry::ModelConfig>, String> {
+    let models = fs::read_to_string("./models.json")
+        .map_err(|e| format!("Failed to read models.json: {e}"))?;
+    serde_json::from_str(&models).map_err(|e| format!("Failed to parse models.json: {e}"))
+}
+
+// Without a routing.json every question goes to the default model
+fn read_routing() -> Result<router::RoutingConfig, String> {
+    match fs::read_to_string("./routing.json") {
+        Ok(routing) => serde_json::from_str(&routing).map_err(|e| format!("Failed to parse routing.json: {e}")),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(router::RoutingConfig::default()),
+        Err(e) => Err(format!("Failed to read routing.json: {e}")),
+    }
+}
+
+// The embedding model is optional until retrieval needs it
+fn read_embedding() -> Result<Option<embedder::EmbeddingConfig>, String> {
+    match fs::read_to_string("./embedding.json") {
+        Ok(embedding) => serde_json::from_str(&embedding)
+            .map(Some)
+            .map_err(|e| format!("Failed to parse embedding.json: {e}")),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(format!("Failed to read embedding.json: {e}")),
+    }
+}
+
 async fn home(jar: CookieJar) -> impl IntoResponse {
     let (color_scheme, jar) = init_and_extract_theme(jar);
     (
@@ -111,17 +275,136 @@ async fn home(jar: CookieJar) -> impl IntoResponse {
     )
 }
 
-async fn admin(jar: CookieJar) -> impl IntoResponse {
+async fn admin(
+    Extension(registry): Extension<Arc<registry::Registry>>,
+    Extension(router): Extension<Arc<router::Router>>,
+    Extension(profiler): Extension<Arc<profiler::Profiler>>,
+    jar: CookieJar,
+) -> impl IntoResponse {
     let (color_scheme, jar) = init_and_extract_theme(jar);
+    let status = registry.status();
+    let models: Vec<_> = status
+        .iter()
+        .map(|(name, readiness, scheduler)| {
+            (*name, readiness, scheduler.as_ref().map(|s| s.llama().cache_stats()))
+        })
+        .collect();
     (
         jar,
         html! {
             (template::head("Cait - Admin", color_scheme.derive_class()))
-            (page::admin())
+            (page::admin(&models, &router.config(), profiler.running(), &profiler.traces()))
         }
     )
 }
 
+/// Loads the model again from its current entry in models.json. That happens in the background,
+/// the model keeps serving from the old weights until the new ones are ready.
+/// A model that's still loading is left alone.
+async fn reload_model(
+    extract::Path(name): extract::Path<String>,
+    Extension(registry): Extension<Arc<registry::Registry>>,
+) -> StatusCode {
+    if !registry.contains(&name) {
+        return StatusCode::NOT_FOUND;
+    }
+    let config = match read_models() {
+        Ok(models) => models.into_iter().find(|m| m.name == name),
+        Err(e) => {
+            error!("{}", e);
+            return StatusCode::INTERNAL_SERVER_ERROR;
+        },
+    };
+    match registry.reload(&name, config) {
+        Ok(()) => StatusCode::ACCEPTED,
+        Err(e @ registry::RegistryError::AlreadyLoading(_)) => {
+            error!("{}", e);
+            StatusCode::CONFLICT
+        },
+        Err(e) => {
+            error!("{}", e);
+            StatusCode::BAD_REQUEST
+        },
+    }
+}
+
+/// Reads the rules from routing.json again. Invalid rules are rejected and the old ones stay.
+async fn reload_routing(
+    Extension(registry): Extension<Arc<registry::Registry>>,
+    Extension(router): Extension<Arc<router::Router>>,
+) -> StatusCode {
+    let routing = match read_routing() {
+        Ok(routing) => routing,
+        Err(e) => {
+            error!("{}", e);
+            return StatusCode::INTERNAL_SERVER_ERROR;
+        },
+    };
+    match router.reload(routing, &registry.names()) {
+        Ok(()) => StatusCode::NO_CONTENT,
+        Err(e) => {
+            error!("Invalid routing.json: {}", e);
+            StatusCode::BAD_REQUEST
+        },
+    }
+}
+
+#[derive(Deserialize)]
+struct ProfilingForm {
+    seconds: u64,
+}
+
+/// Captures a Chrome trace of everything that happens in the next seconds.
+async fn start_profiling(
+    Extension(profiler): Extension<Arc<profiler::Profiler>>,
+    Form(form): Form<ProfilingForm>,
+) -> impl IntoResponse {
+    if let Err(e) = profiler.capture_for(Duration::from_secs(form.seconds)) {
+        error!("Failed to start profiling: {}", e);
+    }
+    component::profiling(profiler.running(), &profiler.traces())
+}
+
+async fn stop_profiling(Extension(profiler): Extension<Arc<profiler::Profiler>>) -> impl IntoResponse {
+    profiler.stop();
+    component::profiling(profiler.running(), &profiler.traces())
+}
+
+async fn download_trace(
+    extract::Path(file): extract::Path<String>,
+    Extension(profiler): Extension<Arc<profiler::Profiler>>,
+) -> Result<impl IntoResponse, StatusCode> {
+    let path = profiler.trace(&file).ok_or(StatusCode::NOT_FOUND)?;
+    let trace = tokio::fs::read(path).await.map_err(|e| {
+        error!("Failed to read trace {}: {}", file, e);
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+    let disposition = format!("attachment; filename=\"{file}\"");
+    Ok(([(CONTENT_TYPE, String::from("application/json")), (CONTENT_DISPOSITION, disposition)], trace))
+}
+
+async fn render_metrics(Extension(metrics): Extension<Arc<metrics::Metrics>>) -> impl IntoResponse {
+    match metrics.render() {
+        Ok(text) => Ok(([(CONTENT_TYPE, "text/plain; version=0.0.4")], text)),
+        Err(e) => {
+            error!("Failed to render metrics: {}", e);
+            Err(StatusCode::INTERNAL_SERVER_ERROR)
+        },
+    }
+}
+
+/// 200 once the default model can take conversations, 503 with how far it got before that.
+async fn ready(Extension(registry): Extension<Arc<registry::Registry>>) -> impl IntoResponse {
+    let default_model = registry.default_model();
+    let readiness = registry.readiness(default_model);
+    // Old weights keep serving while a reload runs or after it failed
+    let status = match registry.ready(default_model) {
+        Some(_) => StatusCode::OK,
+        None => StatusCode::SERVICE_UNAVAILABLE,
+    };
+    (status, readiness.to_string())
+}
+
 async fn conversations(
     Extension(fm_list): Extension<Arc<Vec<page::FakeMessage>>>,
     jar: CookieJar,
@@ -138,40 +421,78 @@ async fn conversations(
 
 async fn conversation(
     extract::Path(id): extract::Path<String>, 
-    Extension(fm_list): Extension<Arc<Vec<page::FakeMessage>>>,
+    Extension(conversations): Extension<Arc<conversation::Conversations>>,
+    Extension(registry): Extension<Arc<registry::Registry>>,
     jar: CookieJar
 ) -> impl IntoResponse {
     let (color_scheme, jar) = init_and_extract_theme(jar);
+    let model = conversations.model(&id);
     (
         jar,
         html! {
             (template::head(&format!("cait - {id}"), color_scheme.derive_class()))
-            (page::conversation(&id, fm_list.as_ref()))
+            (page::conversation(&id, &conversations.messages(&id), &registry.names(), model.as_deref()))
         }
     )
 }
 
+#[derive(Deserialize)]
+struct ModelForm {
+    /// Empty to let the router pick the model of every message
+    model: String,
+}
+
+async fn conversation_model(
+    extract::Path(id): extract::Path<String>,
+    Extension(conversations): Extension<Arc<conversation::Conversations>>,
+    Extension(registry): Extension<Arc<registry::Registry>>,
+    Form(form): Form<ModelForm>,
+) -> StatusCode {
+    if form.model.is_empty() {
+        conversations.unset_model(&id);
+        return StatusCode::NO_CONTENT;
+    }
+    if !registry.contains(&form.model) {
+        error!("Unknown model picked: {}", form.model);
+        return StatusCode::BAD_REQUEST;
+    }
+    conversations.set_model(&id, &form.model);
+    StatusCode::NO_CONTENT
+}
+
 #[derive(Deserialize)]
 struct Message {
     agent: String,
     content: String,
 }
 
-async fn message(m: Form<Message>) -> impl IntoResponse {
+async fn message(
+    extract::Path(id): extract::Path<String>,
+    Extension(conversations): Extension<Arc<conversation::Conversations>>,
+    jar: CookieJar,
+    m: Form<Message>,
+) -> impl IntoResponse {
     let agent = page::str_to_agent(m.agent.as_str());
+    conversations.push(&id, page::FakeMessage { from: m.agent.clone(), content: m.content.clone(), seed: None, model: None, route: None });
+    let stream_url = if highlight_confidence(&jar) {
+        format!("/chatbot?conversation={id}&highlight=true")
+    } else {
+        format!("/chatbot?conversation={id}")
+    };
     html! {
-        (component::message(agent, m.content.as_str(), false))
-        (component::message(page::Agent::Chatbot, m.content.as_str(), true))
+        (component::message(agent, m.content.as_str(), None))
+        (component::message(page::Agent::Chatbot, "", Some(&stream_url)))
     }
 }
 
 async fn settings(jar: CookieJar) -> impl IntoResponse {
     let (color_scheme, jar) = init_and_extract_theme(jar);
+    let highlight = highlight_confidence(&jar);
     (
         jar,
         html! {
             (template::head("Cait - Settings", color_scheme.derive_class()))
-            (page::settings(color_scheme))
+            (page::settings(color_scheme, highlight))
         }
     )
 }
@@ -187,6 +508,29 @@ async fn settings_theme(Form(theme_form): Form<ThemeForm>) -> impl IntoResponse
     )
 }
 
+#[derive(Deserialize)]
+struct ConfidenceForm {
+    highlight_confidence: String,
+}
+
+async fn settings_confidence(Form(form): Form<ConfidenceForm>) -> impl IntoResponse {
+    let highlight = form.highlight_confidence == "on";
+    let cookie = format!(
+        "highlight_confidence={}; Path=/; Secure; SameSite=Lax; HttpOnly",
+        if highlight { "on" } else { "off" },
+    );
+    (
+        AppendHeaders([(SET_COOKIE, cookie)]),
+        html! {
+            (component::confidence_preference(highlight))
+        }
+    )
+}
+
+fn highlight_confidence(jar: &CookieJar) -> bool {
+    jar.get("highlight_confidence").map_or(false, |c| c.value() == "on")
+}
+
 fn init_and_extract_theme(jar: CookieJar) -> (ColorScheme, CookieJar) {
     if let Some(color_mode_cookie) = jar.get("color_mode") {
         if let Some(selected_color_cookie) = jar.get("selected_color") {
@@ -221,28 +565,276 @@ fn extract_theme_from_form(theme: ThemeForm) -> ColorScheme {
         })
 }
 
+#[derive(Deserialize)]
+struct ChatbotQuery {
+    conversation: String,
+    temperature: Option<f64>,
+    top_p: Option<f64>,
+    seed: Option<u64>,
+    repeat_penalty: Option<f32>,
+    repeat_last_n: Option<usize>,
+    frequency_penalty: Option<f32>,
+    presence_penalty: Option<f32>,
+    top_k: Option<usize>,
+    min_p: Option<f64>,
+    typical_p: Option<f64>,
+    /// Turns on mirostat
+    mirostat_tau: Option<f32>,
+    mirostat_eta: Option<f32>,
+    /// JSON object from token ids to the bias added to their logits
+    logit_bias: Option<String>,
+    /// Comma separated ids of tokens the answer can't contain
+    banned_tokens: Option<String>,
+    /// GBNF grammar the answer has to follow
+    grammar: Option<String>,
+    /// JSON schema the answer has to match, instead of a grammar
+    json_schema: Option<String>,
+    /// Sends a logprob event with this many alternatives for every token
+    logprobs: Option<usize>,
+    /// Underlines the text the model was unsure about
+    #[serde(default)]
+    highlight: bool,
+    max_tokens: Option<usize>,
+    stop: Option<String>,
+}
+
 async fn chatbot(
-    m: Query<Message>,
-    Extension(llama_mutex): Extension<Arc<Mutex<llama::Llama>>>,
+    q: Query<ChatbotQuery>,
+    Extension(registry): Extension<Arc<registry::Registry>>,
+    Extension(router): Extension<Arc<router::Router>>,
+    Extension(conversations): Extension<Arc<conversation::Conversations>>,
+    Extension(metrics): Extension<Arc<metrics::Metrics>>,
 ) -> Result<Sse<impl Stream<Item = Result<Event, Infallible>>>, StatusCode> {
-    tracing::info!("prompt: {}", m.content);
-    
-    let Ok(llama) = llama_mutex.lock() else {
-        return Err(StatusCode::INTERNAL_SERVER_ERROR);
+    let start = Instant::now();
+    let history = conversations.chat_history(&q.conversation);
+    if let Some(prompt) = history.last() {
+        tracing::info!("prompt: {}", prompt.content);
+    }
+    // A model picked for the conversation answers everything, the router decides otherwise
+    let route = match conversations.model(&q.conversation) {
+        Some(model) => router::Decision::picked(model),
+        None => router.route(&history, registry.default_model()),
+    };
+    tracing::info!(model = %route.model, reason = %route.reason, "Routed message");
+    let model = route.model.clone();
+
+    let format = match (&q.grammar, &q.json_schema) {
+        (None, None) => None,
+        (Some(grammar), None) => Some(llama::Format::Grammar(grammar.clone())),
+        (None, Some(schema)) => match serde_json::from_str(schema) {
+            Ok(schema) => Some(llama::Format::JsonSchema(schema)),
+            Err(e) => {
+                error!("Invalid json_schema: {}", e);
+                return Err(StatusCode::BAD_REQUEST);
+            },
+        },
+        (Some(_), Some(_)) => {
+            error!("Only one of grammar and json_schema can be given");
+            return Err(StatusCode::BAD_REQUEST);
+        },
+    };
+    let logit_bias = match q.logit_bias.as_deref().map(serde_json::from_str::<HashMap<u32, f32>>) {
+        None => HashMap::new(),
+        Some(Ok(logit_bias)) => logit_bias,
+        Some(Err(e)) => {
+            error!("Invalid logit_bias: {}", e);
+            return Err(StatusCode::BAD_REQUEST);
+        },
+    };
+    let banned_tokens = match q.banned_tokens.as_deref().map(parse_token_ids) {
+        None => vec![],
+        Some(Ok(banned_tokens)) => banned_tokens,
+        Some(Err(e)) => {
+            error!("Invalid banned_tokens: {}", e);
+            return Err(StatusCode::BAD_REQUEST);
+        },
+    };
+    let mut params = llama::Params {
+        temperature: q.temperature,
+        top_p: q.top_p,
+        seed: q.seed,
+        repeat_penalty: q.repeat_penalty,
+        repeat_last_n: q.repeat_last_n,
+        frequency_penalty: q.frequency_penalty,
+        presence_penalty: q.presence_penalty,
+        top_k: q.top_k,
+        min_p: q.min_p,
+        typical_p: q.typical_p,
+        mirostat: q.mirostat_tau.map(|tau| llama::Mirostat { tau, eta: q.mirostat_eta.unwrap_or(llama::Mirostat::DEFAULT_ETA) }),
+        logit_bias,
+        banned_tokens,
+        format,
+        logprobs: q.logprobs.or(if q.highlight { Some(0) } else { None }),
+        max_tokens: q.max_tokens,
+        stop: q.stop.iter().cloned().collect(),
+        ..Default::default()
     };
-    tracing::info!("Got llama lock");
-    let token_stream = llama.run(m.content.clone());
-    let event_stream = stream_events(token_stream);
+    let updates: Pin<Box<dyn Stream<Item = Result<scheduler::Update, String>> + Send>> =
+        match registry.ready(&model) {
+            Some(scheduler) => {
+                if let Err(e) = scheduler.llama().validate(&mut params) {
+                    error!("Invalid chatbot params: {}", e);
+                    return Err(StatusCode::BAD_REQUEST);
+                }
+                let Ok(updates) = scheduler.chat(Some(q.conversation.clone()), history, params) else {
+                    error!("Chatbot queue is full");
+                    return Err(StatusCode::SERVICE_UNAVAILABLE);
+                };
+                Box::pin(updates)
+            },
+            None => Box::pin(chat_once_loaded(
+                registry.clone(),
+                model.clone(),
+                q.conversation.clone(),
+                history,
+                params,
+            )),
+        };
+    let events = EventOptions { logprobs: q.logprobs.is_some(), highlight: q.highlight };
+    let event_stream = stream_events(updates, conversations, metrics, start, q.0.conversation, route, events);
+
+    // Dropping the event stream cancels the generation. Hyper only notices that the client went
+    // away when it writes, so keep writing while the generation waits for the model.
+    Ok(Sse::new(event_stream).keep_alive(KeepAlive::new().interval(Duration::from_secs(1))))
+}
+
+// Token ids like "13,29871"
+fn parse_token_ids(ids: &str) -> Result<Vec<u32>, std::num::ParseIntError> {
+    ids.split(',').map(|id| id.trim().parse()).collect()
+}
 
-    Ok(Sse::new(event_stream))
+/// Reports the progress of loading the model until it's ready, then chats as usual. The params
+/// can only be checked once the model is there, so errors come as events instead of statuses.
+fn chat_once_loaded(
+    registry: Arc<registry::Registry>,
+    model: String,
+    conversation: String,
+    history: Vec<llama::chat::ChatMessage>,
+    mut params: llama::Params,
+) -> impl Stream<Item = Result<scheduler::Update, String>> {
+    async_stream::stream! {
+        let loading = registry.get(&model);
+        tokio::pin!(loading);
+        let loaded = loop {
+            tokio::select! {
+                result = &mut loading => break result,
+                _ = tokio::time::sleep(Duration::from_millis(500)) => {},
+            }
+            if let registry::Readiness::Loading(progress) = registry.readiness(&model) {
+                yield Ok(scheduler::Update::Loading { progress });
+            }
+        };
+        match loaded {
+            Err(e) => yield Err(e.to_string()),
+            Ok(scheduler) => match scheduler.llama().validate(&mut params) {
+                Err(e) => yield Err(e.to_string()),
+                Ok(()) => match scheduler.chat(Some(conversation), history, params) {
+                    Err(_) => yield Err(String::from("the chatbot queue is full")),
+                    Ok(updates) => {
+                        for await update in updates {
+                            yield update;
+                        }
+                    },
+                },
+            },
+        }
+    }
+}
+
+// Below this log probability, about 30%, a token counts as a guess
+const LOW_CONFIDENCE_LOGPROB: f32 = -1.2;
+
+struct EventOptions {
+    /// Send the logprob of every token as JSON
+    logprobs: bool,
+    highlight: bool,
 }
 
-fn stream_events<S: Stream<Item = Result<String, String>>>(s: S) -> impl Stream<Item = Result<Event, Infallible>> {
+#[allow(clippy::too_many_arguments)]
+fn stream_events<S: Stream<Item = Result<scheduler::Update, String>>>(
+    s: S,
+    conversations: Arc<conversation::Conversations>,
+    metrics: Arc<metrics::Metrics>,
+    // When the request came in
+    start: Instant,
+    conversation_id: String,
+    route: router::Decision,
+    options: EventOptions,
+) -> impl Stream<Item = Result<Event, Infallible>> {
     async_stream::stream! {
+        let _active = metrics.open_stream();
+        // Waiting in line only starts once the model is loaded
+        let mut waiting_since = start;
+        let mut first_token = true;
+        yield Ok(Event::default().event("route").data(format!("{}, {}", route.model, route.reason)));
+        let mut answer = String::new();
+        let mut seed = None;
+        // The lowest logprob of the tokens since the last text
+        let mut lowest_logprob: Option<f32> = None;
         for await message in s {
             match message {
-                Ok(message) => {
-                    let html_fragment = format!("<span>{}</span>", message);
+                Ok(scheduler::Update::Loading { progress }) => {
+                    waiting_since = Instant::now();
+                    yield Ok(Event::default().event("queue").data(format!("Loading the model, {:.0}%", progress * 100.0)));
+                },
+                Ok(scheduler::Update::Queued { position }) => {
+                    yield Ok(Event::default().event("queue").data(format!("Waiting in line, position {}", position)));
+                },
+                Ok(scheduler::Update::Admitted) => {
+                    metrics.observe_queue_wait(&route.model, waiting_since.elapsed());
+                    yield Ok(Event::default().event("queue").data(""));
+                },
+                Ok(scheduler::Update::Output(llama::Output::Compacted { dropped_messages })) => {
+                    yield Ok(Event::default().event("compacted").data(format!(
+                        "The {} oldest messages were left out to fit the conversation into the model's memory",
+                        dropped_messages,
+                    )));
+                },
+                Ok(scheduler::Update::Output(llama::Output::Seed(used_seed))) => {
+                    tracing::info!("seed: {}", used_seed);
+                    seed = Some(used_seed);
+                    yield Ok(Event::default().event("seed").data(format!("seed {}", used_seed)));
+                },
+                Ok(scheduler::Update::Output(llama::Output::Finished {
+                    reason,
+                    prompt_tokens,
+                    reused_tokens,
+                    completion_tokens,
+                    prompt_duration,
+                    generation_duration,
+                })) => {
+                    tracing::info!("finished: {:?} after {} tokens", reason, completion_tokens);
+                    metrics.observe_generation(
+                        &route.model,
+                        prompt_tokens - reused_tokens,
+                        prompt_duration,
+                        completion_tokens,
+                        generation_duration,
+                    );
+                },
+                Ok(scheduler::Update::Output(llama::Output::Logprob(logprob))) => {
+                    lowest_logprob = Some(lowest_logprob.map_or(logprob.logprob, |l| l.min(logprob.logprob)));
+                    if options.logprobs {
+                        match serde_json::to_string(&logprob) {
+                            Ok(json) => yield Ok(Event::default().event("logprob").data(json)),
+                            Err(e) => error!("Failed to serialize logprob: {}", e),
+                        }
+                    }
+                },
+                Ok(scheduler::Update::Output(llama::Output::Text(message))) => {
+                    if first_token {
+                        first_token = false;
+                        metrics.observe_time_to_first_token(&route.model, start.elapsed());
+                    }
+                    answer.push_str(&message);
+                    let html_fragment = match lowest_logprob.take() {
+                        Some(logprob) if options.highlight && logprob < LOW_CONFIDENCE_LOGPROB => format!(
+                            "<span class=\"low-confidence\" title=\"{:.0}% sure\">{}</span>",
+                            logprob.exp() * 100.0,
+                            message,
+                        ),
+                        _ => format!("<span>{}</span>", message),
+                    };
                     tracing::info!("response: {}", html_fragment);
                     yield Ok(Event::default().event("chatbot").data(html_fragment));
                     yield Ok(Event::default().event("chatbot").data("\n"));
@@ -267,5 +859,12 @@ fn stream_events<S: Stream<Item = Result<String, String>>>(s: S) -> impl Stream<
                 },
             }
         }
+        conversations.push(&conversation_id, page::FakeMessage {
+            from: String::from("chatbot"),
+            content: answer.trim().to_string(),
+            seed,
+            model: Some(route.model),
+            route: Some(route.reason),
+        });
     }
 }
\ No newline at end of file
@@ -0,0 +1,153 @@
+use std::sync::Arc;
+use std::time::Duration;
+
+use axum::extract::{MatchedPath, State};
+use axum::http::Request;
+use axum::middleware::Next;
+use axum::response::Response;
+use prometheus::{
+    exponential_buckets, Encoder, HistogramOpts, HistogramVec, IntGauge, Opts, Registry, TextEncoder,
+};
+
+const TOKENS_PER_SECOND_BUCKETS: [f64; 10] = [1.0, 2.0, 5.0, 10.0, 20.0, 50.0, 100.0, 200.0, 500.0, 1000.0];
+const LOAD_SECONDS_BUCKETS: [f64; 9] = [1.0, 2.0, 5.0, 10.0, 20.0, 30.0, 60.0, 120.0, 300.0];
+
+/// What `/metrics` reports, in the Prometheus text format.
+pub struct Metrics {
+    registry: Registry,
+    prompt_tokens_per_second: HistogramVec,
+    generation_tokens_per_second: HistogramVec,
+    time_to_first_token: HistogramVec,
+    queue_wait: HistogramVec,
+    active_streams: IntGauge,
+    model_load: HistogramVec,
+    http_requests: HistogramVec,
+}
+
+impl Metrics {
+    pub fn new() -> prometheus::Result<Metrics> {
+        let registry = Registry::new_custom(Some(String::from("cait")), None)?;
+        let latency_buckets = exponential_buckets(0.005, 2.0, 14)?;
+        let histogram = |name: &str, help: &str, buckets: Vec<f64>, labels: &[&str]| {
+            let histogram = HistogramVec::new(HistogramOpts::new(name, help).buckets(buckets), labels)?;
+            registry.register(Box::new(histogram.clone()))?;
+            Ok::<_, prometheus::Error>(histogram)
+        };
+        let metrics = Metrics {
+            prompt_tokens_per_second: histogram(
+                "prompt_tokens_per_second",
+                "Prompt processing speed, without the tokens reused from the prefix cache",
+                TOKENS_PER_SECOND_BUCKETS.to_vec(),
+                &["model"],
+            )?,
+            generation_tokens_per_second: histogram(
+                "generation_tokens_per_second",
+                "Generation speed of the answers",
+                TOKENS_PER_SECOND_BUCKETS.to_vec(),
+                &["model"],
+            )?,
+            time_to_first_token: histogram(
+                "time_to_first_token_seconds",
+                "Time from the request until the first text of the answer, loading the model included",
+                latency_buckets.clone(),
+                &["model"],
+            )?,
+            queue_wait: histogram(
+                "queue_wait_seconds",
+                "Time a generation waited for a free slot of the model",
+                latency_buckets.clone(),
+                &["model"],
+            )?,
+            active_streams: IntGauge::with_opts(Opts::new("active_streams", "Open server-sent event streams"))?,
+            model_load: histogram(
+                "model_load_seconds",
+                "Time it took to load a model",
+                LOAD_SECONDS_BUCKETS.to_vec(),
+                &["model"],
+            )?,
+            http_requests: histogram(
+                "http_request_duration_seconds",
+                "Time until the response headers of an HTTP request were ready",
+                latency_buckets,
+                &["method", "route", "status"],
+            )?,
+            registry,
+        };
+        metrics.registry.register(Box::new(metrics.active_streams.clone()))?;
+        Ok(metrics)
+    }
+
+    /// Records the speed of a finished generation.
+    pub fn observe_generation(
+        &self,
+        model: &str,
+        prompt_tokens: usize,
+        prompt_duration: Duration,
+        completion_tokens: usize,
+        generation_duration: Duration,
+    ) {
+        if prompt_tokens > 0 && !prompt_duration.is_zero() {
+            self.prompt_tokens_per_second
+                .with_label_values(&[model])
+                .observe(prompt_tokens as f64 / prompt_duration.as_secs_f64());
+        }
+        if completion_tokens > 0 && !generation_duration.is_zero() {
+            self.generation_tokens_per_second
+                .with_label_values(&[model])
+                .observe(completion_tokens as f64 / generation_duration.as_secs_f64());
+        }
+    }
+
+    pub fn observe_time_to_first_token(&self, model: &str, duration: Duration) {
+        self.time_to_first_token.with_label_values(&[model]).observe(duration.as_secs_f64());
+    }
+
+    pub fn observe_queue_wait(&self, model: &str, duration: Duration) {
+        self.queue_wait.with_label_values(&[model]).observe(duration.as_secs_f64());
+    }
+
+    pub fn observe_model_load(&self, model: &str, duration: Duration) {
+        self.model_load.with_label_values(&[model]).observe(duration.as_secs_f64());
+    }
+
+    /// Counts a stream as open until the returned guard is dropped.
+    pub fn open_stream(&self) -> ActiveStream {
+        self.active_streams.inc();
+        ActiveStream(self.active_streams.clone())
+    }
+
+    pub fn render(&self) -> Result<String, prometheus::Error> {
+        let mut buffer = vec![];
+        TextEncoder::new().encode(&self.registry.gather(), &mut buffer)?;
+        String::from_utf8(buffer).map_err(|e| prometheus::Error::Msg(e.to_string()))
+    }
+}
+
+pub struct ActiveStream(IntGauge);
+
+impl Drop for ActiveStream {
+    fn drop(&mut self) {
+        self.0.dec();
+    }
+}
+
+/// Records the latency of every request by route, next to the `TraceLayer` that logs it.
+pub async fn track_http<B>(
+    State(metrics): State<Arc<Metrics>>,
+    request: Request<B>,
+    next: Next<B>,
+) -> Response {
+    let start = std::time::Instant::now();
+    let method = request.method().to_string();
+    // Requests that no route matched are lumped together, so random paths don't add labels
+    let route = request
+        .extensions()
+        .get::<MatchedPath>()
+        .map_or(String::from("unmatched"), |path| path.as_str().to_string());
+    let response = next.run(request).await;
+    metrics
+        .http_requests
+        .with_label_values(&[&method, &route, response.status().as_str()])
+        .observe(start.elapsed().as_secs_f64());
+    response
+}
@@ -0,0 +1,617 @@
+// The part of the OpenAI chat completions and embeddings APIs that our internal tools use, so
+// they can talk to cait without changes: https://platform.openai.com/docs/api-reference
+
+use std::convert::Infallible;
+use std::sync::Arc;
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+use axum::extract::rejection::JsonRejection;
+use axum::extract::State;
+use axum::http::{header::AUTHORIZATION, Request, StatusCode};
+use axum::middleware::Next;
+use axum::response::sse::{Event, KeepAlive, Sse};
+use axum::response::{IntoResponse, Response};
+use axum::{Extension, Json};
+use base64::Engine;
+use futures_core::stream::Stream;
+use futures_util::StreamExt;
+use serde::{Deserialize, Serialize};
+
+use crate::llama::chat::{ChatMessage, Role};
+use crate::llama::{self, FinishReason, Output};
+use crate::scheduler::Update;
+use crate::{embedder, metrics, registry, router};
+
+/// The model name that lets the router pick, like the web app does for new conversations.
+const AUTO_MODEL: &str = "auto";
+
+/// The bearer tokens that may call the API, from the comma separated `CAIT_API_KEYS`. Without
+/// any, the API is as open as the web app, which has no accounts or roles yet.
+#[derive(Clone, Default)]
+pub struct ApiKeys(Arc<Vec<String>>);
+
+impl ApiKeys {
+    pub fn from_env() -> ApiKeys {
+        let keys = std::env::var("CAIT_API_KEYS").unwrap_or_default();
+        ApiKeys::new(keys.split(',').map(str::trim).filter(|key| !key.is_empty()).map(String::from).collect())
+    }
+
+    pub fn new(keys: Vec<String>) -> ApiKeys {
+        ApiKeys(Arc::new(keys))
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    fn allows(&self, authorization: Option<&str>) -> bool {
+        if self.is_empty() {
+            return true;
+        }
+        let Some(key) = authorization.and_then(|header| header.strip_prefix("Bearer ")) else {
+            return false;
+        };
+        // Every key is compared in full, so the time taken doesn't tell how much of one matched
+        self.0.iter().fold(false, |found, k| found | constant_time_eq(k.as_bytes(), key.as_bytes()))
+    }
+}
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+/// Turns away requests without one of the API keys.
+pub async fn authenticate<B>(State(keys): State<ApiKeys>, request: Request<B>, next: Next<B>) -> Response {
+    let authorization = request.headers().get(AUTHORIZATION).and_then(|header| header.to_str().ok());
+    if !keys.allows(authorization) {
+        let message = "Missing or incorrect API key, send it as a bearer token";
+        return ApiError::new(StatusCode::UNAUTHORIZED, "invalid_api_key", message).into_response();
+    }
+    next.run(request).await
+}
+
+/// An error in the shape the OpenAI clients understand.
+#[derive(Debug)]
+pub struct ApiError {
+    status: StatusCode,
+    code: &'static str,
+    message: String,
+}
+
+impl ApiError {
+    fn new(status: StatusCode, code: &'static str, message: impl Into<String>) -> ApiError {
+        ApiError { status, code, message: message.into() }
+    }
+
+    fn invalid_request(message: impl Into<String>) -> ApiError {
+        ApiError::new(StatusCode::BAD_REQUEST, "invalid_request", message)
+    }
+
+    fn server(message: impl Into<String>) -> ApiError {
+        ApiError::new(StatusCode::INTERNAL_SERVER_ERROR, "server_error", message)
+    }
+
+    fn body(&self) -> serde_json::Value {
+        let kind = if self.status.is_client_error() { "invalid_request_error" } else { "server_error" };
+        serde_json::json!({
+            "error": { "message": self.message, "type": kind, "code": self.code },
+        })
+    }
+}
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        (self.status, Json(self.body())).into_response()
+    }
+}
+
+#[derive(Deserialize, Debug)]
+pub struct ChatCompletionRequest {
+    /// One of models.json, the router picks when it's left out or "auto"
+    model: Option<String>,
+    messages: Vec<Message>,
+    temperature: Option<f64>,
+    top_p: Option<f64>,
+    max_tokens: Option<usize>,
+    stop: Option<Stop>,
+    seed: Option<u64>,
+    frequency_penalty: Option<f32>,
+    presence_penalty: Option<f32>,
+    /// Only a single choice is supported
+    n: Option<usize>,
+    #[serde(default)]
+    stream: bool,
+}
+
+#[derive(Deserialize, Debug)]
+struct Message {
+    role: String,
+    content: Option<Content>,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(untagged)]
+enum Content {
+    Text(String),
+    Parts(Vec<Part>),
+}
+
+#[derive(Deserialize, Debug)]
+struct Part {
+    #[serde(rename = "type")]
+    kind: String,
+    text: Option<String>,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(untagged)]
+enum Stop {
+    One(String),
+    Many(Vec<String>),
+}
+
+impl ChatCompletionRequest {
+    fn chat_messages(&self) -> Result<Vec<ChatMessage>, String> {
+        if self.messages.is_empty() {
+            return Err(String::from("messages must not be empty"));
+        }
+        self.messages
+            .iter()
+            .map(|message| {
+                let role = match message.role.as_str() {
+                    "system" | "developer" => Role::System,
+                    "user" => Role::User,
+                    "assistant" => Role::Assistant,
+                    role => return Err(format!("unsupported role {role}")),
+                };
+                let content = match &message.content {
+                    // Assistant messages with tool calls come without content
+                    None => String::new(),
+                    Some(Content::Text(text)) => text.clone(),
+                    Some(Content::Parts(parts)) => parts
+                        .iter()
+                        .map(|part| match (part.kind.as_str(), &part.text) {
+                            ("text", Some(text)) => Ok(text.as_str()),
+                            (kind, _) => Err(format!("unsupported content part {kind}, only text is understood")),
+                        })
+                        .collect::<Result<Vec<&str>, String>>()?
+                        .join("\n"),
+                };
+                Ok(ChatMessage { role, content })
+            })
+            .collect()
+    }
+
+    fn params(&self) -> Result<llama::Params, String> {
+        if self.n.map_or(false, |n| n != 1) {
+            return Err(String::from("n must be 1, only a single choice is supported"));
+        }
+        let stop = match &self.stop {
+            None => vec![],
+            Some(Stop::One(stop)) => vec![stop.clone()],
+            Some(Stop::Many(stops)) => stops.clone(),
+        };
+        Ok(llama::Params {
+            temperature: self.temperature,
+            top_p: self.top_p,
+            seed: self.seed,
+            frequency_penalty: self.frequency_penalty,
+            presence_penalty: self.presence_penalty,
+            max_tokens: self.max_tokens,
+            stop,
+            ..Default::default()
+        })
+    }
+}
+
+#[derive(Serialize)]
+struct ChatCompletion {
+    id: String,
+    object: &'static str,
+    created: u64,
+    model: String,
+    choices: Vec<Choice>,
+    usage: Usage,
+}
+
+#[derive(Serialize)]
+struct Choice {
+    index: usize,
+    message: AssistantMessage,
+    finish_reason: Option<&'static str>,
+}
+
+#[derive(Serialize)]
+struct AssistantMessage {
+    role: &'static str,
+    content: String,
+}
+
+#[derive(Serialize, Default)]
+struct Usage {
+    prompt_tokens: usize,
+    completion_tokens: usize,
+    total_tokens: usize,
+}
+
+#[derive(Serialize)]
+struct ChatCompletionChunk<'a> {
+    id: &'a str,
+    object: &'static str,
+    created: u64,
+    model: &'a str,
+    choices: [ChunkChoice; 1],
+}
+
+#[derive(Serialize)]
+struct ChunkChoice {
+    index: usize,
+    delta: Delta,
+    finish_reason: Option<&'static str>,
+}
+
+#[derive(Serialize, Default)]
+struct Delta {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    role: Option<&'static str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    content: Option<String>,
+}
+
+fn finish_reason(reason: FinishReason) -> &'static str {
+    match reason {
+        FinishReason::Eos | FinishReason::Stop => "stop",
+        FinishReason::Length => "length",
+    }
+}
+
+/// Answers like `POST https://api.openai.com/v1/chat/completions`, as one JSON object or as
+/// server-sent event chunks when `stream` is set.
+pub async fn chat_completions(
+    Extension(registry): Extension<Arc<registry::Registry>>,
+    Extension(router): Extension<Arc<router::Router>>,
+    Extension(metrics): Extension<Arc<metrics::Metrics>>,
+    request: Result<Json<ChatCompletionRequest>, JsonRejection>,
+) -> Result<Response, ApiError> {
+    let start = Instant::now();
+    let Json(request) = request.map_err(|e| ApiError::invalid_request(e.body_text()))?;
+    let messages = request.chat_messages().map_err(ApiError::invalid_request)?;
+    let mut params = request.params().map_err(ApiError::invalid_request)?;
+    let route = match request.model.as_deref() {
+        None | Some(AUTO_MODEL) => router.route(&messages, registry.default_model()),
+        Some(model) if registry.contains(model) => router::Decision::picked(model.to_string()),
+        Some(model) => {
+            let message = format!("The model {model} does not exist");
+            return Err(ApiError::new(StatusCode::NOT_FOUND, "model_not_found", message));
+        },
+    };
+    tracing::info!(model = %route.model, reason = %route.reason, "Routed API request");
+
+    // The clients have no way to show the loading progress, so it's just a slow first token
+    let scheduler = registry.get(&route.model).await.map_err(|e| ApiError::server(e.to_string()))?;
+    scheduler.llama().validate(&mut params).map_err(|e| ApiError::invalid_request(e.to_string()))?;
+    let waiting_since = Instant::now();
+    let updates = scheduler.chat(None, messages, params).map_err(|_| {
+        ApiError::new(StatusCode::SERVICE_UNAVAILABLE, "queue_full", "The model is busy, try again later")
+    })?;
+    let outputs = observe(updates, metrics.clone(), route.model.clone(), start, waiting_since);
+
+    let id = format!("chatcmpl-{:016x}", rand::random::<u64>());
+    let created = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+    if request.stream {
+        let chunks = stream_chunks(outputs, metrics, id, created, route.model);
+        return Ok(Sse::new(chunks).keep_alive(KeepAlive::default()).into_response());
+    }
+
+    tokio::pin!(outputs);
+    let mut content = String::new();
+    let mut finish = None;
+    let mut usage = Usage::default();
+    while let Some(output) = outputs.next().await {
+        match output {
+            Ok(Output::Text(text)) => content.push_str(&text),
+            Ok(Output::Finished { reason, prompt_tokens, completion_tokens, .. }) => {
+                finish = Some(finish_reason(reason));
+                usage = Usage { prompt_tokens, completion_tokens, total_tokens: prompt_tokens + completion_tokens };
+            },
+            Ok(_) => {},
+            Err(e) => {
+                tracing::error!("API generation failed: {}", e);
+                return Err(ApiError::server(e));
+            },
+        }
+    }
+    Ok(Json(ChatCompletion {
+        id,
+        object: "chat.completion",
+        created,
+        model: route.model,
+        choices: vec![Choice {
+            index: 0,
+            message: AssistantMessage { role: "assistant", content },
+            finish_reason: finish,
+        }],
+        usage,
+    })
+    .into_response())
+}
+
+// The output of the generation, recording the same metrics as the web app on the way
+fn observe<S: Stream<Item = Result<Update, String>>>(
+    updates: S,
+    metrics: Arc<metrics::Metrics>,
+    model: String,
+    start: Instant,
+    waiting_since: Instant,
+) -> impl Stream<Item = Result<Output, String>> {
+    async_stream::stream! {
+        let mut first_token = true;
+        for await update in updates {
+            match update {
+                Ok(Update::Loading { .. }) | Ok(Update::Queued { .. }) => {},
+                Ok(Update::Admitted) => metrics.observe_queue_wait(&model, waiting_since.elapsed()),
+                Ok(Update::Output(output)) => {
+                    match &output {
+                        Output::Text(_) if first_token => {
+                            first_token = false;
+                            metrics.observe_time_to_first_token(&model, start.elapsed());
+                        },
+                        Output::Finished {
+                            prompt_tokens,
+                            reused_tokens,
+                            completion_tokens,
+                            prompt_duration,
+                            generation_duration,
+                            ..
+                        } => metrics.observe_generation(
+                            &model,
+                            prompt_tokens - reused_tokens,
+                            *prompt_duration,
+                            *completion_tokens,
+                            *generation_duration,
+                        ),
+                        _ => {},
+                    }
+                    yield Ok(output);
+                },
+                Err(e) => yield Err(e),
+            }
+        }
+    }
+}
+
+fn stream_chunks<S: Stream<Item = Result<Output, String>>>(
+    outputs: S,
+    metrics: Arc<metrics::Metrics>,
+    id: String,
+    created: u64,
+    model: String,
+) -> impl Stream<Item = Result<Event, Infallible>> {
+    async_stream::stream! {
+        let _active = metrics.open_stream();
+        let chunk = |delta: Delta, finish_reason: Option<&'static str>| {
+            let chunk = ChatCompletionChunk {
+                id: &id,
+                object: "chat.completion.chunk",
+                created,
+                model: &model,
+                choices: [ChunkChoice { index: 0, delta, finish_reason }],
+            };
+            Event::default().json_data(chunk).unwrap_or_else(|e| {
+                tracing::error!("Failed to serialize chunk: {}", e);
+                Event::default().data("{}")
+            })
+        };
+        yield Ok(chunk(Delta { role: Some("assistant"), content: None }, None));
+        for await output in outputs {
+            match output {
+                Ok(Output::Text(text)) => yield Ok(chunk(Delta { role: None, content: Some(text) }, None)),
+                Ok(Output::Finished { reason, .. }) => yield Ok(chunk(Delta::default(), Some(finish_reason(reason)))),
+                Ok(_) => {},
+                Err(e) => {
+                    tracing::error!("API generation failed: {}", e);
+                    yield Ok(Event::default().data(ApiError::server(e).body().to_string()));
+                    return;
+                },
+            }
+        }
+        yield Ok(Event::default().data("[DONE]"));
+    }
+}
+
+/// As many inputs as OpenAI takes per request.
+const MAX_EMBEDDING_INPUTS: usize = 2048;
+
+#[derive(Deserialize, Debug)]
+pub struct EmbeddingRequest {
+    /// Only the configured embedding model is there
+    model: Option<String>,
+    input: Input,
+    /// "float", the default, or "base64" for the little-endian bytes of the floats
+    encoding_format: Option<String>,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(untagged)]
+enum Input {
+    One(String),
+    Many(Vec<String>),
+}
+
+impl EmbeddingRequest {
+    fn texts(&self) -> Result<Vec<String>, String> {
+        let texts = match &self.input {
+            Input::One(text) => vec![text.clone()],
+            Input::Many(texts) => texts.clone(),
+        };
+        if texts.is_empty() || texts.len() > MAX_EMBEDDING_INPUTS {
+            return Err(format!("input must have between 1 and {MAX_EMBEDDING_INPUTS} texts"));
+        }
+        if texts.iter().any(|text| text.is_empty()) {
+            return Err(String::from("input must not contain empty texts"));
+        }
+        Ok(texts)
+    }
+
+    fn base64(&self) -> Result<bool, String> {
+        match self.encoding_format.as_deref() {
+            None | Some("float") => Ok(false),
+            Some("base64") => Ok(true),
+            Some(format) => Err(format!("unsupported encoding_format {format}")),
+        }
+    }
+}
+
+#[derive(Serialize)]
+pub struct EmbeddingList {
+    object: &'static str,
+    data: Vec<EmbeddingData>,
+    model: String,
+    usage: EmbeddingUsage,
+}
+
+#[derive(Serialize)]
+struct EmbeddingData {
+    object: &'static str,
+    index: usize,
+    embedding: Vector,
+}
+
+#[derive(Serialize, Debug, PartialEq)]
+#[serde(untagged)]
+enum Vector {
+    Float(Vec<f32>),
+    Base64(String),
+}
+
+impl Vector {
+    fn new(values: Vec<f32>, base64: bool) -> Vector {
+        if !base64 {
+            return Vector::Float(values);
+        }
+        let bytes: Vec<u8> = values.iter().flat_map(|value| value.to_le_bytes()).collect();
+        Vector::Base64(base64::engine::general_purpose::STANDARD.encode(bytes))
+    }
+}
+
+#[derive(Serialize)]
+struct EmbeddingUsage {
+    prompt_tokens: usize,
+    total_tokens: usize,
+}
+
+/// Answers like `POST https://api.openai.com/v1/embeddings` with the model of embedding.json.
+/// The BERT forward pass takes no attention mask, so only inputs with the same number of tokens
+/// are embedded together and a mixed list costs about one pass per input.
+pub async fn embeddings(
+    Extension(embedding_model): Extension<Option<Arc<embedder::EmbeddingModel>>>,
+    request: Result<Json<EmbeddingRequest>, JsonRejection>,
+) -> Result<Json<EmbeddingList>, ApiError> {
+    let Json(request) = request.map_err(|e| ApiError::invalid_request(e.body_text()))?;
+    let Some(embedding_model) = embedding_model else {
+        return Err(ApiError::new(StatusCode::NOT_FOUND, "model_not_found", "No embedding model is configured"));
+    };
+    if let Some(model) = request.model.as_deref().filter(|&model| model != embedding_model.name()) {
+        let message = format!("The embedding model {model} does not exist");
+        return Err(ApiError::new(StatusCode::NOT_FOUND, "model_not_found", message));
+    }
+    let texts = request.texts().map_err(ApiError::invalid_request)?;
+    let base64 = request.base64().map_err(ApiError::invalid_request)?;
+
+    let embedder = embedding_model.get().await.map_err(|e| ApiError::server(e.to_string()))?;
+    // A batch keeps a core busy for a while, which would stall the other requests of its thread
+    let embeddings = tokio::task::spawn_blocking(move || embedder.embed(&texts))
+        .await
+        .map_err(|e| ApiError::server(e.to_string()))?
+        .map_err(|e| {
+            tracing::error!("Embedding failed: {}", e);
+            ApiError::server(e.to_string())
+        })?;
+    Ok(Json(EmbeddingList {
+        object: "list",
+        data: embeddings
+            .vectors
+            .into_iter()
+            .enumerate()
+            .map(|(index, values)| EmbeddingData { object: "embedding", index, embedding: Vector::new(values, base64) })
+            .collect(),
+        model: embedding_model.name().to_string(),
+        usage: EmbeddingUsage { prompt_tokens: embeddings.tokens, total_tokens: embeddings.tokens },
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(json: &str) -> ChatCompletionRequest {
+        serde_json::from_str(json).unwrap()
+    }
+
+    #[test]
+    fn maps_messages() {
+        let request = request(r#"{
+            "model": "mistral",
+            "messages": [
+                { "role": "developer", "content": "Be brief." },
+                { "role": "user", "content": [{ "type": "text", "text": "Hi" }, { "type": "text", "text": "there" }] },
+                { "role": "assistant", "content": null },
+                { "role": "user", "content": "Thanks" }
+            ]
+        }"#);
+        let messages = request.chat_messages().unwrap();
+        let roles: Vec<Role> = messages.iter().map(|m| m.role).collect();
+        assert_eq!(roles, [Role::System, Role::User, Role::Assistant, Role::User]);
+        assert_eq!(messages[1].content, "Hi\nthere");
+        assert_eq!(messages[2].content, "");
+
+        let tool = request_with_message(r#"{ "role": "tool", "content": "42" }"#);
+        assert_eq!(tool.chat_messages().unwrap_err(), "unsupported role tool");
+        let image = request_with_message(r#"{ "role": "user", "content": [{ "type": "image_url" }] }"#);
+        assert!(image.chat_messages().is_err());
+    }
+
+    fn request_with_message(message: &str) -> ChatCompletionRequest {
+        request(&format!(r#"{{ "messages": [{message}] }}"#))
+    }
+
+    #[test]
+    fn maps_params() {
+        let params = request(r#"{ "messages": [], "temperature": 0.2, "max_tokens": 64, "stop": "\n", "seed": 7 }"#)
+            .params()
+            .unwrap();
+        assert_eq!((params.temperature, params.max_tokens, params.seed), (Some(0.2), Some(64), Some(7)));
+        assert_eq!(params.stop, ["\n"]);
+        let params = request(r#"{ "messages": [], "stop": ["a", "b"] }"#).params().unwrap();
+        assert_eq!(params.stop, ["a", "b"]);
+        assert!(request(r#"{ "messages": [], "n": 2 }"#).params().is_err());
+    }
+
+    #[test]
+    fn api_keys() {
+        assert!(ApiKeys::default().allows(None));
+        let keys = ApiKeys::new(vec![String::from("secret"), String::from("other")]);
+        assert!(keys.allows(Some("Bearer other")));
+        assert!(!keys.allows(Some("Bearer secre")));
+        assert!(!keys.allows(Some("secret")));
+        assert!(!keys.allows(None));
+    }
+
+    #[test]
+    fn embedding_inputs() {
+        let request = |json: &str| serde_json::from_str::<EmbeddingRequest>(json).unwrap();
+        assert_eq!(request(r#"{ "input": "Hi" }"#).texts().unwrap(), ["Hi"]);
+        assert_eq!(request(r#"{ "input": ["a", "b"] }"#).texts().unwrap(), ["a", "b"]);
+        assert!(request(r#"{ "input": [] }"#).texts().is_err());
+        assert!(request(r#"{ "input": ["a", ""] }"#).texts().is_err());
+        assert!(!request(r#"{ "input": "Hi", "encoding_format": "float" }"#).base64().unwrap());
+        assert!(request(r#"{ "input": "Hi", "encoding_format": "base64" }"#).base64().unwrap());
+        assert!(request(r#"{ "input": "Hi", "encoding_format": "hex" }"#).base64().is_err());
+    }
+
+    #[test]
+    fn base64_vectors() {
+        assert_eq!(Vector::new(vec![1.0, -2.0], true), Vector::Base64(String::from("AACAPwAAAMA=")));
+        assert_eq!(Vector::new(vec![1.0], false), Vector::Float(vec![1.0]));
+    }
+}
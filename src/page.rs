@@ -1,3 +1,5 @@
+use std::time::Duration;
+
 use maud::{html, Markup};
 use serde::{Deserialize, Serialize};
 
@@ -5,6 +7,9 @@ use crate::template;
 use crate::component;
 use crate::icon;
 use crate::theme;
+use crate::llama;
+use crate::registry::Readiness;
+use crate::router::{Rule, RoutingConfig};
 
 
 #[derive(PartialEq)]
@@ -25,15 +30,26 @@ pub fn str_to_agent(s: &str) -> Agent {
 pub struct FakeMessage {
     pub from: String,
     pub content: String,
+    /// The seed the chatbot sampled this message with, to be able to reproduce it
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub seed: Option<u64>,
+    /// The model that wrote this message
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub model: Option<String>,
+    /// Why the router sent the question to that model
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub route: Option<String>,
 }
 
-pub fn settings(color_scheme: theme::ColorScheme) -> Markup {
+pub fn settings(color_scheme: theme::ColorScheme, highlight_confidence: bool) -> Markup {
     html! {
         body {
             (template::top_navbar("Settings", html! { div {} }, html! { div {}}))
             main class="mt-6 mb-4 px-2" {
                 h3 { "Theme Preferences" }
                 (component::theme_preference(color_scheme, false))
+                h3 { "Answers" }
+                (component::confidence_preference(highlight_confidence))
             }
             (template::bottom_navbar(Pathname::Settings))
         }
@@ -55,17 +71,18 @@ pub fn conversations(messages: &Vec<FakeMessage>) -> Markup {
     }
 }
 
-pub fn conversation(id: &str, messages: &Vec<FakeMessage>) -> Markup {
+/// `model` is the one picked for the conversation, if any.
+pub fn conversation(id: &str, messages: &Vec<FakeMessage>, models: &[&str], model: Option<&str>) -> Markup {
     html! {
         body {
             (template::top_navbar(
                 id, 
                 component::primary_svg_button("/conversations", icon::arrow_left()), 
-                html! { div {} },
+                component::model_picker(id, models, model),
             ))
             div #messages class="flex flex-col items-center w-full" {
                 @for msg in messages {
-                    (component::message(str_to_agent(msg.from.as_str()), msg.content.as_str(), false))
+                    (component::message(str_to_agent(msg.from.as_str()), msg.content.as_str(), None))
                 }
             }
             div #bottom-spacer class="w-full min-h-4" {}
@@ -85,10 +102,75 @@ pub fn home() -> Markup {
     }
 }
 
-pub fn admin() -> Markup {
+/// `running` is the trace being captured and for how long, `traces` the finished ones.
+pub fn admin(
+    models: &[(&str, &Readiness, Option<&llama::CacheStats>)],
+    routing: &RoutingConfig,
+    running: Option<(String, Duration)>,
+    traces: &[String],
+) -> Markup {
     html! {
         body {
+            (template::top_navbar("Admin", html! { div {} }, html! { div {}}))
+            main class="mt-6 mb-4 px-2" {
+                @for (model, readiness, cache_stats) in models {
+                    h3 { (model) }
+                    p class="text-sm" { "Status: " (readiness) " " (component::reload_button(model)) }
+                    @if let Some(cache_stats) = cache_stats {
+                        h4 { "Prompt Cache" }
+                        ul class="text-sm" {
+                            li { (format!("Hit rate: {:.1}% of {} prompts", cache_stats.hit_rate() * 100.0, cache_stats.lookups())) }
+                            li { (format!("Reused tokens: {} of {}", cache_stats.reused_tokens(), cache_stats.prompt_tokens())) }
+                            li { (format!(
+                                "Cached conversations: {} ({})",
+                                cache_stats.entries(),
+                                llama::format_size(cache_stats.size_in_bytes()),
+                            )) }
+                            li { (format!("Evictions: {}", cache_stats.evictions())) }
+                        }
+                    }
+                }
+                h3 { "Routing" }
+                p class="text-sm" { "Rules from routing.json, the first match picks the model " (component::reload_routing_button()) }
+                @if routing.rules.is_empty() {
+                    p class="text-sm text-gray-500" { "No rules, every question goes to the default model." }
+                }
+                ol class="text-sm" {
+                    @for rule in &routing.rules {
+                        li { (rule.name) " → " (rule.model) ": " (rule_conditions(rule)) }
+                    }
+                }
+                h3 { "Profiling" }
+                (component::profiling(running, traces))
+            }
             (template::bottom_navbar(Pathname::Admin))
         }
     }
-}
\ No newline at end of file
+}
+
+fn rule_conditions(rule: &Rule) -> String {
+    let mut conditions = vec![];
+    if let Some(n) = rule.min_words {
+        conditions.push(format!("at least {n} words"));
+    }
+    if let Some(n) = rule.max_words {
+        conditions.push(format!("at most {n} words"));
+    }
+    if let Some(n) = rule.max_turns {
+        conditions.push(format!("at most {n} turns"));
+    }
+    if let Some(code) = rule.code {
+        conditions.push(String::from(if code { "with code" } else { "without code" }));
+    }
+    if let Some(retrieval) = rule.retrieval {
+        conditions.push(String::from(if retrieval { "needs documents" } else { "no documents" }));
+    }
+    if !rule.keywords.is_empty() {
+        conditions.push(format!("mentions {}", rule.keywords.join(" or ")));
+    }
+    if conditions.is_empty() {
+        String::from("always")
+    } else {
+        conditions.join(", ")
+    }
+}
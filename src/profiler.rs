@@ -0,0 +1,146 @@
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use tracing_chrome::{ChromeLayer, ChromeLayerBuilder, FlushGuard};
+use tracing_subscriber::{reload, Registry};
+
+/// The subscriber layer that records Chrome traces while the profiler runs, and nothing
+/// otherwise.
+pub type Layer = reload::Layer<Option<ChromeLayer<Registry>>, Registry>;
+
+/// The longest capture, a trace grows by every span of every forward pass
+pub const MAX_WINDOW: Duration = Duration::from_secs(300);
+
+// Older traces are deleted once a capture finishes
+const KEPT_TRACES: usize = 10;
+
+#[derive(Debug)]
+pub enum ProfilerError {
+    AlreadyRunning(String),
+    Io(std::io::Error),
+    Reload(reload::Error),
+}
+
+impl std::fmt::Display for ProfilerError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ProfilerError::AlreadyRunning(file) => write!(f, "already capturing {file}"),
+            ProfilerError::Io(e) => write!(f, "{e}"),
+            ProfilerError::Reload(e) => write!(f, "failed to switch the trace layer: {e}"),
+        }
+    }
+}
+
+impl std::error::Error for ProfilerError {}
+
+struct Capture {
+    file: String,
+    started: Instant,
+    // Dropping it writes the end of the trace
+    guard: FlushGuard,
+}
+
+/// Captures Chrome traces of every span, the forward pass of each model layer included, into
+/// files of a directory, keeping the last ten. The traces open in chrome://tracing or Perfetto.
+pub struct Profiler {
+    handle: reload::Handle<Option<ChromeLayer<Registry>>, Registry>,
+    dir: PathBuf,
+    capture: Mutex<Option<Capture>>,
+}
+
+impl Profiler {
+    /// The profiler and the layer it switches on and off, which goes into the subscriber first.
+    pub fn new(dir: impl Into<PathBuf>) -> (Layer, Profiler) {
+        let (layer, handle) = reload::Layer::new(None);
+        (layer, Profiler { handle, dir: dir.into(), capture: Mutex::new(None) })
+    }
+
+    /// Starts a new trace and returns the name of its file.
+    pub fn start(&self) -> Result<String, ProfilerError> {
+        let mut capture = self.capture.lock().unwrap();
+        if let Some(capture) = capture.as_ref() {
+            return Err(ProfilerError::AlreadyRunning(capture.file.clone()));
+        }
+        std::fs::create_dir_all(&self.dir).map_err(ProfilerError::Io)?;
+        let started = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+        let file = format!("trace-{}.json", started.as_millis());
+        let (layer, guard) = ChromeLayerBuilder::new()
+            .file(self.dir.join(&file))
+            .include_args(true)
+            .build();
+        self.handle.reload(Some(layer)).map_err(ProfilerError::Reload)?;
+        tracing::info!(file, "Started capturing a Chrome trace");
+        *capture = Some(Capture { file: file.clone(), started: Instant::now(), guard });
+        Ok(file)
+    }
+
+    /// Finishes the running trace and returns the name of its file.
+    pub fn stop(&self) -> Option<String> {
+        let capture = self.capture.lock().unwrap().take()?;
+        if let Err(e) = self.handle.reload(None) {
+            tracing::error!("Failed to switch the trace layer off: {}", e);
+        }
+        drop(capture.guard);
+        tracing::info!(file = capture.file, "Finished the Chrome trace");
+        for old in self.traces().into_iter().skip(KEPT_TRACES) {
+            if let Err(e) = std::fs::remove_file(self.dir.join(&old)) {
+                tracing::error!("Failed to delete the old trace {}: {}", old, e);
+            }
+        }
+        Some(capture.file)
+    }
+
+    /// Starts a trace that stops by itself after `window`, at most `MAX_WINDOW`, unless it's
+    /// stopped before.
+    pub fn capture_for(self: &Arc<Self>, window: Duration) -> Result<String, ProfilerError> {
+        let window = window.min(MAX_WINDOW);
+        let file = self.start()?;
+        let profiler = self.clone();
+        let started = file.clone();
+        tokio::spawn(async move {
+            tokio::time::sleep(window).await;
+            // A trace started after this one stopped is left alone
+            if profiler.running().map_or(false, |(file, _)| file == started) {
+                profiler.stop();
+            }
+        });
+        Ok(file)
+    }
+
+    /// The file of the running trace and how long it has been running.
+    pub fn running(&self) -> Option<(String, Duration)> {
+        let capture = self.capture.lock().unwrap();
+        capture.as_ref().map(|c| (c.file.clone(), c.started.elapsed()))
+    }
+
+    /// The names of the finished traces, newest first.
+    pub fn traces(&self) -> Vec<String> {
+        let running = self.running().map(|(file, _)| file);
+        let Ok(entries) = std::fs::read_dir(&self.dir) else {
+            return vec![];
+        };
+        let mut traces: Vec<String> = entries
+            .filter_map(|entry| entry.ok()?.file_name().into_string().ok())
+            .filter(|name| is_trace(name) && Some(name) != running.as_ref())
+            .collect();
+        traces.sort_unstable_by(|a, b| b.cmp(a));
+        traces
+    }
+
+    /// The path of a finished trace, None for names that aren't one.
+    pub fn trace(&self, name: &str) -> Option<PathBuf> {
+        if !is_trace(name) || self.running().map_or(false, |(file, _)| file == name) {
+            return None;
+        }
+        let path = self.dir.join(name);
+        Path::exists(&path).then_some(path)
+    }
+}
+
+// Only names the profiler made, so nothing outside the directory can be requested
+fn is_trace(name: &str) -> bool {
+    name.strip_prefix("trace-")
+        .and_then(|rest| rest.strip_suffix(".json"))
+        .map_or(false, |millis| !millis.is_empty() && millis.chars().all(|c| c.is_ascii_digit()))
+}
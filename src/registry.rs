@@ -0,0 +1,449 @@
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Mutex, RwLock};
+
+use serde::Deserialize;
+
+use crate::hub::{self, Hub, HubFile};
+use crate::llama::{self, chat::Template, Llama};
+use crate::metrics::Metrics;
+use crate::scheduler::{self, Scheduler};
+
+/// A model entry of `models.json`. The GGUF file is either a local `model` path or a `file` of a
+/// `repo` on the Hugging Face hub. Settings that are left out fall back to the defaults of
+/// `llama::Config`.
+#[derive(Deserialize, Clone, Debug)]
+pub struct ModelConfig {
+    pub name: String,
+    pub model: Option<String>,
+    pub repo: Option<String>,
+    pub file: Option<String>,
+    pub revision: Option<String>,
+    /// Expected SHA-256 of the GGUF file, checked on every load
+    pub sha256: Option<String>,
+    /// Path of a tokenizer.json, the tokenizer is built from the GGUF metadata otherwise
+    pub tokenizer: Option<String>,
+    /// Loaded at startup instead of by the first conversation that picks it
+    #[serde(default)]
+    pub eager: bool,
+    /// llama2, mistral, chatml, zephyr or phi, the usual one of the architecture by default
+    pub chat_template: Option<String>,
+    pub system_prompt: Option<String>,
+    pub temperature: Option<f64>,
+    pub top_p: Option<f64>,
+    pub repeat_penalty: Option<f32>,
+    pub repeat_last_n: Option<usize>,
+    pub frequency_penalty: Option<f32>,
+    pub presence_penalty: Option<f32>,
+    pub top_k: Option<usize>,
+    pub min_p: Option<f64>,
+    pub typical_p: Option<f64>,
+    /// Turns on mirostat, with a learning rate of `mirostat_eta`
+    pub mirostat_tau: Option<f32>,
+    pub mirostat_eta: Option<f32>,
+    #[serde(default)]
+    pub logit_bias: HashMap<u32, f32>,
+    #[serde(default)]
+    pub banned_tokens: Vec<u32>,
+    pub sample_len: Option<usize>,
+    pub max_batch_size: Option<usize>,
+    /// Memory for the KV caches kept between the turns of conversations, in bytes
+    pub cache_size: Option<usize>,
+    pub gqa: Option<usize>,
+    /// A small model with the same vocabulary that proposes tokens for speculative decoding
+    pub draft: Option<DraftConfig>,
+    /// Tokens the draft model proposes at a time
+    pub lookahead: Option<usize>,
+}
+
+/// The GGUF file of a draft model, given like the one of the main model.
+#[derive(Deserialize, Clone, Debug)]
+pub struct DraftConfig {
+    pub model: Option<String>,
+    pub repo: Option<String>,
+    pub file: Option<String>,
+    pub revision: Option<String>,
+    pub sha256: Option<String>,
+}
+
+#[derive(Debug)]
+pub enum RegistryError {
+    NoModels,
+    DuplicateName(String),
+    InvalidConfig(String, String),
+    UnknownModel(String),
+    Load(String, String),
+    AlreadyLoading(String),
+}
+
+impl std::fmt::Display for RegistryError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RegistryError::NoModels => write!(f, "no models are configured"),
+            RegistryError::DuplicateName(name) => write!(f, "model {name} is configured twice"),
+            RegistryError::InvalidConfig(name, e) => write!(f, "invalid config of model {name}: {e}"),
+            RegistryError::UnknownModel(name) => write!(f, "unknown model {name}"),
+            RegistryError::Load(name, e) => write!(f, "failed to load model {name}: {e}"),
+            RegistryError::AlreadyLoading(name) => write!(f, "model {name} is already loading"),
+        }
+    }
+}
+
+impl std::error::Error for RegistryError {}
+
+/// How far a model got with loading.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Readiness {
+    /// Nobody picked the model yet
+    NotLoaded,
+    /// The fraction of the layers read so far
+    Loading(f32),
+    Ready,
+    /// The last attempt failed, the next conversation that picks the model tries again
+    Failed(String),
+    /// New weights are loading while the old ones keep serving
+    Reloading(f32),
+    /// The old weights keep serving
+    ReloadFailed(String),
+}
+
+impl std::fmt::Display for Readiness {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Readiness::NotLoaded => write!(f, "not loaded"),
+            Readiness::Loading(progress) => write!(f, "loading, {:.0}%", progress * 100.0),
+            Readiness::Ready => write!(f, "ready"),
+            Readiness::Failed(e) => write!(f, "failed: {e}"),
+            Readiness::Reloading(progress) => write!(f, "ready, reloading {:.0}%", progress * 100.0),
+            Readiness::ReloadFailed(e) => write!(f, "ready, reload failed: {e}"),
+        }
+    }
+}
+
+#[derive(Clone)]
+enum Source {
+    Path(String),
+    Hub(HubFile),
+}
+
+impl Source {
+    fn new(
+        model: &Option<String>,
+        repo: &Option<String>,
+        file: &Option<String>,
+        revision: &Option<String>,
+        sha256: &Option<String>,
+    ) -> Option<Source> {
+        match (model, repo, file) {
+            (Some(path), None, None) => Some(Source::Path(path.clone())),
+            (None, Some(repo), Some(file)) => Some(Source::Hub(HubFile {
+                repo: repo.clone(),
+                file: file.clone(),
+                revision: revision.clone(),
+                sha256: sha256.clone(),
+            })),
+            _ => None,
+        }
+    }
+
+    // The local path of the file, downloaded first if needed
+    fn resolve(&self, sha256: &Option<String>, hub: &Hub) -> anyhow::Result<String> {
+        match self {
+            Source::Path(path) => {
+                if let Some(sha256) = sha256 {
+                    hub::verify(std::path::Path::new(path), Some(sha256))?;
+                }
+                Ok(path.clone())
+            },
+            Source::Hub(hub_file) => Ok(hub.resolve(hub_file)?.to_string_lossy().into_owned()),
+        }
+    }
+}
+
+// What a model is loaded from, checked when the config is read.
+#[derive(Clone)]
+struct Spec {
+    config: ModelConfig,
+    source: Source,
+    draft: Option<Source>,
+    chat_template: Option<Template>,
+}
+
+impl Spec {
+    fn new(config: ModelConfig) -> Result<Spec, RegistryError> {
+        let invalid = |reason: String| RegistryError::InvalidConfig(config.name.clone(), reason);
+        // Without one, the model's architecture decides once it's loaded
+        let chat_template = match &config.chat_template {
+            Some(template) => Some(
+                template
+                    .parse()
+                    .map_err(|e: llama::chat::UnknownTemplate| invalid(e.to_string()))?,
+            ),
+            None => None,
+        };
+        let c = &config;
+        let Some(source) = Source::new(&c.model, &c.repo, &c.file, &c.revision, &c.sha256) else {
+            return Err(invalid(String::from("either model or repo and file must be set")));
+        };
+        let draft = match &config.draft {
+            Some(d) => match Source::new(&d.model, &d.repo, &d.file, &d.revision, &d.sha256) {
+                Some(draft) => Some(draft),
+                None => return Err(invalid(String::from("either model or repo and file of the draft must be set"))),
+            },
+            None => None,
+        };
+        Ok(Spec { config, source, draft, chat_template })
+    }
+}
+
+struct Model {
+    name: String,
+    spec: RwLock<Spec>,
+    scheduler: RwLock<Option<Arc<Scheduler>>>,
+    // Held while weights are loading, so a model is only ever loaded once at a time
+    loading: Arc<tokio::sync::Mutex<()>>,
+    readiness: Arc<Mutex<Readiness>>,
+}
+
+impl Model {
+    fn scheduler(&self) -> Option<Arc<Scheduler>> {
+        self.scheduler.read().unwrap_or_else(|e| e.into_inner()).clone()
+    }
+
+    fn set_readiness(&self, readiness: Readiness) {
+        *self.readiness.lock().unwrap_or_else(|e| e.into_inner()) = readiness;
+    }
+}
+
+/// The models conversations can pick from, each with its own compute thread and queue. The
+/// first one is the default.
+pub struct Registry {
+    models: Vec<Model>,
+    hub: Arc<Hub>,
+    metrics: Arc<Metrics>,
+}
+
+impl Registry {
+    pub fn new(configs: Vec<ModelConfig>, hub: Hub, metrics: Arc<Metrics>) -> Result<Registry, RegistryError> {
+        if configs.is_empty() {
+            return Err(RegistryError::NoModels);
+        }
+        let mut names = HashSet::new();
+        let mut models = Vec::with_capacity(configs.len());
+        for config in configs {
+            if !names.insert(config.name.clone()) {
+                return Err(RegistryError::DuplicateName(config.name));
+            }
+            models.push(Model {
+                name: config.name.clone(),
+                spec: RwLock::new(Spec::new(config)?),
+                scheduler: RwLock::new(None),
+                loading: Arc::new(tokio::sync::Mutex::new(())),
+                readiness: Arc::new(Mutex::new(Readiness::NotLoaded)),
+            });
+        }
+        Ok(Registry { models, hub: Arc::new(hub), metrics })
+    }
+
+    pub fn default_model(&self) -> &str {
+        &self.models[0].name
+    }
+
+    pub fn names(&self) -> Vec<&str> {
+        self.models.iter().map(|m| m.name.as_str()).collect()
+    }
+
+    pub fn contains(&self, name: &str) -> bool {
+        self.models.iter().any(|m| m.name == name)
+    }
+
+    /// Loads the models marked as eager, one after the other. A model that fails to load doesn't
+    /// hold up the others, its readiness tells what went wrong.
+    pub async fn load_eager(&self) {
+        for model in &self.models {
+            let eager = model.spec.read().unwrap_or_else(|e| e.into_inner()).config.eager;
+            if eager {
+                let _ = self.get(&model.name).await;
+            }
+        }
+    }
+
+    fn model(&self, name: &str) -> Result<&Model, RegistryError> {
+        self.models
+            .iter()
+            .find(|m| m.name == name)
+            .ok_or_else(|| RegistryError::UnknownModel(name.to_string()))
+    }
+
+    /// The scheduler of a model, loading the model first if nobody used it yet.
+    pub async fn get(&self, name: &str) -> Result<Arc<Scheduler>, RegistryError> {
+        let model = self.model(name)?;
+        if let Some(scheduler) = model.scheduler() {
+            return Ok(scheduler);
+        }
+        let _loading = model.loading.lock().await;
+        // Somebody else might have loaded it while we waited
+        if let Some(scheduler) = model.scheduler() {
+            return Ok(scheduler);
+        }
+        let spec = model.spec.read().unwrap_or_else(|e| e.into_inner()).clone();
+        let result = self.load(model, spec, Readiness::Loading).await;
+        match &result {
+            Ok(scheduler) => {
+                *model.scheduler.write().unwrap_or_else(|e| e.into_inner()) = Some(scheduler.clone());
+                model.set_readiness(Readiness::Ready);
+            },
+            Err(e) => model.set_readiness(Readiness::Failed(e.to_string())),
+        }
+        result
+    }
+
+    /// Starts loading the model again from `config`, or from its current config, in the
+    /// background of serving it. Once the new weights are ready they take over new
+    /// conversations, generations that already started finish on the old ones, which are freed
+    /// after that. Fails right away while the model is already loading.
+    pub fn reload(self: &Arc<Self>, name: &str, config: Option<ModelConfig>) -> Result<(), RegistryError> {
+        let model = self.model(name)?;
+        let spec = match config {
+            Some(config) if config.name != name => {
+                let reason = format!("got the config of {}", config.name);
+                return Err(RegistryError::InvalidConfig(name.to_string(), reason));
+            },
+            Some(config) => Spec::new(config)?,
+            None => model.spec.read().unwrap_or_else(|e| e.into_inner()).clone(),
+        };
+        let Ok(loading) = model.loading.clone().try_lock_owned() else {
+            return Err(RegistryError::AlreadyLoading(name.to_string()));
+        };
+        let registry = self.clone();
+        let name = name.to_string();
+        tokio::spawn(async move {
+            if let Err(e) = registry.swap_in(&name, spec).await {
+                tracing::error!("{}", e);
+            }
+            drop(loading);
+        });
+        Ok(())
+    }
+
+    // Loads the new weights of a reload, while the caller holds the model's loading lock
+    async fn swap_in(&self, name: &str, spec: Spec) -> Result<(), RegistryError> {
+        let model = self.model(name)?;
+        let previous = model.scheduler();
+        let progress: fn(f32) -> Readiness = if previous.is_some() { Readiness::Reloading } else { Readiness::Loading };
+        match self.load(model, spec.clone(), progress).await {
+            Ok(scheduler) => {
+                *model.spec.write().unwrap_or_else(|e| e.into_inner()) = spec;
+                *model.scheduler.write().unwrap_or_else(|e| e.into_inner()) = Some(scheduler);
+                model.set_readiness(Readiness::Ready);
+                // The queue and the in-flight generations hold on to the old model until they're done
+                drop(previous);
+                tracing::info!(model = name, "Swapped in the reloaded model");
+                Ok(())
+            },
+            Err(e) => {
+                model.set_readiness(match previous {
+                    Some(_) => Readiness::ReloadFailed(e.to_string()),
+                    None => Readiness::Failed(e.to_string()),
+                });
+                Err(e)
+            },
+        }
+    }
+
+    // Reading the weights takes a while, so it happens off the tokio workers. `progress` makes
+    // the readiness reported along the way.
+    async fn load(
+        &self,
+        model: &Model,
+        spec: Spec,
+        progress: fn(f32) -> Readiness,
+    ) -> Result<Arc<Scheduler>, RegistryError> {
+        let name = model.name.as_str();
+        model.set_readiness(progress(0.0));
+        tracing::info!(model = name, "Loading model");
+        let start = std::time::Instant::now();
+        let on_progress = {
+            let readiness = model.readiness.clone();
+            Box::new(move |fraction: f32| {
+                *readiness.lock().unwrap_or_else(|e| e.into_inner()) = progress(fraction);
+            })
+        };
+        let hub = self.hub.clone();
+        let loading = tokio::task::spawn_blocking(move || load(&spec, &hub, on_progress));
+        let result = match loading.await {
+            Ok(result) => result.map_err(|e| RegistryError::Load(name.to_string(), e.to_string())),
+            Err(e) => Err(RegistryError::Load(name.to_string(), e.to_string())),
+        };
+        match &result {
+            Ok(_) => {
+                tracing::info!(model = name, elapsed_s = start.elapsed().as_secs_f32(), "Model ready");
+                self.metrics.observe_model_load(name, start.elapsed());
+            },
+            Err(e) => tracing::error!(model = name, "{}", e),
+        }
+        result
+    }
+
+    /// The scheduler of a model that is loaded by now, without waiting for it.
+    pub fn ready(&self, name: &str) -> Option<Arc<Scheduler>> {
+        self.model(name).ok()?.scheduler()
+    }
+
+    pub fn readiness(&self, name: &str) -> Readiness {
+        match self.model(name) {
+            Ok(model) => model.readiness.lock().unwrap_or_else(|e| e.into_inner()).clone(),
+            Err(_) => Readiness::NotLoaded,
+        }
+    }
+
+    /// Every model in the configured order with how far it got with loading, and its scheduler
+    /// once it's ready.
+    pub fn status(&self) -> Vec<(&str, Readiness, Option<Arc<Scheduler>>)> {
+        self.models
+            .iter()
+            .map(|m| {
+                let readiness = m.readiness.lock().unwrap_or_else(|e| e.into_inner()).clone();
+                (m.name.as_str(), readiness, m.scheduler())
+            })
+            .collect()
+    }
+}
+
+fn load(spec: &Spec, hub: &Hub, on_progress: Box<dyn Fn(f32) + Send>) -> anyhow::Result<Arc<Scheduler>> {
+    let config = &spec.config;
+    let path = spec.source.resolve(&config.sha256, hub)?;
+    let draft_model = match (&spec.draft, &config.draft) {
+        (Some(source), Some(draft)) => Some(source.resolve(&draft.sha256, hub)?),
+        _ => None,
+    };
+    let defaults = llama::Config::default();
+    let max_batch_size = config.max_batch_size.unwrap_or(defaults.max_batch_size);
+    let llama = Llama::new(&path, config.tokenizer.as_deref(), llama::Config {
+        sample_len: config.sample_len.unwrap_or(defaults.sample_len),
+        top_p: config.top_p.or(defaults.top_p),
+        temperature: config.temperature.or(defaults.temperature),
+        repeat_penalty: config.repeat_penalty.unwrap_or(defaults.repeat_penalty),
+        repeat_last_n: config.repeat_last_n.unwrap_or(defaults.repeat_last_n),
+        frequency_penalty: config.frequency_penalty.unwrap_or(defaults.frequency_penalty),
+        presence_penalty: config.presence_penalty.unwrap_or(defaults.presence_penalty),
+        top_k: config.top_k.or(defaults.top_k),
+        min_p: config.min_p.or(defaults.min_p),
+        typical_p: config.typical_p.or(defaults.typical_p),
+        mirostat: config.mirostat_tau.map(|tau| llama::Mirostat { tau, eta: config.mirostat_eta.unwrap_or(llama::Mirostat::DEFAULT_ETA) }),
+        logit_bias: config.logit_bias.clone(),
+        banned_tokens: config.banned_tokens.clone(),
+        chat_template: spec.chat_template,
+        system_prompt: config.system_prompt.clone().or(defaults.system_prompt),
+        max_batch_size,
+        prefix_cache_size: config.cache_size.unwrap_or(defaults.prefix_cache_size),
+        gqa: config.gqa,
+        draft_model,
+        lookahead: config.lookahead.unwrap_or(defaults.lookahead),
+        on_progress: Some(on_progress),
+        ..llama::Config::default()
+    })?;
+    Ok(Arc::new(Scheduler::new(llama, scheduler::Config {
+        max_active: max_batch_size,
+        ..Default::default()
+    })))
+}
@@ -0,0 +1,274 @@
+use std::sync::RwLock;
+
+use serde::Deserialize;
+
+use crate::llama::chat::{ChatMessage, Role};
+
+/// The rules of `routing.json`, which send every question to the cheapest model that can
+/// answer it. The first rule whose conditions all hold picks the model, so cheap models go
+/// first. Questions no rule matches go to the default model.
+#[derive(Deserialize, Clone, Debug, Default)]
+pub struct RoutingConfig {
+    #[serde(default)]
+    pub rules: Vec<Rule>,
+    /// Words that mark a question about the company's own documents, replacing the defaults
+    pub retrieval_keywords: Option<Vec<String>>,
+}
+
+/// A condition that is left out always holds.
+#[derive(Deserialize, Clone, Debug)]
+pub struct Rule {
+    pub name: String,
+    pub model: String,
+    pub min_words: Option<usize>,
+    pub max_words: Option<usize>,
+    /// Questions asked in the conversation so far, this one included
+    pub max_turns: Option<usize>,
+    /// Whether the question contains code
+    pub code: Option<bool>,
+    /// Whether the question needs the company's documents
+    pub retrieval: Option<bool>,
+    /// The question contains one of these, ignoring case
+    #[serde(default)]
+    pub keywords: Vec<String>,
+}
+
+const RETRIEVAL_KEYWORDS: [&str; 12] = [
+    "our", "company", "policy", "policies", "internal", "handbook", "process", "document",
+    "guideline", "onboarding", "benefits", "wiki",
+];
+
+const CODE_MARKERS: [&str; 8] = ["```", "fn ", "def ", "class ", "();", "=>", "SELECT ", "#include"];
+
+#[derive(Debug)]
+pub enum RoutingError {
+    UnknownModel { rule: String, model: String },
+    DuplicateRule(String),
+}
+
+impl std::fmt::Display for RoutingError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RoutingError::UnknownModel { rule, model } => write!(f, "rule {rule} routes to unknown model {model}"),
+            RoutingError::DuplicateRule(rule) => write!(f, "rule {rule} is configured twice"),
+        }
+    }
+}
+
+impl std::error::Error for RoutingError {}
+
+/// What the router looks at in the latest question of a conversation.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Features {
+    pub words: usize,
+    pub turns: usize,
+    pub code: bool,
+    pub retrieval: bool,
+}
+
+impl Features {
+    fn new(history: &[ChatMessage], retrieval_keywords: &[String]) -> Features {
+        let question = history
+            .iter()
+            .rev()
+            .find(|m| m.role == Role::User)
+            .map_or("", |m| m.content.as_str());
+        let lowercase = question.to_lowercase();
+        let words: Vec<&str> = lowercase
+            .split(|c: char| !c.is_alphanumeric() && c != '\'')
+            .filter(|w| !w.is_empty())
+            .collect();
+        Features {
+            words: words.len(),
+            turns: history.iter().filter(|m| m.role == Role::User).count(),
+            code: CODE_MARKERS.iter().any(|marker| question.contains(marker)),
+            retrieval: words.iter().any(|w| retrieval_keywords.iter().any(|k| k == w)),
+        }
+    }
+}
+
+impl std::fmt::Display for Features {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} words, turn {}", self.words, self.turns)?;
+        if self.code {
+            write!(f, ", code")?;
+        }
+        if self.retrieval {
+            write!(f, ", needs documents")?;
+        }
+        Ok(())
+    }
+}
+
+impl Rule {
+    fn matches(&self, features: &Features, question: &str) -> bool {
+        self.min_words.map_or(true, |n| features.words >= n)
+            && self.max_words.map_or(true, |n| features.words <= n)
+            && self.max_turns.map_or(true, |n| features.turns <= n)
+            && self.code.map_or(true, |code| features.code == code)
+            && self.retrieval.map_or(true, |retrieval| features.retrieval == retrieval)
+            && (self.keywords.is_empty() || self.keywords.iter().any(|k| question.contains(k.as_str())))
+    }
+}
+
+/// Which model answers a message, and why.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Decision {
+    pub model: String,
+    pub reason: String,
+}
+
+impl Decision {
+    /// The model the user picked for the conversation, which turns routing off.
+    pub fn picked(model: String) -> Decision {
+        Decision { model, reason: String::from("picked for the conversation") }
+    }
+}
+
+pub struct Router {
+    config: RwLock<RoutingConfig>,
+}
+
+impl Router {
+    /// Checks that the rules only route to the given models.
+    pub fn new(config: RoutingConfig, models: &[&str]) -> Result<Router, RoutingError> {
+        validate(&config, models)?;
+        Ok(Router { config: RwLock::new(lowercase_keywords(config)) })
+    }
+
+    /// Replaces the rules, the old ones stay when the new ones are invalid.
+    pub fn reload(&self, config: RoutingConfig, models: &[&str]) -> Result<(), RoutingError> {
+        validate(&config, models)?;
+        *self.config.write().unwrap() = lowercase_keywords(config);
+        Ok(())
+    }
+
+    pub fn config(&self) -> RoutingConfig {
+        self.config.read().unwrap().clone()
+    }
+
+    /// Picks the model for the latest question of `history`.
+    pub fn route(&self, history: &[ChatMessage], default_model: &str) -> Decision {
+        let config = self.config.read().unwrap();
+        let default_keywords = || RETRIEVAL_KEYWORDS.iter().map(|k| k.to_string()).collect();
+        let retrieval_keywords: Vec<String> = config.retrieval_keywords.clone().unwrap_or_else(default_keywords);
+        let features = Features::new(history, &retrieval_keywords);
+        let question = history
+            .iter()
+            .rev()
+            .find(|m| m.role == Role::User)
+            .map_or(String::new(), |m| m.content.to_lowercase());
+        match config.rules.iter().find(|rule| rule.matches(&features, &question)) {
+            Some(rule) => Decision {
+                model: rule.model.clone(),
+                reason: format!("rule {} ({})", rule.name, features),
+            },
+            None => Decision {
+                model: default_model.to_string(),
+                reason: format!("no rule matched ({})", features),
+            },
+        }
+    }
+}
+
+fn validate(config: &RoutingConfig, models: &[&str]) -> Result<(), RoutingError> {
+    for (i, rule) in config.rules.iter().enumerate() {
+        if !models.contains(&rule.model.as_str()) {
+            return Err(RoutingError::UnknownModel { rule: rule.name.clone(), model: rule.model.clone() });
+        }
+        if config.rules[..i].iter().any(|r| r.name == rule.name) {
+            return Err(RoutingError::DuplicateRule(rule.name.clone()));
+        }
+    }
+    Ok(())
+}
+
+// The questions are lowercased before they're compared with the keywords
+fn lowercase_keywords(mut config: RoutingConfig) -> RoutingConfig {
+    let rule_keywords = config.rules.iter_mut().flat_map(|rule| rule.keywords.iter_mut());
+    for keyword in rule_keywords.chain(config.retrieval_keywords.iter_mut().flatten()) {
+        *keyword = keyword.to_lowercase();
+    }
+    config
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn router() -> Router {
+        let config: RoutingConfig = serde_json::from_str(r#"{
+            "rules": [
+                { "name": "code", "model": "big", "code": true },
+                { "name": "quick", "model": "small", "max_words": 12, "max_turns": 3, "retrieval": false },
+                { "name": "hr", "model": "medium", "keywords": ["Vacation", "payroll"] }
+            ]
+        }"#).unwrap();
+        Router::new(config, &["small", "medium", "big"]).unwrap()
+    }
+
+    fn ask(questions: &[&str]) -> Vec<ChatMessage> {
+        questions
+            .iter()
+            .flat_map(|q| [ChatMessage::new(Role::User, q), ChatMessage::new(Role::Assistant, "Sure.")])
+            .take(questions.len() * 2 - 1)
+            .collect()
+    }
+
+    #[test]
+    fn first_matching_rule_wins() {
+        let router = router();
+        assert_eq!(router.route(&ask(&["What's the capital of France?"]), "big").model, "small");
+        let decision = router.route(&ask(&["Why does `let x = f();` not compile?"]), "small");
+        assert_eq!(decision, Decision { model: String::from("big"), reason: String::from("rule code (7 words, turn 1, code)") });
+        assert_eq!(router.route(&ask(&["How many vacation days do I get under our policy?"]), "big").model, "medium");
+    }
+
+    #[test]
+    fn falls_back_to_the_default_model() {
+        let router = router();
+        let long = "Can you explain in detail how the quarterly planning works and who takes part in it?";
+        let decision = router.route(&ask(&[long]), "big");
+        assert_eq!(decision.model, "big");
+        assert_eq!(decision.reason, "no rule matched (16 words, turn 1)");
+        // Too deep into the conversation for the small model
+        assert_eq!(router.route(&ask(&["Hi", "Hi", "Hi", "Thanks!"]), "big").model, "big");
+    }
+
+    #[test]
+    fn retrieval_keywords() {
+        let history = ask(&["Where is the onboarding checklist?"]);
+        assert!(Features::new(&history, &[String::from("onboarding")]).retrieval);
+        assert!(!Features::new(&history, &[String::from("payroll")]).retrieval);
+    }
+
+    #[test]
+    fn configured_keywords_ignore_case() {
+        let config: RoutingConfig = serde_json::from_str(r#"{
+            "rules": [{ "name": "docs", "model": "big", "retrieval": true }],
+            "retrieval_keywords": ["Onboarding", "HANDBOOK"]
+        }"#).unwrap();
+        let router = Router::new(config, &["small", "big"]).unwrap();
+        assert_eq!(router.route(&ask(&["Where is the onboarding checklist?"]), "small").model, "big");
+        assert_eq!(router.route(&ask(&["What does the Handbook say?"]), "small").model, "big");
+        assert_eq!(router.route(&ask(&["What's the capital of France?"]), "small").model, "small");
+    }
+
+    #[test]
+    fn invalid_rules() {
+        let rule = |name: &str, model: &str| Rule {
+            name: name.to_string(),
+            model: model.to_string(),
+            min_words: None,
+            max_words: None,
+            max_turns: None,
+            code: None,
+            retrieval: None,
+            keywords: vec![],
+        };
+        let unknown = RoutingConfig { rules: vec![rule("a", "huge")], retrieval_keywords: None };
+        assert!(matches!(Router::new(unknown, &["small"]), Err(RoutingError::UnknownModel { .. })));
+        let duplicate = RoutingConfig { rules: vec![rule("a", "small"), rule("a", "small")], retrieval_keywords: None };
+        assert!(matches!(Router::new(duplicate, &["small"]), Err(RoutingError::DuplicateRule(_))));
+    }
+}
@@ -0,0 +1,340 @@
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::Duration;
+
+use futures_core::stream::Stream;
+use futures_util::StreamExt;
+use tokio::sync::watch;
+use tokio::time::Instant;
+
+use crate::llama::{self, chat::ChatMessage, Llama};
+
+pub struct Config {
+    /// Requests waiting for the model, more are turned away
+    pub max_queue_depth: usize,
+    /// Requests the model works on at the same time, should match the llama batch size
+    pub max_active: usize,
+    /// Time a request may take from being queued until its last token
+    pub deadline: Duration,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            max_queue_depth: 16,
+            max_active: 4,
+            deadline: Duration::from_secs(300),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct QueueFull;
+
+#[derive(Debug, PartialEq)]
+pub enum Update {
+    /// The model is still being loaded, with the fraction of its layers read so far. Only sent
+    /// by the server before the request gets to the scheduler.
+    Loading { progress: f32 },
+    /// Sent whenever the position in the queue changes, 1 is next in line
+    Queued { position: usize },
+    /// The request left the queue and is being worked on
+    Admitted,
+    Output(llama::Output),
+}
+
+struct State {
+    next_ticket: u64,
+    queue: VecDeque<u64>,
+    active: usize,
+}
+
+/// Hands out the model to requests in the order they arrived.
+pub struct Scheduler {
+    llama: Arc<Llama>,
+    queue: Queue,
+    deadline: Duration,
+}
+
+impl Scheduler {
+    pub fn new(llama: Llama, c: Config) -> Scheduler {
+        Scheduler {
+            llama: Arc::new(llama),
+            queue: Queue::new(c.max_queue_depth, c.max_active),
+            deadline: c.deadline,
+        }
+    }
+
+    pub fn llama(&self) -> &Llama {
+        &self.llama
+    }
+
+    /// Queues a chat request. The returned stream reports the position in the queue until the
+    /// model is free and then the generated output. Dropping the stream gives up the place in
+    /// the queue or cancels the generation. Only a conversation keeps its KV cache for the
+    /// next turn.
+    pub fn chat(
+        &self,
+        conversation: Option<String>,
+        messages: Vec<ChatMessage>,
+        params: llama::Params,
+    ) -> Result<impl Stream<Item = Result<Update, String>>, QueueFull> {
+        let ticket = self.queue.enqueue()?;
+        let llama = self.llama.clone();
+        let max_active = self.queue.max_active;
+        let deadline = Instant::now() + self.deadline;
+        Ok(async_stream::stream! {
+            let queued_at = Instant::now();
+            let mut admitted = None;
+            for await waiting in wait_for_turn(ticket, max_active, deadline) {
+                match waiting {
+                    Ok(Waiting::Queued { position }) => yield Ok(Update::Queued { position }),
+                    Ok(Waiting::Admitted(ticket)) => admitted = Some(ticket),
+                    Err(e) => yield Err(e),
+                }
+            }
+            let Some(ticket) = admitted else { return };
+            tracing::info!("Admitted request after waiting {:.2}s", queued_at.elapsed().as_secs_f32());
+            yield Ok(Update::Admitted);
+
+            let mut generation = llama.chat(conversation.as_deref(), &messages, params);
+            loop {
+                let next = tokio::select! {
+                    output = generation.next() => Some(output),
+                    _ = tokio::time::sleep_until(deadline) => None,
+                };
+                match next {
+                    Some(Some(output)) => yield output.map(Update::Output),
+                    Some(None) => break,
+                    None => {
+                        generation.cancel();
+                        yield Err(String::from("Deadline exceeded while generating"));
+                        break;
+                    },
+                }
+            }
+            drop(ticket);
+        })
+    }
+}
+
+// The requests waiting for the model and the ones it works on
+struct Queue {
+    state: Arc<Mutex<State>>,
+    changed: Arc<watch::Sender<()>>,
+    max_queue_depth: usize,
+    max_active: usize,
+}
+
+impl Queue {
+    fn new(max_queue_depth: usize, max_active: usize) -> Queue {
+        let (changed, _) = watch::channel(());
+        Queue {
+            state: Arc::new(Mutex::new(State {
+                next_ticket: 0,
+                queue: VecDeque::new(),
+                active: 0,
+            })),
+            changed: Arc::new(changed),
+            max_queue_depth,
+            max_active: max_active.max(1),
+        }
+    }
+
+    // A place at the end of the queue
+    fn enqueue(&self) -> Result<Ticket, QueueFull> {
+        let ticket = {
+            let mut state = lock(&self.state);
+            if state.queue.len() >= self.max_queue_depth {
+                return Err(QueueFull);
+            }
+            let id = state.next_ticket;
+            state.next_ticket += 1;
+            state.queue.push_back(id);
+            Ticket {
+                id,
+                admitted: false,
+                state: self.state.clone(),
+                changed: self.changed.clone(),
+            }
+        };
+        self.changed.send_replace(());
+        Ok(ticket)
+    }
+}
+
+enum Waiting {
+    Queued { position: usize },
+    Admitted(Ticket),
+}
+
+// Reports the position of the ticket whenever it changes, and ends with the admitted ticket or
+// an error once the deadline passed
+fn wait_for_turn(
+    mut ticket: Ticket,
+    max_active: usize,
+    deadline: Instant,
+) -> impl Stream<Item = Result<Waiting, String>> {
+    async_stream::stream! {
+        let mut changed = ticket.changed.subscribe();
+        let mut last_position = None;
+        loop {
+            match ticket.try_admit(max_active) {
+                Ok(()) => {
+                    yield Ok(Waiting::Admitted(ticket));
+                    break;
+                },
+                Err(position) => {
+                    if last_position != Some(position) {
+                        last_position = Some(position);
+                        yield Ok(Waiting::Queued { position });
+                    }
+                },
+            }
+            let timed_out = tokio::select! {
+                _ = changed.changed() => false,
+                _ = tokio::time::sleep_until(deadline) => true,
+            };
+            if timed_out {
+                yield Err(String::from("Deadline exceeded while waiting in the queue"));
+                break;
+            }
+        }
+    }
+}
+
+fn lock(state: &Mutex<State>) -> MutexGuard<'_, State> {
+    state.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+// A place in the queue, and once admitted, one of the active slots. Both are given back on drop.
+struct Ticket {
+    id: u64,
+    admitted: bool,
+    state: Arc<Mutex<State>>,
+    changed: Arc<watch::Sender<()>>,
+}
+
+impl Ticket {
+    // Returns the position in the queue while the ticket has to wait
+    fn try_admit(&mut self, max_active: usize) -> Result<(), usize> {
+        let mut state = lock(&self.state);
+        let position = state.queue
+            .iter()
+            .position(|&id| id == self.id)
+            .expect("Tickets stay queued until they are admitted or dropped");
+        if position == 0 && state.active < max_active {
+            state.queue.pop_front();
+            state.active += 1;
+            self.admitted = true;
+            drop(state);
+            self.changed.send_replace(());
+            return Ok(());
+        }
+        Err(position + 1)
+    }
+}
+
+impl Drop for Ticket {
+    fn drop(&mut self) {
+        let mut state = lock(&self.state);
+        if self.admitted {
+            state.active -= 1;
+        } else {
+            state.queue.retain(|&id| id != self.id);
+        }
+        drop(state);
+        self.changed.send_replace(());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // The queued tickets and the number of active ones
+    fn state(queue: &Queue) -> (Vec<u64>, usize) {
+        let state = lock(&queue.state);
+        (state.queue.iter().copied().collect(), state.active)
+    }
+
+    // A queue whose only slot is taken by the returned ticket
+    fn busy_queue() -> (Queue, Ticket) {
+        let queue = Queue::new(4, 1);
+        let mut active = queue.enqueue().unwrap();
+        active.try_admit(1).unwrap();
+        (queue, active)
+    }
+
+    #[test]
+    fn admission_is_first_in_first_out() {
+        let queue = Queue::new(4, 1);
+        let mut first = queue.enqueue().unwrap();
+        let mut second = queue.enqueue().unwrap();
+        // The slot is free, but the first ticket is ahead
+        assert_eq!(second.try_admit(1), Err(2));
+        assert_eq!(first.try_admit(1), Ok(()));
+        assert_eq!(second.try_admit(1), Err(1));
+        drop(first);
+        assert_eq!(second.try_admit(1), Ok(()));
+    }
+
+    #[test]
+    fn queue_full_at_max_depth() {
+        let queue = Queue::new(2, 1);
+        let first = queue.enqueue().unwrap();
+        let _second = queue.enqueue().unwrap();
+        assert!(queue.enqueue().is_err());
+        drop(first);
+        assert!(queue.enqueue().is_ok());
+    }
+
+    #[test]
+    fn dropped_tickets_give_back_their_place() {
+        let (queue, active) = busy_queue();
+        let waiting = queue.enqueue().unwrap();
+        let last = queue.enqueue().unwrap();
+        assert_eq!(state(&queue), (vec![1, 2], 1));
+        drop(waiting);
+        assert_eq!(state(&queue), (vec![2], 1));
+        drop(active);
+        assert_eq!(state(&queue), (vec![2], 0));
+        drop(last);
+        assert_eq!(state(&queue), (vec![], 0));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn position_updates_as_tickets_ahead_leave() {
+        let (queue, active) = busy_queue();
+        let ahead = queue.enqueue().unwrap();
+        let waiting = wait_for_turn(queue.enqueue().unwrap(), 1, Instant::now() + Duration::from_secs(60));
+        tokio::pin!(waiting);
+        assert!(matches!(waiting.next().await, Some(Ok(Waiting::Queued { position: 2 }))));
+        drop(ahead);
+        assert!(matches!(waiting.next().await, Some(Ok(Waiting::Queued { position: 1 }))));
+        drop(active);
+        let Some(Ok(Waiting::Admitted(ticket))) = waiting.next().await else {
+            panic!("the ticket wasn't admitted");
+        };
+        assert!(waiting.next().await.is_none());
+        assert_eq!(state(&queue), (vec![], 1));
+        drop(ticket);
+        assert_eq!(state(&queue), (vec![], 0));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn waiting_ends_at_the_deadline() {
+        let (queue, _active) = busy_queue();
+        let start = Instant::now();
+        let waiting: Vec<_> = wait_for_turn(queue.enqueue().unwrap(), 1, start + Duration::from_secs(30))
+            .collect()
+            .await;
+        assert!(matches!(
+            waiting.as_slice(),
+            [Ok(Waiting::Queued { position: 1 }), Err(e)] if e == "Deadline exceeded while waiting in the queue"
+        ));
+        assert_eq!(start.elapsed(), Duration::from_secs(30));
+        // The ticket gave back its place in the queue
+        assert_eq!(state(&queue), (vec![], 1));
+    }
+}